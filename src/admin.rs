@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::errors::*;
+use crate::new_index::{precache, Query};
+use crate::util::spawn_thread;
+
+/// The maintenance operations of the admin API. The long ones run in the background, one at a
+/// time.
+pub struct Admin {
+    query: Arc<Query>,
+    busy: Arc<AtomicBool>,
+}
+
+impl Admin {
+    pub fn new(query: Arc<Query>) -> Self {
+        Admin {
+            query,
+            busy: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn spawn_job<F>(&self, name: &'static str, job: F) -> Result<()>
+    where
+        F: FnOnce(&Query) + Send + 'static,
+    {
+        ensure!(
+            !self.busy.swap(true, Ordering::SeqCst),
+            "another admin job is still running"
+        );
+        let query = Arc::clone(&self.query);
+        let busy = Arc::clone(&self.busy);
+        spawn_thread(name, move || {
+            info!("starting admin job {}", name);
+            job(&query);
+            info!("finished admin job {}", name);
+            busy.store(false, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+
+    /// Compact the databases, e.g. to reclaim space after a large reorg
+    pub fn compact(&self) -> Result<()> {
+        self.spawn_job("compaction", |query| {
+            let store = query.chain().store();
+            store.txstore_db().full_compaction();
+            store.history_db().full_compaction();
+            store.cache_db().full_compaction();
+        })
+    }
+
+    /// Reload the scripts listed in the `--precache-scripts` file and precache their stats,
+    /// returning the number of scripts
+    pub fn reload_precache(&self) -> Result<usize> {
+        let path = self
+            .query
+            .config()
+            .precache_scripts
+            .clone()
+            .chain_err(|| "no precache file configured")?;
+        let scripthashes = precache::scripthashes_from_file(path)?;
+        let count = scripthashes.len();
+        self.spawn_job("precache", move |query| {
+            precache::precache(query.chain(), scripthashes)
+        })?;
+        Ok(count)
+    }
+
+    /// The mempool backlog, along with the fee settings of tapyrusd
+    pub fn mempool_stats(&self) -> Result<Value> {
+        let daemon_info = self.query.get_mempool_info()?;
+        let mempool = self.query.mempool();
+        Ok(json!({
+            "backlog": mempool.backlog_stats(),
+            "daemon": daemon_info,
+        }))
+    }
+}
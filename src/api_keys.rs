@@ -0,0 +1,152 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::errors::*;
+use crate::rate_limit::Quota;
+
+// The scope granting access to the admin API, which is never granted implicitly
+const ADMIN_SCOPE: &str = "/admin";
+
+// An entry of the API keys file, which holds a JSON array of them
+#[derive(Deserialize)]
+struct ApiKeyEntry {
+    key: String,
+    name: String,
+    #[serde(default)]
+    scopes: Vec<String>,
+    rate_limit: Option<u32>,
+    rate_limit_burst: Option<u32>,
+}
+
+/// The settings of a client identified by an API key.
+///
+/// Scopes are prefixes of the REST routes (e.g. `/address`) and of the Electrum methods (e.g.
+/// `blockchain.scripthash`) the key may access. A key without scopes may access everything but
+/// the admin API.
+#[derive(Debug)]
+pub struct ApiKey {
+    pub name: String,
+    scopes: Vec<String>,
+    pub quota: Option<Quota>,
+}
+
+impl ApiKey {
+    /// Whether the key grants access to the REST route or Electrum method
+    pub fn allows(&self, route: &str) -> bool {
+        if self.scopes.is_empty() {
+            return !is_admin_route(route);
+        }
+        self.scopes.iter().any(|scope| in_scope(route, scope))
+    }
+}
+
+/// Whether the REST route belongs to the admin API
+pub fn is_admin_route(route: &str) -> bool {
+    in_scope(route, ADMIN_SCOPE)
+}
+
+// Scopes match whole path segments or method name components, so that `/address` does not
+// grant `/address-prefix`
+fn in_scope(route: &str, scope: &str) -> bool {
+    match route.strip_prefix(scope) {
+        Some(rest) => {
+            rest.is_empty() || scope.ends_with(&['/', '.'][..]) || rest.starts_with(&['/', '.'][..])
+        }
+        None => false,
+    }
+}
+
+pub struct ApiKeys {
+    keys: HashMap<String, Arc<ApiKey>>,
+}
+
+impl ApiKeys {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read(path).chain_err(|| format!("cannot read {:?}", path))?;
+        let entries: Vec<ApiKeyEntry> =
+            serde_json::from_slice(&contents).chain_err(|| "invalid API keys file")?;
+        ApiKeys::new(entries)
+    }
+
+    fn new(entries: Vec<ApiKeyEntry>) -> Result<Self> {
+        let mut keys = HashMap::new();
+        let mut names = HashSet::new();
+        for entry in entries {
+            // names identify the keys in the logs and in the rate limiter
+            ensure!(
+                names.insert(entry.name.clone()),
+                "duplicate API key name {}",
+                entry.name
+            );
+            let quota = entry.rate_limit.map(|rate| Quota {
+                rate,
+                burst: entry.rate_limit_burst.unwrap_or(rate),
+            });
+            let api_key = ApiKey {
+                name: entry.name,
+                scopes: entry.scopes,
+                quota,
+            };
+            ensure!(
+                keys.insert(entry.key, Arc::new(api_key)).is_none(),
+                "duplicate API key"
+            );
+        }
+        info!("loaded {} API keys", keys.len());
+        Ok(ApiKeys { keys })
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<ApiKey>> {
+        self.keys.get(key).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_keys() {
+        let entries = serde_json::from_value(json!([
+            {"key": "k1", "name": "wallet", "scopes": ["/address", "blockchain.scripthash"], "rate_limit": 50},
+            {"key": "k2", "name": "ops", "scopes": ["/admin"]},
+            {"key": "k3", "name": "explorer"},
+        ]))
+        .unwrap();
+        let keys = ApiKeys::new(entries).unwrap();
+        assert!(keys.get("k4").is_none());
+
+        let wallet = keys.get("k1").unwrap();
+        assert_eq!(
+            wallet.quota,
+            Some(Quota {
+                rate: 50,
+                burst: 50
+            })
+        );
+        assert!(wallet.allows("/address/abcd/txs"));
+        assert!(!wallet.allows("/address-prefix/ab"));
+        assert!(!wallet.allows("/tx/abcd"));
+        assert!(wallet.allows("blockchain.scripthash.get_history"));
+        assert!(!wallet.allows("blockchain.transaction.get"));
+
+        let ops = keys.get("k2").unwrap();
+        assert!(ops.allows("/admin/compact"));
+        assert!(!ops.allows("/blocks/tip/height"));
+
+        let explorer = keys.get("k3").unwrap();
+        assert_eq!(explorer.quota, None);
+        assert!(explorer.allows("/tx/abcd"));
+        assert!(explorer.allows("server.version"));
+        assert!(!explorer.allows("/admin/webhooks"));
+
+        let duplicate = serde_json::from_value(json!([
+            {"key": "k1", "name": "a"},
+            {"key": "k1", "name": "b"},
+        ]))
+        .unwrap();
+        assert!(ApiKeys::new(duplicate).is_err());
+    }
+}
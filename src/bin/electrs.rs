@@ -6,21 +6,31 @@ extern crate log;
 extern crate esplora_tapyrus;
 
 use error_chain::ChainedError;
+use std::collections::{HashMap, HashSet};
 use std::process;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use esplora_tapyrus::{
+    api_keys::ApiKeys,
     config::Config,
     daemon::Daemon,
-    electrum::RPC as ElectrumRPC,
+    electrum::{server_features, DiscoveryManager, RPC as ElectrumRPC},
     errors::*,
     metrics::Metrics,
-    new_index::{precache, ChainQuery, FetchFrom, Indexer, Mempool, Query, Store},
+    new_index::{events::Events, precache, ChainQuery, FetchFrom, Indexer, Mempool, Query, Store},
+    rate_limit::{Quota, RateLimiter},
     rest,
     signal::Waiter,
+    webhooks::{self, WebhookRegistry},
+    zmq,
 };
 
+// tapyrusd is polled at this interval. With zmq rawtx notifications, the mempool is updated
+// incrementally in between, and still fully resynced at this interval to catch evictions and
+// the notifications that zmq dropped silently.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
 fn fetch_from(config: &Config, store: &Store) -> FetchFrom {
     let mut jsonrpc_import = config.jsonrpc_import;
     if !jsonrpc_import {
@@ -87,30 +97,136 @@ fn run_server(config: Arc<Config>) -> Result<()> {
         Arc::clone(&config),
     ));
 
+    let mut events = Events::new(Arc::clone(&query));
+
+    let webhooks = if config.enable_webhooks {
+        let registry = Arc::new(WebhookRegistry::open(Arc::clone(&store)));
+        webhooks::start(
+            Arc::clone(&registry),
+            Arc::clone(&query),
+            events.sender().subscribe(),
+            &metrics,
+        );
+        Some(registry)
+    } else {
+        None
+    };
+
+    let api_keys = match config.api_keys_file {
+        Some(ref path) => Some(Arc::new(
+            ApiKeys::from_file(path).chain_err(|| "failed to load the API keys")?,
+        )),
+        None => None,
+    };
+
+    // the clients share their token buckets between the REST and Electrum servers, and API keys
+    // may have their own quotas even when anonymous clients are not limited
+    let rate_limiter = if config.rate_limit.is_some() || api_keys.is_some() {
+        let default = config.rate_limit.map(|rate| Quota {
+            rate,
+            burst: config.rate_limit_burst,
+        });
+        Some(Arc::new(RateLimiter::new(default, &metrics)))
+    } else {
+        None
+    };
+
     // TODO: configuration for which servers to start
-    let rest_server = rest::start(Arc::clone(&config), Arc::clone(&query));
-    let electrum_server = ElectrumRPC::start(Arc::clone(&config), Arc::clone(&query), &metrics);
+    let rest_server = rest::start(
+        Arc::clone(&config),
+        Arc::clone(&query),
+        events.sender(),
+        webhooks,
+        rate_limiter.clone(),
+        api_keys.clone(),
+    );
+    // server discovery is only enabled when we know the public hosts to advertise
+    let discovery = config.electrum_public_hosts.clone().map(|hosts| {
+        let features = server_features(hosts);
+        let our_version = features.protocol_max.clone();
+        let discovery = Arc::new(DiscoveryManager::new(
+            config.network,
+            features,
+            our_version,
+            config.electrum_announce,
+            config.tor_proxy,
+        ));
+        DiscoveryManager::spawn_jobs_thread(Arc::clone(&discovery));
+        discovery
+    });
+    let electrum_server = ElectrumRPC::start(
+        Arc::clone(&config),
+        Arc::clone(&query),
+        &metrics,
+        discovery,
+        rate_limiter,
+        api_keys,
+    );
+
+    // polling remains as a fallback for missed notifications
+    let (zmq_sender, zmq_receiver) = crossbeam_channel::unbounded();
+    let mut zmq_topics: HashMap<_, Vec<&'static str>> = HashMap::new();
+    if let Some(addr) = config.zmq_hashblock_addr {
+        zmq_topics.entry(addr).or_default().push("hashblock");
+    }
+    if let Some(addr) = config.zmq_rawtx_addr {
+        zmq_topics.entry(addr).or_default().push("rawtx");
+    }
+    for (addr, topics) in zmq_topics {
+        zmq::subscribe(addr, topics, zmq_sender.clone());
+    }
+    let mut mempool_synced_at = Instant::now();
 
     loop {
-        if let Err(err) = signal.wait(Duration::from_secs(5), true) {
-            info!("stopping server: {}", err);
-            rest_server.stop();
-            // the electrum server is stopped when dropped
-            break;
+        let notification = match signal.wait_or(POLL_INTERVAL, &zmq_receiver) {
+            Ok(notification) => notification,
+            Err(err) => {
+                info!("stopping server: {}", err);
+                rest_server.stop();
+                // the electrum server is stopped when dropped
+                break;
+            }
+        };
+        // kept in the order they were published, so that parents are added before their children
+        let mut new_txids = vec![];
+        let mut seen_txids = HashSet::new();
+        for notification in notification.into_iter().chain(zmq_receiver.try_iter()) {
+            if let zmq::Notification::Tx(txid) = notification {
+                if seen_txids.insert(txid) {
+                    new_txids.push(txid);
+                }
+            }
         }
 
         // Index new blocks
         let current_tip = daemon.getbestblockhash()?;
-        if current_tip != tip {
+        let new_block = current_tip != tip;
+        if new_block {
             indexer.update(&daemon)?;
             tip = current_tip;
         };
-
-        // Update mempool
-        mempool.write().unwrap().update(&daemon)?;
+        let mut touched_scripthashes = indexer.take_touched_scripthashes();
+
+        // Update mempool, incrementally when possible
+        {
+            let mut mempool = mempool.write().unwrap();
+            if config.zmq_rawtx_addr.is_some()
+                && !new_block
+                && mempool_synced_at.elapsed() < POLL_INTERVAL
+            {
+                for txid in &new_txids {
+                    mempool.add_by_txid(&daemon, txid);
+                }
+            } else {
+                mempool.update(&daemon)?;
+                mempool_synced_at = Instant::now();
+            }
+            touched_scripthashes.extend(mempool.take_touched_scripthashes());
+        }
 
         // Update subscribed clients
-        electrum_server.notify();
+        electrum_server.notify(touched_scripthashes);
+        events.update();
     }
     info!("server stopped");
     Ok(())
@@ -10,6 +10,7 @@ use stderrlog;
 
 use crate::chain::{Network, NetworkType};
 use crate::daemon::CookieGetter;
+use crate::electrum::ServerHosts;
 
 use crate::errors::*;
 
@@ -26,6 +27,11 @@ pub struct Config {
     pub daemon_rpc_addr: SocketAddr,
     pub cookie: Option<String>,
     pub electrum_rpc_addr: SocketAddr,
+    pub electrum_tls_addr: Option<SocketAddr>,
+    pub electrum_ws_addr: Option<SocketAddr>,
+    pub electrum_wss_addr: Option<SocketAddr>,
+    pub electrum_tls_cert: Option<PathBuf>,
+    pub electrum_tls_key: Option<PathBuf>,
     pub http_addr: SocketAddr,
     pub http_socket_file: Option<PathBuf>,
     pub monitoring_addr: SocketAddr,
@@ -36,9 +42,26 @@ pub struct Config {
     pub cors: Option<String>,
     pub precache_scripts: Option<String>,
     pub utxos_limit: usize,
+    pub rest_batch_limit: usize,
     pub electrum_txs_limit: usize,
+    pub electrum_max_connections: usize,
+    pub electrum_max_connections_per_ip: Option<usize>,
+    pub electrum_max_subscriptions: usize,
+    pub electrum_idle_timeout: u64,
     pub electrum_banner: String,
     pub enable_open_assets: bool,
+    pub electrum_public_hosts: Option<ServerHosts>,
+    pub electrum_announce: bool,
+    pub tor_proxy: Option<SocketAddr>,
+    pub verify_block_proofs: bool,
+    pub enable_webhooks: bool,
+    pub zmq_hashblock_addr: Option<SocketAddr>,
+    pub zmq_rawtx_addr: Option<SocketAddr>,
+    pub rate_limit: Option<u32>,
+    pub rate_limit_burst: u32,
+    pub trusted_proxy: bool,
+    pub api_keys_file: Option<PathBuf>,
+    pub require_api_key: bool,
 }
 
 fn str_to_socketaddr(address: &str, what: &str) -> SocketAddr {
@@ -101,6 +124,31 @@ impl Config {
                     .long("electrum-rpc-addr")
                     .help("Electrum server JSONRPC 'addr:port' to listen on (default: '127.0.0.1:50001' for prod and '127.0.0.1:60001' for dev)"),
             )
+            .arg(
+                Arg::new("electrum_tls_addr")
+                    .long("electrum-tls-addr")
+                    .help("Electrum server JSONRPC over TLS 'addr:port' to listen on (default disabled, requires --electrum-tls-cert and --electrum-tls-key)"),
+            )
+            .arg(
+                Arg::new("electrum_ws_addr")
+                    .long("electrum-ws-addr")
+                    .help("Electrum server JSONRPC over WebSocket 'addr:port' to listen on (default disabled)"),
+            )
+            .arg(
+                Arg::new("electrum_wss_addr")
+                    .long("electrum-wss-addr")
+                    .help("Electrum server JSONRPC over secure WebSocket 'addr:port' to listen on (default disabled, requires --electrum-tls-cert and --electrum-tls-key)"),
+            )
+            .arg(
+                Arg::new("electrum_tls_cert")
+                    .long("electrum-tls-cert")
+                    .help("PEM file with the TLS certificate chain of the Electrum server"),
+            )
+            .arg(
+                Arg::new("electrum_tls_key")
+                    .long("electrum-tls-key")
+                    .help("PEM file with the PKCS#8 private key of the TLS certificate"),
+            )
             .arg(
                 Arg::new("network_id")
                     .long("network-id")
@@ -162,12 +210,42 @@ impl Config {
                     .default_value("500")
                     .value_parser(value_parser!(usize))
             )
+            .arg(
+                Arg::new("rest_batch_limit")
+                    .long("rest-batch-limit")
+                    .help("Maximum number of addresses and scripthashes, combined, of the HTTP batch queries (POST /addresses/...)")
+                    .default_value("100")
+                    .value_parser(value_parser!(usize))
+            )
             .arg(
                 Arg::new("electrum_txs_limit")
                     .long("electrum-txs-limit")
                     .help("Maximum number of transactions returned by Electrum history queries. Lookups with more results will fail.")
                     .default_value("500")
                     .value_parser(value_parser!(usize))
+            ).arg(
+                Arg::new("electrum_max_connections")
+                    .long("electrum-max-connections")
+                    .help("Maximum number of Electrum clients connected at the same time, over all the listeners")
+                    .default_value("10000")
+                    .value_parser(value_parser!(usize))
+            ).arg(
+                Arg::new("electrum_max_connections_per_ip")
+                    .long("electrum-max-connections-per-ip")
+                    .help("Maximum number of Electrum clients connected at the same time from a single IP address (default: unlimited)")
+                    .value_parser(value_parser!(usize))
+            ).arg(
+                Arg::new("electrum_max_subscriptions")
+                    .long("electrum-max-subscriptions")
+                    .help("Maximum number of scripthash subscriptions per Electrum client. Subscriptions beyond it will fail.")
+                    .default_value("50000")
+                    .value_parser(value_parser!(usize))
+            ).arg(
+                Arg::new("electrum_idle_timeout")
+                    .long("electrum-idle-timeout")
+                    .help("Seconds after which Electrum clients that sent no request and received no notification are disconnected")
+                    .default_value("600")
+                    .value_parser(value_parser!(u64))
             ).arg(
                 Arg::new("electrum_banner")
                     .long("electrum-banner")
@@ -177,6 +255,62 @@ impl Config {
                     .long("enable-open-assets")
                     .action(ArgAction::SetTrue)
                     .help("Enable open assets feature")
+            ).arg(
+                Arg::new("electrum_public_hosts")
+                    .long("electrum-public-hosts")
+                    .help("A dictionary of hosts where the Electrum server can be reached at. Required to enable server discovery. See https://electrumx.readthedocs.io/en/latest/protocol-methods.html#server-features")
+            ).arg(
+                Arg::new("electrum_announce")
+                    .long("electrum-announce")
+                    .action(ArgAction::SetTrue)
+                    .help("Announce the Electrum server to other servers")
+            ).arg(
+                Arg::new("tor_proxy")
+                    .long("tor-proxy")
+                    .help("'addr:port' of a socks proxy for accessing onion hosts")
+            ).arg(
+                Arg::new("verify_block_proofs")
+                    .long("verify-block-proofs")
+                    .action(ArgAction::SetTrue)
+                    .help("Verify the federation signature of every block header against the aggregate public key in force before indexing it")
+            ).arg(
+                Arg::new("enable_webhooks")
+                    .long("enable-webhooks")
+                    .action(ArgAction::SetTrue)
+                    .help("Call the webhooks registered through the /admin/webhooks REST API, which requires an API key with the /admin scope (requires --api-keys-file)")
+            ).arg(
+                Arg::new("zmq_hashblock_addr")
+                    .long("zmq-hashblock-addr")
+                    .help("'addr:port' of tapyrusd's zmqpubhashblock publisher, for processing new blocks as they arrive (default: poll every 5 seconds)")
+            ).arg(
+                Arg::new("zmq_rawtx_addr")
+                    .long("zmq-rawtx-addr")
+                    .help("'addr:port' of tapyrusd's zmqpubrawtx publisher, for adding new transactions to the mempool as they arrive (default: poll every 5 seconds)")
+            ).arg(
+                Arg::new("rate_limit")
+                    .long("rate-limit")
+                    .help("Tokens per second granted to each client of the REST and Electrum servers, spent according to the cost of their requests (default: unlimited)")
+                    .value_parser(value_parser!(u32).range(1..))
+            ).arg(
+                Arg::new("rate_limit_burst")
+                    .long("rate-limit-burst")
+                    .help("Maximum number of tokens a client may accumulate, and spend at once")
+                    .default_value("100")
+                    .value_parser(value_parser!(u32))
+            ).arg(
+                Arg::new("trusted_proxy")
+                    .long("trusted-proxy")
+                    .action(ArgAction::SetTrue)
+                    .help("Rate limit the REST clients by the last address of the X-Forwarded-For header, set by the reverse proxy in front of the server (required to rate limit the clients of the unix socket)")
+            ).arg(
+                Arg::new("api_keys_file")
+                    .long("api-keys-file")
+                    .help("JSON file of the API keys accepted by the REST and Electrum servers, with their scopes and rate limits. Enables the /admin REST API for the keys with the /admin scope.")
+            ).arg(
+                Arg::new("require_api_key")
+                    .long("require-api-key")
+                    .action(ArgAction::SetTrue)
+                    .help("Refuse the requests without a valid API key")
             );
 
         #[cfg(unix)]
@@ -222,6 +356,32 @@ impl Config {
                 .unwrap_or(&format!("127.0.0.1:{}", default_electrum_port)),
             "Electrum RPC",
         );
+        let electrum_tls_addr: Option<SocketAddr> = m
+            .get_one::<String>("electrum_tls_addr")
+            .map(|s| str_to_socketaddr(s, "Electrum TLS"));
+        let electrum_ws_addr: Option<SocketAddr> = m
+            .get_one::<String>("electrum_ws_addr")
+            .map(|s| str_to_socketaddr(s, "Electrum WebSocket"));
+        let electrum_wss_addr: Option<SocketAddr> = m
+            .get_one::<String>("electrum_wss_addr")
+            .map(|s| str_to_socketaddr(s, "Electrum secure WebSocket"));
+        let electrum_tls_cert: Option<PathBuf> = m.get_one::<String>("electrum_tls_cert").map(PathBuf::from);
+        let electrum_tls_key: Option<PathBuf> = m.get_one::<String>("electrum_tls_key").map(PathBuf::from);
+        if (electrum_tls_addr.is_some() || electrum_wss_addr.is_some())
+            && (electrum_tls_cert.is_none() || electrum_tls_key.is_none())
+        {
+            panic!("--electrum-tls-cert and --electrum-tls-key are required to listen over TLS");
+        }
+        let api_keys_file: Option<PathBuf> = m.get_one::<String>("api_keys_file").map(PathBuf::from);
+        let require_api_key = m.get_flag("require_api_key");
+        if require_api_key && api_keys_file.is_none() {
+            panic!("--api-keys-file is required with --require-api-key");
+        }
+        // the webhooks make the server call the registered URLs, so registering them is reserved
+        // to admin API keys
+        if m.get_flag("enable_webhooks") && api_keys_file.is_none() {
+            panic!("--api-keys-file is required with --enable-webhooks");
+        }
         let http_addr: SocketAddr = str_to_socketaddr(
             m.get_one::<String>("http_addr").map(|s| s.as_str())
                 .unwrap_or(&format!("127.0.0.1:{}", default_http_port)),
@@ -235,6 +395,19 @@ impl Config {
             "Prometheus monitoring",
         );
 
+        let electrum_public_hosts: Option<ServerHosts> = m
+            .get_one::<String>("electrum_public_hosts")
+            .map(|s| serde_json::from_str(s).expect("invalid --electrum-public-hosts"));
+        let tor_proxy: Option<SocketAddr> = m
+            .get_one::<String>("tor_proxy")
+            .map(|s| str_to_socketaddr(s, "Tor proxy"));
+        let zmq_hashblock_addr: Option<SocketAddr> = m
+            .get_one::<String>("zmq_hashblock_addr")
+            .map(|s| str_to_socketaddr(s, "ZMQ hashblock"));
+        let zmq_rawtx_addr: Option<SocketAddr> = m
+            .get_one::<String>("zmq_rawtx_addr")
+            .map(|s| str_to_socketaddr(s, "ZMQ rawtx"));
+
         let daemon_dir = m
             .get_one::<String>("daemon_dir")
             .map(PathBuf::from)
@@ -272,8 +445,18 @@ impl Config {
             daemon_rpc_addr,
             cookie,
             utxos_limit: *m.get_one::<usize>("utxos_limit").unwrap(),
+            rest_batch_limit: *m.get_one::<usize>("rest_batch_limit").unwrap(),
             electrum_rpc_addr,
+            electrum_tls_addr,
+            electrum_ws_addr,
+            electrum_wss_addr,
+            electrum_tls_cert,
+            electrum_tls_key,
             electrum_txs_limit: *m.get_one::<usize>("electrum_txs_limit").unwrap(),
+            electrum_max_connections: *m.get_one::<usize>("electrum_max_connections").unwrap(),
+            electrum_max_connections_per_ip: m.get_one::<usize>("electrum_max_connections_per_ip").copied(),
+            electrum_max_subscriptions: *m.get_one::<usize>("electrum_max_subscriptions").unwrap(),
+            electrum_idle_timeout: *m.get_one::<u64>("electrum_idle_timeout").unwrap(),
             electrum_banner,
             http_addr,
             http_socket_file,
@@ -285,6 +468,18 @@ impl Config {
             cors: m.get_one::<String>("cors").map(|s| s.to_string()),
             precache_scripts: m.get_one::<String>("precache_scripts").map(|s| s.to_string()),
             enable_open_assets: m.get_flag("enable_open_assets"),
+            electrum_public_hosts,
+            electrum_announce: m.get_flag("electrum_announce"),
+            tor_proxy,
+            verify_block_proofs: m.get_flag("verify_block_proofs"),
+            enable_webhooks: m.get_flag("enable_webhooks"),
+            zmq_hashblock_addr,
+            zmq_rawtx_addr,
+            rate_limit: m.get_one::<u32>("rate_limit").copied(),
+            rate_limit_burst: *m.get_one::<u32>("rate_limit_burst").unwrap(),
+            trusted_proxy: m.get_flag("trusted_proxy"),
+            api_keys_file,
+            require_api_key,
         };
         eprintln!("{:?}", config);
         config
@@ -70,7 +70,7 @@ fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Resu
                     match code {
                         // RPC_IN_WARMUP -> retry by later reconnection
                         -28 => bail!(ErrorKind::Connection(err.to_string())),
-                        _ => bail!("{} RPC error: {}", method, err),
+                        _ => bail!(ErrorKind::Daemon(method.to_owned(), err.clone())),
                     }
                 }
             }
@@ -106,6 +106,14 @@ pub struct BlockchainInfo {
     pub initialblockdownload: Option<bool>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MempoolInfo {
+    pub mempoolminfee: f64, // in BTC/kB
+    pub minrelaytxfee: f64, // in BTC/kB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub incrementalrelayfee: Option<f64>, // in BTC/kB, not reported by older daemons
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct NetworkInfo {
     version: u64,
@@ -488,10 +496,11 @@ impl Daemon {
         Ok(txs)
     }
 
+    // the blockhash is needed for confirmed transactions when tapyrusd has no txindex
     pub fn gettransaction_raw(
         &self,
         txid: &Txid,
-        blockhash: &BlockHash,
+        blockhash: Option<&BlockHash>,
         verbose: bool,
     ) -> Result<Value> {
         self.request(
@@ -510,6 +519,11 @@ impl Daemon {
         Ok(serde_json::from_value(res).chain_err(|| "invalid getrawmempool reply")?)
     }
 
+    pub fn getmempoolinfo(&self) -> Result<MempoolInfo> {
+        let res = self.request("getmempoolinfo", json!([]))?;
+        Ok(serde_json::from_value(res).chain_err(|| "invalid getmempoolinfo reply")?)
+    }
+
     pub fn getmempool(&self) -> Result<HashMap<Txid, MempoolTx>> {
         let res = self.request("getrawmempool", json!([/*verbose=*/ true]))?;
         Ok(serde_json::from_value(res).chain_err(|| "invalid getrawmempool (verbose) reply")?)
@@ -1,87 +1,123 @@
-use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
-use std::net::ToSocketAddrs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
 
-use tapyrus::{hashes::Hash, BlockHash};
-use electrum_client::client::{
-    Client as RClient, ElectrumPlaintextStream, ElectrumProxyStream, ElectrumSslStream,
-};
-pub use electrum_client::types::ServerFeaturesRes;
-pub use electrum_client::Error as ElectrumError;
+use native_tls::TlsConnector;
+use serde_json::Value;
+use socks::Socks5Stream;
 
 use crate::electrum::ServerFeatures;
-use crate::errors::{Error, ResultExt};
+use crate::errors::*;
 
-pub enum Client {
-    Tcp(RClient<ElectrumPlaintextStream>),
-    Ssl(RClient<ElectrumSslStream>),
-    ProxyTcp(RClient<ElectrumProxyStream>),
-    // proxy+ssl on the same connection appears to be unsupported by the electrum_client crate
-}
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+trait Stream: Read + Write + Send {}
+impl<S: Read + Write + Send> Stream for S {}
 
-// impl<S: Read + Write> Client<S> {
+/// A minimal Electrum client, implementing just the methods needed for server discovery
+pub struct Client {
+    stream: BufReader<Box<dyn Stream>>,
+    next_id: u64,
+}
 
 impl Client {
-    pub fn new<A: ToSocketAddrs>(socket_addr: A) -> Result<Self, ElectrumError> {
-        Ok(Client::Tcp(RClient::new(socket_addr)?))
+    pub fn new<A: ToSocketAddrs>(socket_addr: A) -> Result<Self> {
+        Ok(Client::from_stream(Box::new(connect(socket_addr)?)))
     }
 
-    pub fn new_ssl(domain_addr: (&str, u16)) -> Result<Self, ElectrumError> {
-        // SSL certificates are not validated
-        Ok(Client::Ssl(RClient::new_ssl(domain_addr, false)?))
-        // XXX this should ideally use the previously resolved IP address instead of the hostname, which
-        // shuold be possible because we don't validate, but it appears like rustls does not support this.
+    /// Connect over TLS without verifying the certificate chain, since Electrum servers commonly
+    /// use self-signed certificates. Returns the DER encoded certificate of the server along with
+    /// the client, for the caller to pin.
+    pub fn new_ssl(domain_addr: (&str, u16)) -> Result<(Self, Vec<u8>)> {
+        let connector = TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .chain_err(|| "failed to create tls connector")?;
+        let stream = connector
+            .connect(domain_addr.0, connect(domain_addr)?)
+            .chain_err(|| "tls handshake failed")?;
+        let certificate = stream
+            .peer_certificate()
+            .chain_err(|| "failed to read server certificate")?
+            .chain_err(|| "server sent no certificate")?
+            .to_der()
+            .chain_err(|| "failed to encode server certificate")?;
+        Ok((Client::from_stream(Box::new(stream)), certificate))
     }
 
-    pub fn new_proxy<A: ToSocketAddrs>(
-        target_addr: (&str, u16),
-        proxy_addr: A,
-    ) -> Result<Self, ElectrumError> {
-        Ok(Client::ProxyTcp(RClient::new_proxy(
-            target_addr,
-            proxy_addr,
-        )?))
+    /// Connect through a SOCKS5 proxy, which resolves the hostname of `target_addr`
+    pub fn new_proxy<A: ToSocketAddrs>(target_addr: (&str, u16), proxy_addr: A) -> Result<Self> {
+        let stream = Socks5Stream::connect(proxy_addr, target_addr)
+            .chain_err(|| "socks5 proxy connection failed")?
+            .into_inner();
+        set_timeouts(&stream)?;
+        Ok(Client::from_stream(Box::new(stream)))
     }
 
-    pub fn server_features(&mut self) -> Result<ServerFeatures, Error> {
-        match self {
-            Client::Tcp(c) => c.server_features(),
-            Client::Ssl(c) => c.server_features(),
-            Client::ProxyTcp(c) => c.server_features(),
-        }?
-        .try_into()
+    fn from_stream(stream: Box<dyn Stream>) -> Self {
+        Client {
+            stream: BufReader::new(stream),
+            next_id: 0,
+        }
     }
 
-    pub fn server_add_peer(&mut self, features: &ServerFeatures) -> Result<bool, ElectrumError> {
-        match self {
-            Client::Tcp(c) => c.server_add_peer(features),
-            Client::Ssl(c) => c.server_add_peer(features),
-            Client::ProxyTcp(c) => c.server_add_peer(features),
+    fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let request = json!({"jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params});
+        self.stream
+            .get_mut()
+            .write_all((request.to_string() + "\n").as_bytes())
+            .chain_err(|| format!("failed to send {}", method))?;
+
+        loop {
+            let mut line = String::new();
+            let len = self
+                .stream
+                .read_line(&mut line)
+                .chain_err(|| format!("failed to receive {} reply", method))?;
+            ensure!(len > 0, "disconnected while waiting for {} reply", method);
+
+            let mut reply: Value = serde_json::from_str(&line).chain_err(|| "invalid JSON reply")?;
+            if reply["id"].as_u64() != Some(self.next_id) {
+                continue; // skip notifications
+            }
+            if !reply["error"].is_null() {
+                bail!("{} failed: {}", method, reply["error"]);
+            }
+            return Ok(reply["result"].take());
         }
     }
-}
 
-// Convert from electrum-client's server features struct to ours. We're using a different struct because
-// the electrum-client's one doesn't support the "hosts" key.
-impl TryFrom<ServerFeaturesRes> for ServerFeatures {
-    type Error = Error;
-    fn try_from(mut features: ServerFeaturesRes) -> Result<Self, Self::Error> {
-        Ok(ServerFeatures {
-            // electrum-client doesn't retain the hosts map data, but we already have it from the add_peer request
-            hosts: HashMap::new(),
-            server_version: features.server_version,
-            protocol_min: features
-                .protocol_min
-                .parse()
-                .chain_err(|| "invalid protocol_min")?,
-            protocol_max: features
-                .protocol_max
-                .parse()
-                .chain_err(|| "invalid protocol_max")?,
-            pruning: features.pruning.map(|pruning| pruning as usize),
-            hash_function: features
-                .hash_function
-                .chain_err(|| "missing hash_function")?,
-        })
+    pub fn server_features(&mut self) -> Result<ServerFeatures> {
+        let features = self.call("server.features", json!([]))?;
+        serde_json::from_value(features).chain_err(|| "invalid server features")
     }
+
+    pub fn server_add_peer(&mut self, features: &ServerFeatures) -> Result<bool> {
+        self.call("server.add_peer", json!([features]))?
+            .as_bool()
+            .chain_err(|| "invalid server.add_peer reply")
+    }
+}
+
+fn connect<A: ToSocketAddrs>(socket_addr: A) -> Result<TcpStream> {
+    let addr = socket_addr
+        .to_socket_addrs()
+        .chain_err(|| "address resolution failed")?
+        .next()
+        .chain_err(|| "address resolution failed")?;
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+        .chain_err(|| format!("failed to connect to {}", addr))?;
+    set_timeouts(&stream)?;
+    Ok(stream)
+}
+
+fn set_timeouts(stream: &TcpStream) -> Result<()> {
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .chain_err(|| "failed to set read timeout")?;
+    stream
+        .set_write_timeout(Some(TIMEOUT))
+        .chain_err(|| "failed to set write timeout")
 }
@@ -7,7 +7,7 @@ use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use tapyrus::BlockHash;
+use sha2::{Digest, Sha256};
 
 use crate::chain::Network;
 use crate::electrum::{Client, Hostname, Port, ProtocolVersion, ServerFeatures};
@@ -46,6 +46,9 @@ pub struct DiscoveryManager {
 
     /// Optional, will not support onion hosts without this
     tor_proxy: Option<SocketAddr>,
+
+    /// The sha256 of the certificate first seen on each ssl service, which it must keep using
+    pinned_certs: RwLock<HashMap<(ServerAddr, Service), [u8; 32]>>,
 }
 
 /// A Server corresponds to a single IP address or onion hostname, with one or more services
@@ -114,6 +117,7 @@ impl DiscoveryManager {
             tor_proxy,
             healthy: Default::default(),
             queue: Default::default(),
+            pinned_certs: Default::default(),
         };
         add_default_servers(&discovery, our_network);
         discovery
@@ -277,6 +281,9 @@ impl DiscoveryManager {
                     self.queue.write().unwrap().push(job);
                 } else {
                     debug!("giving up on {:?}", job);
+                    // a server that is added again may come back with a new certificate
+                    let pin = (job.addr.clone(), job.service);
+                    self.pinned_certs.write().unwrap().remove(&pin);
                 }
 
                 Err(e)
@@ -320,7 +327,13 @@ impl DiscoveryManager {
 
         let mut client: Client = match (addr, service) {
             (ServerAddr::Clearnet(ip), Service::Tcp(port)) => Client::new((*ip, port))?,
-            (ServerAddr::Clearnet(_), Service::Ssl(port)) => Client::new_ssl((hostname, port))?,
+            (ServerAddr::Clearnet(_), Service::Ssl(port)) => {
+                // Electrum servers commonly use self-signed certificates, so the certificate is
+                // pinned on first use instead, like wallets do
+                let (client, certificate) = Client::new_ssl((hostname, port))?;
+                self.verify_pinned_cert(addr, service, &certificate)?;
+                client
+            }
             (ServerAddr::Onion(hostname), Service::Tcp(port)) => {
                 let tor_proxy = self
                     .tor_proxy
@@ -344,6 +357,27 @@ impl DiscoveryManager {
         Ok(features)
     }
 
+    /// Pin the certificate of the service if it is the first one seen, or check that it is
+    /// unchanged, so that a man in the middle can't get another server advertised in its place
+    fn verify_pinned_cert(
+        &self,
+        addr: &ServerAddr,
+        service: Service,
+        certificate: &[u8],
+    ) -> Result<()> {
+        let mut fingerprint = [0; 32];
+        fingerprint.copy_from_slice(&Sha256::digest(certificate));
+        let mut pinned_certs = self.pinned_certs.write().unwrap();
+        let pinned = pinned_certs
+            .entry((addr.clone(), service))
+            .or_insert(fingerprint);
+        ensure!(
+            *pinned == fingerprint,
+            "certificate changed since it was pinned"
+        );
+        Ok(())
+    }
+
     fn verify_compatibility(&self, features: &ServerFeatures) -> Result<()> {
         ensure!(
             features.protocol_min <= self.our_version && features.protocol_max >= self.our_version,
@@ -505,51 +539,143 @@ fn is_remote_addr(addr: &ServerAddr) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::chain::Network;
-    use std::time;
+    use serde_json::Value;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    fn features(hash_function: &str) -> ServerFeatures {
+        serde_json::from_value(json!({
+            "hosts": {},
+            "server_version": "electrs-esplora test",
+            "protocol_min": "1.4",
+            "protocol_max": "1.4",
+            "pruning": null,
+            "hash_function": hash_function,
+        }))
+        .unwrap()
+    }
+
+    // A stand-in Electrum server that answers `server.features` and `server.add_peer`
+    fn spawn_server(features: ServerFeatures) -> Port {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let features = features.clone();
+                thread::spawn(move || serve(stream.unwrap(), features));
+            }
+        });
+        port
+    }
+
+    fn serve(stream: TcpStream, features: ServerFeatures) {
+        let mut writer = stream.try_clone().unwrap();
+        for line in BufReader::new(stream).lines() {
+            let request: Value = serde_json::from_str(&line.unwrap()).unwrap();
+            let result = match request["method"].as_str().unwrap() {
+                "server.features" => json!(features),
+                "server.add_peer" => json!(true),
+                method => panic!("unexpected method {}", method),
+            };
+            let reply = json!({"jsonrpc": "2.0", "id": request["id"], "result": result});
+            writer.write_all((reply.to_string() + "\n").as_bytes()).unwrap();
+        }
+    }
+
+    fn discovery(announce: bool) -> DiscoveryManager {
+        DiscoveryManager::new(
+            Network::new("dev", 1),
+            features("sha256"),
+            ProtocolVersion::new(1, 4),
+            announce,
+            None,
+        )
+    }
 
     #[test]
-    fn test() -> Result<()> {
-        stderrlog::new().verbosity(4).init().unwrap();
+    fn test_health_check_healthy_server() {
+        let port = spawn_server(features("sha256"));
+        let discovery = discovery(true);
+        discovery
+            .add_default_server("127.0.0.1".into(), vec![Service::Tcp(port)])
+            .unwrap();
+        assert!(discovery.get_servers().is_empty());
 
-        let discovery = DiscoveryManager::new(
-            Network::Dev,
-            "1.4".parse().unwrap(),
-            Some("127.0.0.1:9150".parse().unwrap()),
-        );
+        discovery.run_health_check().unwrap();
 
-        discovery.add_default_server(
-            "electrum.blockstream.info".into(),
-            vec![Service::Tcp(60001)],
-        );
-        discovery.add_default_server("testnet.hsmiths.com".into(), vec![Service::Ssl(53012)]);
-        discovery.add_default_server(
-            "tn.not.fyi".into(),
-            vec![Service::Tcp(55001), Service::Ssl(55002)],
-        );
-        discovery.add_default_server(
-            "electrum.blockstream.info".into(),
-            vec![Service::Tcp(60001), Service::Ssl(60002)],
-        );
-        discovery.add_default_server(
-            "explorerzydxu5ecjrkwceayqybizmpjjznk5izmitf2modhcusuqlid.onion".into(),
-            vec![Service::Tcp(143)],
+        assert_eq!(
+            json!(discovery.get_servers()),
+            json!([["127.0.0.1", "127.0.0.1", ["v1.4", format!("t{}", port)]]])
         );
+    }
 
-        debug!("{:#?}", discovery);
+    #[test]
+    fn test_health_check_incompatible_server() {
+        let port = spawn_server(features("sha1"));
+        let discovery = discovery(false);
+        discovery
+            .add_default_server("127.0.0.1".into(), vec![Service::Tcp(port)])
+            .unwrap();
 
-        for _ in 0..12 {
-            discovery
-                .run_health_check()
-                .map_err(|e| warn!("{:?}", e))
-                .ok();
-            thread::sleep(time::Duration::from_secs(1));
-        }
+        assert!(discovery.run_health_check().is_err());
+        assert!(discovery.get_servers().is_empty());
+    }
 
-        debug!("{:#?}", discovery);
+    #[test]
+    fn test_health_check_unavailable_server() {
+        // bind and immediately drop a listener to get a port nobody listens on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let discovery = discovery(false);
+        discovery
+            .add_default_server("127.0.0.1".into(), vec![Service::Tcp(port)])
+            .unwrap();
 
-        info!("{}", json!(discovery.get_servers()));
+        assert!(discovery.run_health_check().is_err());
+        assert!(discovery.get_servers().is_empty());
+    }
 
-        Ok(())
+    #[test]
+    fn test_add_server_request_skips_local_servers() {
+        let discovery = discovery(false);
+        let mut features = features("sha256");
+        features.hosts = serde_json::from_value(json!({
+            "127.0.0.1": {"tcp_port": 50001, "ssl_port": null}
+        }))
+        .unwrap();
+
+        discovery
+            .add_server_request("127.0.0.1".parse().unwrap(), features)
+            .unwrap();
+        assert!(discovery.queue.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_verify_pinned_cert() {
+        let discovery = discovery(false);
+        let addr = ServerAddr::Clearnet("127.0.0.1".parse().unwrap());
+
+        // the first certificate seen is pinned
+        discovery
+            .verify_pinned_cert(&addr, Service::Ssl(50002), b"first")
+            .unwrap();
+        discovery
+            .verify_pinned_cert(&addr, Service::Ssl(50002), b"first")
+            .unwrap();
+        assert!(discovery
+            .verify_pinned_cert(&addr, Service::Ssl(50002), b"second")
+            .is_err());
+
+        // each service has its own pin
+        discovery
+            .verify_pinned_cert(&addr, Service::Ssl(50003), b"second")
+            .unwrap();
+        let other_addr = ServerAddr::Clearnet("127.0.0.2".parse().unwrap());
+        discovery
+            .verify_pinned_cert(&other_addr, Service::Ssl(50002), b"second")
+            .unwrap();
     }
 }
@@ -1,13 +1,11 @@
-use crate::chain::Network;
-use crate::electrum::discovery::{DiscoveryManager, Service};
+use crate::chain::{Network, NetworkType};
+use crate::electrum::discovery::DiscoveryManager;
 
-pub fn add_default_servers(discovery: &DiscoveryManager, network: Network) {
-    match network {
-        Network::Prod => {
-        }
-        Network::Dev => {
-        }
-
-        _ => (),
+pub fn add_default_servers(_discovery: &DiscoveryManager, network: Network) {
+    // there are no well-known public Electrum servers for Tapyrus networks yet. they should be
+    // added here with `discovery.add_default_server(hostname, vec![Service::Tcp(port), ...])`.
+    match network.network_type {
+        NetworkType::Prod => (),
+        NetworkType::Dev => (),
     }
 }
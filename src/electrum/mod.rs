@@ -1,5 +1,10 @@
+mod client;
+mod discovery;
 mod server;
-pub use server::RPC;
+mod transport;
+pub use client::Client;
+pub use discovery::DiscoveryManager;
+pub use server::{server_features, RPC};
 
 use std::cmp::Ordering;
 use std::collections::HashMap;
@@ -1,9 +1,9 @@
+use std::cmp;
 use std::collections::{HashMap, HashSet};
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpStream};
-use std::sync::mpsc::{Sender, SyncSender, TrySendError};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use sha2::{Digest, Sha256};
 use error_chain::ChainedError;
@@ -14,24 +14,48 @@ use tapyrus::blockdata::transaction::OutPoint;
 use tapyrus::consensus::encode::{deserialize, serialize};
 use tapyrus::hashes::sha256d::Hash as Sha256dHash;
 use tapyrus::Txid;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, Instant};
 
+use crate::api_keys::{ApiKey, ApiKeys};
 use crate::config::Config;
-use crate::electrum::{get_electrum_height, ProtocolVersion};
+use crate::electrum::transport::{tls_acceptor, ReplyWriter, RequestReader, Transport};
+use crate::electrum::{
+    get_electrum_height, DiscoveryManager, ProtocolVersion, ServerFeatures, ServerHosts,
+};
 use crate::errors::*;
-use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use crate::metrics::{CounterVec, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::new_index::schema::{ScriptStats, StatsMap};
 use crate::new_index::Query;
 use crate::new_index::Utxo;
 use crate::open_assets::OpenAsset;
+use crate::rate_limit::{
+    ClientId, RateLimiter, COST_DEFAULT, COST_HISTORY, COST_LIST, COST_SCRIPT,
+};
 use crate::util::electrum_merkle::{get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof};
 use crate::util::{
-    create_socket, full_hash, spawn_thread, BlockId, BoolThen, Channel, FullHash, HeaderEntry,
-    SyncChannel,
+    create_socket, full_hash, spawn_thread, BlockId, BoolThen, FullHash, HeaderEntry,
 };
 
 const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
-const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 4);
+const PROTOCOL_MIN: ProtocolVersion = ProtocolVersion::new(1, 4);
+const PROTOCOL_MAX: ProtocolVersion = ProtocolVersion::new(1, 4);
 const MAX_HEADERS: usize = 2016;
+// how many updates may be queued for a client before it recomputes all its subscriptions
+const UPDATES_CAPACITY: usize = 100;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// JSON-RPC error codes, and the application error codes used by ElectrumX
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const BAD_REQUEST: i64 = 1;
+const DAEMON_ERROR: i64 = 2;
+const EXCESSIVE_RESOURCE_USAGE: i64 = -101;
 
 // TODO: Sha256dHash should be a generic hash-container (since script hash is single SHA256)
 fn hash_from_value(val: Option<&Value>) -> Result<Sha256dHash> {
@@ -41,6 +65,37 @@ fn hash_from_value(val: Option<&Value>) -> Result<Sha256dHash> {
     Ok(script_hash)
 }
 
+fn version_from_value(val: &Value) -> Result<ProtocolVersion> {
+    val.as_str()
+        .chain_err(|| "non-string protocol version")?
+        .parse()
+        .chain_err(|| "bad protocol version")
+}
+
+// The highest protocol version supported by both the client and the server, given the version
+// or the [min, max] range of versions sent by the client with server.version
+fn negotiate_version(val: Option<&Value>) -> Result<ProtocolVersion> {
+    let (client_min, client_max) = match val {
+        None => (PROTOCOL_MIN, PROTOCOL_MIN),
+        Some(Value::Array(range)) if range.len() == 2 => (
+            version_from_value(&range[0])?,
+            version_from_value(&range[1])?,
+        ),
+        Some(version) => {
+            let version = version_from_value(version)?;
+            (version.clone(), version)
+        }
+    };
+    let version = cmp::min(client_max, PROTOCOL_MAX);
+    ensure!(
+        version >= client_min && version >= PROTOCOL_MIN,
+        "unsupported protocol version (supported: {} to {})",
+        PROTOCOL_MIN,
+        PROTOCOL_MAX
+    );
+    Ok(version)
+}
+
 fn usize_from_value(val: Option<&Value>, name: &str) -> Result<usize> {
     let val = val.chain_err(|| format!("missing {}", name))?;
     let val = val.as_u64().chain_err(|| format!("non-integer {}", name))?;
@@ -73,6 +128,7 @@ fn color_id_from_value(val: Option<&Value>, name: &str) -> Result<Option<ColorId
             .as_str()
             .chain_err(|| format!("not a string {}", name))?;
         let val = hex::decode(val).chain_err(|| format!("invalie hex format: {}", name))?;
+        ensure!(val.len() == 33, "invalid length: {}", name);
         let color_id: ColorIdentifier =
             deserialize(&val[0..33]).chain_err(|| format!("decode failed: {}", name))?;
         Ok(Some(color_id))
@@ -81,6 +137,18 @@ fn color_id_from_value(val: Option<&Value>, name: &str) -> Result<Option<ColorId
     }
 }
 
+/// The features we advertise via `server.features`, for the given public hosts
+pub fn server_features(hosts: ServerHosts) -> ServerFeatures {
+    ServerFeatures {
+        hosts,
+        server_version: format!("electrs-esplora {}", ELECTRS_VERSION),
+        protocol_min: PROTOCOL_MIN,
+        protocol_max: PROTOCOL_MAX,
+        pruning: None,
+        hash_function: "sha256".to_string(),
+    }
+}
+
 // TODO: implement caching and delta updates
 fn get_status_hash(txs: Vec<(Txid, Option<BlockId>)>, query: &Query) -> Option<FullHash> {
     if txs.is_empty() {
@@ -102,37 +170,110 @@ fn get_status_hash(txs: Vec<(Txid, Option<BlockId>)>, query: &Query) -> Option<F
     }
 }
 
+// The number of rate limiter tokens spent by a method, by how much work it may take
+fn method_cost(method: &str) -> u32 {
+    match method {
+        "blockchain.scripthash.get_history"
+        | "blockchain.scripthash.get_mempool"
+        | "blockchain.scripthash.subscribe" => COST_HISTORY,
+        "blockchain.scripthash.get_balance"
+        | "blockchain.scripthash.listunspent"
+        | "blockchain.scripthash.listcoloredunspent"
+        | "blockchain.scripthash.listuncoloredunspent"
+        | "blockchain.openassets.scripthash.listunspent"
+        | "blockchain.openassets.scripthash.listcoloredunspent"
+        | "blockchain.openassets.scripthash.listuncoloredunspent" => COST_SCRIPT,
+        "blockchain.block.headers" | "blockchain.transaction.get_merkle" => COST_LIST,
+        _ => COST_DEFAULT,
+    }
+}
+
+fn error_reply(id: &Value, code: i64, message: String) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+// The first error of the chain with a kind other than a plain message
+fn error_kind(e: &Error) -> &ErrorKind {
+    let mut e = e;
+    while let ErrorKind::Msg(_) = e.kind() {
+        let next = e.1.next_error.as_ref();
+        match next.and_then(|next| next.downcast_ref::<Error>()) {
+            Some(next) => e = next,
+            None => break,
+        }
+    }
+    e.kind()
+}
+
+fn error_code(e: &Error) -> i64 {
+    match error_kind(e) {
+        ErrorKind::Daemon(..) | ErrorKind::Connection(_) => DAEMON_ERROR,
+        ErrorKind::TooPopular | ErrorKind::LimitExceeded(_) | ErrorKind::RateLimited(_) => {
+            EXCESSIVE_RESOURCE_USAGE
+        }
+        _ => BAD_REQUEST,
+    }
+}
+
+// Daemon errors are reported with the message of tapyrusd, e.g. the reason a tx was rejected
+fn error_message(e: &Error) -> String {
+    match error_kind(e) {
+        ErrorKind::Daemon(_, err) => err
+            .get("message")
+            .and_then(Value::as_str)
+            .map_or_else(|| e.to_string(), str::to_owned),
+        _ => e.to_string(),
+    }
+}
+
 struct Connection {
     query: Arc<Query>,
     last_header_entry: Option<HeaderEntry>,
-    status_hashes: HashMap<Sha256dHash, Value>, // ScriptHash -> StatusHash
-    stream: TcpStream,
+    // (ScriptHash, ColorIdentifier) -> StatusHash, without a color for unscoped subscriptions
+    status_hashes: HashMap<(Sha256dHash, Option<ColorIdentifier>), Value>,
     addr: SocketAddr,
-    chan: SyncChannel<Message>,
+    sender: mpsc::Sender<Message>,
+    receiver: Option<mpsc::Receiver<Message>>, // taken by run()
     stats: Arc<Stats>,
     txs_limit: usize,
+    max_subscriptions: usize,
     enable_open_assets: bool,
+    discovery: Option<Arc<DiscoveryManager>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    api_keys: Option<Arc<ApiKeys>>,
+    require_api_key: bool,
+    api_key: Option<Arc<ApiKey>>, // set by server.authenticate
+    protocol_version: Option<ProtocolVersion>, // set by server.version
 }
 
 impl Connection {
     pub fn new(
         query: Arc<Query>,
-        stream: TcpStream,
         addr: SocketAddr,
         stats: Arc<Stats>,
-        txs_limit: usize,
-        enable_open_assets: bool,
+        config: &Config,
+        discovery: Option<Arc<DiscoveryManager>>,
+        rate_limiter: Option<Arc<RateLimiter>>,
+        api_keys: Option<Arc<ApiKeys>>,
     ) -> Connection {
+        let (sender, receiver) = mpsc::channel(10);
         Connection {
             query,
             last_header_entry: None, // disable header subscription for now
             status_hashes: HashMap::new(),
-            stream,
             addr,
-            chan: SyncChannel::new(10),
+            sender,
+            receiver: Some(receiver),
             stats,
-            txs_limit,
-            enable_open_assets,
+            txs_limit: config.electrum_txs_limit,
+            max_subscriptions: config.electrum_max_subscriptions,
+            enable_open_assets: config.enable_open_assets,
+            discovery,
+            rate_limiter,
+            api_keys,
+            require_api_key: config.require_api_key,
+            api_key: None,
+            protocol_version: None,
         }
     }
 
@@ -144,13 +285,33 @@ impl Connection {
         Ok(result)
     }
 
-    fn server_version(&self) -> Result<Value> {
+    fn server_version(&mut self, params: &[Value]) -> Result<Value> {
+        // the version is negotiated once per connection
+        ensure!(
+            self.protocol_version.is_none(),
+            "server.version already sent"
+        );
+        let version = negotiate_version(params.get(1))?;
+        self.protocol_version = Some(version.clone());
         Ok(json!([
             format!("electrs-esplora {}", ELECTRS_VERSION),
-            PROTOCOL_VERSION
+            version
         ]))
     }
 
+    fn server_authenticate(&mut self, params: &[Value]) -> Result<Value> {
+        let api_keys = self
+            .api_keys
+            .as_ref()
+            .chain_err(|| "API keys are disabled")?;
+        let key = params.get(0).chain_err(|| "missing API key")?;
+        let key = key.as_str().chain_err(|| "non-string API key")?;
+        let api_key = api_keys.get(key).chain_err(|| "invalid API key")?;
+        debug!("[{}] authenticated as {}", self.addr, api_key.name);
+        self.api_key = Some(api_key);
+        Ok(Value::Bool(true))
+    }
+
     fn server_banner(&self) -> Result<Value> {
         Ok(json!(self.query.config().electrum_banner.clone()))
     }
@@ -160,15 +321,39 @@ impl Connection {
     }
 
     fn server_peers_subscribe(&self) -> Result<Value> {
-        let servers = json!([]);
+        Ok(match self.discovery {
+            Some(ref discovery) => json!(discovery.get_servers()),
+            None => json!([]),
+        })
+    }
 
-        Ok(servers)
+    fn server_features(&self) -> Result<Value> {
+        Ok(match self.discovery {
+            Some(ref discovery) => json!(discovery.our_features()),
+            None => json!(server_features(ServerHosts::new())),
+        })
+    }
+
+    fn server_add_peer(&self, params: &[Value]) -> Result<Value> {
+        let discovery = self
+            .discovery
+            .as_ref()
+            .chain_err(|| "server discovery is disabled")?;
+        let features = params.get(0).chain_err(|| "missing features")?;
+        let features: ServerFeatures =
+            serde_json::from_value(features.clone()).chain_err(|| "invalid features")?;
+        discovery.add_server_request(self.addr.ip(), features)?;
+        Ok(json!(true))
     }
 
     fn mempool_get_fee_histogram(&self) -> Result<Value> {
         Ok(json!(&self.query.mempool().backlog_stats().fee_histogram))
     }
 
+    fn mempool_get_info(&self) -> Result<Value> {
+        Ok(json!(self.query.get_mempool_info()?))
+    }
+
     fn blockchain_block_header(&self, params: &[Value]) -> Result<Value> {
         let height = usize_from_value(params.get(0), "height")?;
         let cp_height = usize_from_value_or(params.get(1), "cp_height", 0)?;
@@ -362,17 +547,53 @@ impl Connection {
 
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let color_id =
+            color_id_from_value(params.get(1), "color_id").chain_err(|| "bad color_id")?;
 
-        let history_txids = get_history(&self.query, &script_hash[..], self.txs_limit)?;
+        let key = (script_hash, color_id.clone());
+        if !self.status_hashes.contains_key(&key)
+            && self.status_hashes.len() >= self.max_subscriptions
+        {
+            self.stats
+                .limits
+                .with_label_values(&["max_subscriptions"])
+                .inc();
+            bail!(ErrorKind::LimitExceeded(format!(
+                "too many subscriptions (max {})",
+                self.max_subscriptions
+            )));
+        }
+
+        let history_txids = get_history(
+            &self.query,
+            &script_hash[..],
+            color_id.as_ref(),
+            self.txs_limit,
+        )?;
         let status_hash = get_status_hash(history_txids, &self.query)
             .map_or(Value::Null, |h| json!(hex::encode(full_hash(&h[..]))));
 
-        if let None = self.status_hashes.insert(script_hash, status_hash.clone()) {
+        if let None = self.status_hashes.insert(key, status_hash.clone()) {
             self.stats.subscriptions.inc();
         }
         Ok(status_hash)
     }
 
+    fn blockchain_scripthash_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let color_id =
+            color_id_from_value(params.get(1), "color_id").chain_err(|| "bad color_id")?;
+
+        let removed = self
+            .status_hashes
+            .remove(&(script_hash, color_id))
+            .is_some();
+        if removed {
+            self.stats.subscriptions.dec();
+        }
+        Ok(json!(removed))
+    }
+
     fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
         let (chain_stats, mempool_stats) = self.query.stats(&script_hash[..]);
@@ -395,7 +616,14 @@ impl Connection {
 
     fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
-        let history_txids = get_history(&self.query, &script_hash[..], self.txs_limit)?;
+        let color_id =
+            color_id_from_value(params.get(1), "color_id").chain_err(|| "bad color_id")?;
+        let history_txids = get_history(
+            &self.query,
+            &script_hash[..],
+            color_id.as_ref(),
+            self.txs_limit,
+        )?;
 
         Ok(json!(history_txids
             .into_iter()
@@ -411,6 +639,32 @@ impl Connection {
             .collect::<Vec<_>>()))
     }
 
+    fn blockchain_scripthash_get_mempool(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let color_id =
+            color_id_from_value(params.get(1), "color_id").chain_err(|| "bad color_id")?;
+        let txids = {
+            let mempool = self.query.mempool();
+            match color_id {
+                Some(ref color_id) => {
+                    mempool.history_txids_by_color(&script_hash[..], color_id, self.txs_limit + 1)
+                }
+                None => mempool.history_txids(&script_hash[..], self.txs_limit + 1),
+            }
+        };
+        ensure!(txids.len() <= self.txs_limit, ErrorKind::TooPopular);
+
+        Ok(json!(txids
+            .into_iter()
+            .map(|txid| {
+                let fee = self.query.get_mempool_tx_fee(&txid);
+                let has_unconfirmed_parents = self.query.has_unconfirmed_parents(&txid);
+                let height = get_electrum_height(None, has_unconfirmed_parents);
+                GetHistoryResult { txid, height, fee }
+            })
+            .collect::<Vec<_>>()))
+    }
+
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
         let utxos = self.query.utxo(&script_hash[..])?;
@@ -426,12 +680,14 @@ impl Connection {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
         let color_id =
             color_id_from_value(params.get(1), "color_id").chain_err(|| "bad color_id")?;
-        let utxos = self.query.utxo(&script_hash[..])?;
+        let utxos = match color_id {
+            Some(ref color_id) => self.query.utxo_by_color(&script_hash[..], color_id)?,
+            None => self.query.utxo(&script_hash[..])?,
+        };
         Ok(json!(Value::Array(
             utxos
                 .into_iter()
-                .filter(|o| o.color_id.is_colored()
-                    && (color_id.is_none() || color_id == Some(o.color_id.clone())))
+                .filter(|o| o.color_id.is_colored())
                 .map(|utxo| self.utxo_to_json(&utxo, None))
                 .collect()
         )))
@@ -453,7 +709,7 @@ impl Connection {
         let tx = params.get(0).chain_err(|| "missing tx")?;
         let tx = tx.as_str().chain_err(|| "non-string tx")?.to_string();
         let txid = self.query.broadcast_raw(&tx)?;
-        if let Err(e) = self.chan.sender().try_send(Message::PeriodicUpdate) {
+        if let Err(e) = self.sender.try_send(Message::PeriodicUpdate(None)) {
             warn!("failed to issue PeriodicUpdate after broadcast: {}", e);
         }
         Ok(json!(txid))
@@ -466,9 +722,11 @@ impl Connection {
             None => false,
         };
 
-        // FIXME: implement verbose support
         if verbose {
-            bail!("verbose transactions are currently unsupported");
+            return self
+                .query
+                .lookup_txn_verbose(&tx_hash)
+                .chain_err(|| "missing transaction");
         }
 
         let tx = self
@@ -513,7 +771,13 @@ impl Connection {
             "merkle" : merkle}))
     }
 
-    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Value> {
+    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Value {
+        if let Err(e) = self
+            .authorize(method)
+            .and_then(|()| self.check_rate_limit(method))
+        {
+            return error_reply(id, error_code(&e), error_message(&e));
+        }
         let timer = self
             .stats
             .latency
@@ -536,6 +800,7 @@ impl Connection {
             "blockchain.relayfee" => self.blockchain_relayfee(),
             "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
             "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
+            "blockchain.scripthash.get_mempool" => self.blockchain_scripthash_get_mempool(&params),
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(&params),
             "blockchain.scripthash.listcoloredunspent" => {
                 self.blockchain_scripthash_listcoloredunspent(&params)
@@ -544,6 +809,7 @@ impl Connection {
                 self.blockchain_scripthash_listuncoloredunspent(&params)
             }
             "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(&params),
+            "blockchain.scripthash.unsubscribe" => self.blockchain_scripthash_unsubscribe(&params),
             "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(&params),
             "blockchain.transaction.get" => self.blockchain_transaction_get(&params),
             "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(&params),
@@ -551,17 +817,23 @@ impl Connection {
                 self.blockchain_transaction_id_from_pos(&params)
             }
             "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
+            "mempool.get_info" => self.mempool_get_info(),
+            "server.add_peer" => self.server_add_peer(&params),
+            "server.authenticate" => self.server_authenticate(&params),
             "server.banner" => self.server_banner(),
             "server.donation_address" => self.server_donation_address(),
+            "server.features" => self.server_features(),
             "server.peers.subscribe" => self.server_peers_subscribe(),
             "server.ping" => Ok(Value::Null),
-            "server.version" => self.server_version(),
+            "server.version" => self.server_version(&params),
 
-            &_ => bail!("unknown method {} {:?}", method, params),
+            &_ => {
+                let message = format!("unknown method {}", method);
+                return error_reply(id, METHOD_NOT_FOUND, message);
+            }
         };
         timer.observe_duration();
-        // TODO: return application errors should be sent to the client
-        Ok(match result {
+        match result {
             Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
             Err(e) => {
                 warn!(
@@ -571,12 +843,88 @@ impl Connection {
                     params,
                     e.display_chain()
                 );
-                json!({"jsonrpc": "2.0", "id": id, "error": format!("{}", e)})
+                error_reply(id, error_code(&e), error_message(&e))
             }
-        })
+        }
+    }
+
+    // Methods must be in the scopes of the client's API key. Without a key, only the server.*
+    // methods (including server.authenticate) are allowed when keys are required.
+    fn authorize(&self, method: &str) -> Result<()> {
+        match self.api_key {
+            Some(ref api_key) => ensure!(
+                api_key.allows(method),
+                "API key {} is not allowed to call {}",
+                api_key.name,
+                method
+            ),
+            None => ensure!(
+                !self.require_api_key || method.starts_with("server."),
+                "API key required, see server.authenticate"
+            ),
+        }
+        Ok(())
+    }
+
+    // Spend the tokens of the client for the method, failing when they are exhausted. Clients
+    // authenticated with an API key are accounted to the key.
+    fn check_rate_limit(&self, method: &str) -> Result<()> {
+        if let Some(ref rate_limiter) = self.rate_limiter {
+            let (client, quota) = match self.api_key {
+                Some(ref api_key) => (ClientId::ApiKey(api_key.name.clone()), api_key.quota),
+                None => (ClientId::Ip(self.addr.ip()), None),
+            };
+            let cost = method_cost(method);
+            if let Err(retry_after) = rate_limiter.check(&client, cost, quota.as_ref(), "electrum")
+            {
+                bail!(ErrorKind::RateLimited(retry_after));
+            }
+        }
+        Ok(())
     }
 
-    fn update_subscriptions(&mut self) -> Result<Vec<Value>> {
+    // Requests without an id are notifications, which are not replied to
+    fn handle_request(&mut self, cmd: &Value) -> Option<Value> {
+        let empty_params = json!([]);
+        let id = cmd.get("id");
+        let params = cmd.get("params").unwrap_or(&empty_params);
+        match (cmd.get("method"), params) {
+            (Some(Value::String(method)), Value::Array(params)) => {
+                let reply = self.handle_command(method, params, id.unwrap_or(&Value::Null));
+                id.map(|_| reply)
+            }
+            _ => Some(error_reply(
+                id.unwrap_or(&Value::Null),
+                INVALID_REQUEST,
+                format!("invalid request: {}", cmd),
+            )),
+        }
+    }
+
+    // A line holds either a single request or a batch of them, whose replies are sent together
+    fn handle_line(&mut self, line: &str) -> Option<Value> {
+        match from_str(line) {
+            Ok(Value::Array(cmds)) => {
+                if cmds.is_empty() {
+                    let message = "empty batch".to_owned();
+                    return Some(error_reply(&Value::Null, INVALID_REQUEST, message));
+                }
+                let replies: Vec<Value> = cmds
+                    .iter()
+                    .filter_map(|cmd| self.handle_request(cmd))
+                    .collect();
+                (!replies.is_empty()).and_then(|| Some(Value::Array(replies)))
+            }
+            Ok(cmd) => self.handle_request(&cmd),
+            Err(e) => {
+                let message = format!("invalid JSON: {}", e);
+                Some(error_reply(&Value::Null, PARSE_ERROR, message))
+            }
+        }
+    }
+
+    // Recompute the status of the subscribed scripthashes, or only of the touched ones when known
+    fn update_subscriptions(&mut self, touched: Option<&HashSet<FullHash>>) -> Result<Vec<Value>> {
         let timer = self
             .stats
             .latency
@@ -595,115 +943,184 @@ impl Connection {
                     "params": [header]}));
             }
         }
-        for (script_hash, status_hash) in self.status_hashes.iter_mut() {
-            let history_txids = get_history(&self.query, &script_hash[..], self.txs_limit)?;
+        let (mut computed, mut skipped) = (0, 0);
+        for ((script_hash, color_id), status_hash) in self.status_hashes.iter_mut() {
+            // color-scoped subscriptions are touched through the uncolored scripthash
+            if let Some(touched) = touched {
+                if !touched.contains(&full_hash(&script_hash[..])) {
+                    skipped += 1;
+                    continue;
+                }
+            }
+            computed += 1;
+            let history_txids = get_history(
+                &self.query,
+                &script_hash[..],
+                color_id.as_ref(),
+                self.txs_limit,
+            )?;
             let new_status_hash = get_status_hash(history_txids, &self.query)
                 .map_or(Value::Null, |h| json!(hex::encode(full_hash(&h[..]))));
             if new_status_hash == *status_hash {
                 continue;
             }
+            // color-scoped subscriptions are told apart by their trailing color_id param
+            let params = match color_id {
+                Some(color_id) => json!([script_hash, new_status_hash, color_id]),
+                None => json!([script_hash, new_status_hash]),
+            };
             result.push(json!({
                 "jsonrpc": "2.0",
                 "method": "blockchain.scripthash.subscribe",
-                "params": [script_hash, new_status_hash]}));
+                "params": params}));
             *status_hash = new_status_hash;
         }
+        self.stats
+            .status_updates
+            .with_label_values(&["computed"])
+            .inc_by(computed);
+        self.stats
+            .status_updates
+            .with_label_values(&["skipped"])
+            .inc_by(skipped);
         timer.observe_duration();
         Ok(result)
     }
 
-    fn send_values(&mut self, values: &[Value]) -> Result<()> {
-        for value in values {
-            let line = value.to_string() + "\n";
-            self.stream
-                .write_all(line.as_bytes())
-                .chain_err(|| format!("failed to send {}", value))?;
-        }
-        Ok(())
+    // Queries may block on RocksDB or on the daemon, so they run on the blocking thread pool
+    async fn blocking<T, F>(conn: &Arc<Mutex<Connection>>, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = Arc::clone(conn);
+        task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .chain_err(|| "connection task failed")
     }
 
-    fn handle_replies(&mut self) -> Result<()> {
-        let empty_params = json!([]);
+    async fn handle_replies(
+        conn: &Arc<Mutex<Connection>>,
+        writer: &mut ReplyWriter,
+        mut receiver: mpsc::Receiver<Message>,
+        mut updates: broadcast::Receiver<Arc<HashSet<FullHash>>>,
+        mut shutdown: watch::Receiver<bool>,
+        idle_timeout: Duration,
+    ) -> Result<()> {
+        let (addr, stats) = {
+            let conn = conn.lock().unwrap();
+            (conn.addr, Arc::clone(&conn.stats))
+        };
+        let mut idle_deadline = Instant::now() + idle_timeout;
         loop {
-            let msg = self.chan.receiver().recv().chain_err(|| "channel closed")?;
+            let msg = tokio::select! {
+                msg = receiver.recv() => msg.chain_err(|| "channel closed")?,
+                touched = updates.recv() => Message::from_update(touched),
+                _ = time::sleep_until(idle_deadline) => {
+                    debug!("[{}] idle for {:?}", addr, idle_timeout);
+                    stats.limits.with_label_values(&["idle_timeout"]).inc();
+                    Message::Done
+                }
+                _ = shutdown.changed() => Message::Done,
+            };
             trace!("RPC {:?}", msg);
-            match msg {
+            let values = match msg {
                 Message::Request(line) => {
-                    let cmd: Value = from_str(&line).chain_err(|| "invalid JSON format")?;
-                    let reply = match (
-                        cmd.get("method"),
-                        cmd.get("params").unwrap_or_else(|| &empty_params),
-                        cmd.get("id"),
-                    ) {
-                        (
-                            Some(&Value::String(ref method)),
-                            &Value::Array(ref params),
-                            Some(ref id),
-                        ) => self.handle_command(method, params, id)?,
-                        _ => bail!("invalid command: {}", cmd),
-                    };
-                    self.send_values(&[reply])?
+                    idle_deadline = Instant::now() + idle_timeout;
+                    let reply = Connection::blocking(conn, move |conn| conn.handle_line(&line));
+                    reply.await?.into_iter().collect()
                 }
-                Message::PeriodicUpdate => {
-                    let values = self
-                        .update_subscriptions()
+                Message::PeriodicUpdate(touched) => {
+                    let values = Connection::blocking(conn, move |conn| {
+                        conn.update_subscriptions(touched.as_deref())
+                    });
+                    let values = values
+                        .await?
                         .chain_err(|| "failed to update subscriptions")?;
-                    self.send_values(&values)?
+                    // a subscribed client may only listen, it isn't idle while it is notified
+                    if !values.is_empty() {
+                        idle_deadline = Instant::now() + idle_timeout;
+                    }
+                    values
                 }
                 Message::Done => return Ok(()),
+            };
+            for value in values {
+                writer
+                    .write_reply(&value.to_string())
+                    .await
+                    .chain_err(|| format!("failed to send {}", value))?;
             }
         }
     }
 
-    fn handle_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+    async fn handle_requests(mut reader: RequestReader, tx: mpsc::Sender<Message>) -> Result<()> {
         loop {
-            let mut line = Vec::<u8>::new();
-            reader
-                .read_until(b'\n', &mut line)
-                .chain_err(|| "failed to read a request")?;
-            if line.is_empty() {
-                tx.send(Message::Done).chain_err(|| "channel closed")?;
-                return Ok(());
-            } else {
+            if let Some(line) = reader.read_request().await? {
                 if line.starts_with(&[22, 3, 1]) {
                     // (very) naive SSL handshake detection
-                    let _ = tx.send(Message::Done);
+                    let _ = tx.send(Message::Done).await;
                     bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
                 }
                 match String::from_utf8(line) {
                     Ok(req) => tx
                         .send(Message::Request(req))
+                        .await
                         .chain_err(|| "channel closed")?,
                     Err(err) => {
-                        let _ = tx.send(Message::Done);
+                        let _ = tx.send(Message::Done).await;
                         bail!("invalid UTF8: {}", err)
                     }
                 }
+            } else {
+                tx.send(Message::Done)
+                    .await
+                    .chain_err(|| "channel closed")?;
+                return Ok(());
             }
         }
     }
 
-    pub fn run(mut self) {
-        self.stats.clients.inc();
-        let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
-        let tx = self.chan.sender();
-        let child = spawn_thread("reader", || Connection::handle_requests(reader, tx));
-        if let Err(e) = self.handle_replies() {
+    pub async fn run(
+        mut self,
+        reader: RequestReader,
+        mut writer: ReplyWriter,
+        updates: broadcast::Receiver<Arc<HashSet<FullHash>>>,
+        shutdown: watch::Receiver<bool>,
+        idle_timeout: Duration,
+    ) {
+        let (addr, stats) = (self.addr, Arc::clone(&self.stats));
+        stats.clients.inc();
+        let receiver = self.receiver.take().expect("connection is already running");
+        let child = tokio::spawn(Connection::handle_requests(reader, self.sender.clone()));
+        let conn = Arc::new(Mutex::new(self));
+        let result = Connection::handle_replies(
+            &conn,
+            &mut writer,
+            receiver,
+            updates,
+            shutdown,
+            idle_timeout,
+        )
+        .await;
+        if let Err(e) = result {
             error!(
                 "[{}] connection handling failed: {}",
-                self.addr,
+                addr,
                 e.display_chain().to_string()
             );
         }
-        self.stats.clients.dec();
-        self.stats
+        stats.clients.dec();
+        stats
             .subscriptions
-            .sub(self.status_hashes.len() as i64);
-
-        debug!("[{}] shutting down connection", self.addr);
-        let _ = self.stream.shutdown(Shutdown::Both);
-        if let Err(err) = child.join().expect("receiver panicked") {
-            error!("[{}] receiver failed: {}", self.addr, err);
+            .sub(conn.lock().unwrap().status_hashes.len() as i64);
+
+        debug!("[{}] shutting down connection", addr);
+        writer.shutdown().await;
+        // the reader may still be waiting for a request, e.g. after an idle timeout
+        child.abort();
+        if let Ok(Err(err)) = child.await {
+            error!("[{}] receiver failed: {}", addr, err);
         }
     }
 }
@@ -711,10 +1128,14 @@ impl Connection {
 fn get_history(
     query: &Query,
     scripthash: &[u8],
+    color_id: Option<&ColorIdentifier>,
     txs_limit: usize,
 ) -> Result<Vec<(Txid, Option<BlockId>)>> {
     // to avoid silently trunacting history entries, ask for one extra more than the limit and fail if it exists
-    let history_txids = query.history_txids(scripthash, txs_limit + 1);
+    let history_txids = match color_id {
+        Some(color_id) => query.history_txids_by_color(scripthash, color_id, txs_limit + 1),
+        None => query.history_txids(scripthash, txs_limit + 1),
+    };
     ensure!(history_txids.len() <= txs_limit, ErrorKind::TooPopular);
     Ok(history_txids)
 }
@@ -731,17 +1152,25 @@ struct GetHistoryResult {
 #[derive(Debug)]
 pub enum Message {
     Request(String),
-    PeriodicUpdate,
+    // the scripthashes touched since the previous update, or None to update all subscriptions
+    PeriodicUpdate(Option<Arc<HashSet<FullHash>>>),
     Done,
 }
 
-pub enum Notification {
-    Periodic,
-    Exit,
+impl Message {
+    fn from_update(update: std::result::Result<Arc<HashSet<FullHash>>, RecvError>) -> Self {
+        match update {
+            Ok(touched) => Message::PeriodicUpdate(Some(touched)),
+            // some updates were missed, so all the subscriptions must be recomputed
+            Err(RecvError::Lagged(_)) => Message::PeriodicUpdate(None),
+            Err(RecvError::Closed) => Message::Done,
+        }
+    }
 }
 
 pub struct RPC {
-    notification: Sender<Notification>,
+    updates: broadcast::Sender<Arc<HashSet<FullHash>>>,
+    shutdown: watch::Sender<bool>,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
 }
 
@@ -749,58 +1178,12 @@ struct Stats {
     latency: HistogramVec,
     clients: Gauge,
     subscriptions: Gauge,
+    status_updates: CounterVec,
+    limits: CounterVec,
 }
 
-impl RPC {
-    fn start_notifier(
-        notification: Channel<Notification>,
-        senders: Arc<Mutex<Vec<SyncSender<Message>>>>,
-        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
-    ) {
-        spawn_thread("notification", move || {
-            for msg in notification.receiver().iter() {
-                let mut senders = senders.lock().unwrap();
-                match msg {
-                    Notification::Periodic => {
-                        for sender in senders.split_off(0) {
-                            if let Err(TrySendError::Disconnected(_)) =
-                                sender.try_send(Message::PeriodicUpdate)
-                            {
-                                continue;
-                            }
-                            senders.push(sender);
-                        }
-                    }
-                    Notification::Exit => acceptor.send(None).unwrap(), // mark acceptor as done
-                }
-            }
-        });
-    }
-
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
-        let chan = Channel::unbounded();
-        let acceptor = chan.sender();
-        spawn_thread("acceptor", move || {
-            let socket = create_socket(&addr);
-            socket.listen(511).expect("setting backlog failed");
-            socket
-                .set_nonblocking(false)
-                .expect("cannot set nonblocking to false");
-            let listener = socket.into_tcp_listener();
-
-            info!("Electrum RPC server running on {}", addr);
-            loop {
-                let (stream, addr) = listener.accept().expect("accept failed");
-                stream
-                    .set_nonblocking(false)
-                    .expect("failed to set connection as blocking");
-                acceptor.send(Some((stream, addr))).expect("send failed");
-            }
-        });
-        chan
-    }
-
-    pub fn start(config: Arc<Config>, query: Arc<Query>, metrics: &Metrics) -> RPC {
+impl Stats {
+    fn new(metrics: &Metrics) -> Arc<Self> {
         let stats = Arc::new(Stats {
             latency: metrics.histogram_vec(
                 HistogramOpts::new("electrum_rpc", "Electrum RPC latency (seconds)"),
@@ -811,89 +1194,474 @@ impl RPC {
                 "electrum_subscriptions",
                 "# of Electrum subscriptions",
             )),
+            status_updates: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrum_status_updates",
+                    "# of subscription status hashes recomputed or skipped as untouched",
+                ),
+                &["type"],
+            ),
+            limits: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrum_limits",
+                    "# of Electrum connections or subscriptions refused or closed by a limit",
+                ),
+                &["limit"],
+            ),
         });
         stats.clients.set(0);
         stats.subscriptions.set(0);
+        stats
+    }
+}
 
-        let notification = Channel::unbounded();
-        let rpc_addr = config.electrum_rpc_addr;
-        let txs_limit = config.electrum_txs_limit;
-        let enable_open_assets = config.enable_open_assets;
+// The number of open connections, in total and per IP address
+struct ConnectionLimits {
+    max_connections: usize,
+    max_connections_per_ip: Option<usize>,
+    open: Mutex<(usize, HashMap<IpAddr, usize>)>,
+}
 
-        RPC {
-            notification: notification.sender(),
-            server: Some(spawn_thread("rpc", move || {
-                let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
-
-                let acceptor = RPC::start_acceptor(rpc_addr);
-                RPC::start_notifier(notification, senders.clone(), acceptor.sender());
-
-                let mut threads = HashMap::new();
-                let (garbage_sender, garbage_receiver) = crossbeam_channel::unbounded();
-
-                while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
-                    // explicitely scope the shadowed variables for the new thread
-                    let query = Arc::clone(&query);
-                    let senders = Arc::clone(&senders);
-                    let stats = Arc::clone(&stats);
-                    let garbage_sender = garbage_sender.clone();
-                    let spawned = spawn_thread("peer", move || {
-                        info!("[{}] connected peer", addr);
-                        let conn = Connection::new(
-                            query,
-                            stream,
-                            addr,
-                            stats,
-                            txs_limit,
-                            enable_open_assets,
-                        );
-                        senders.lock().unwrap().push(conn.chan.sender());
-                        conn.run();
-                        info!("[{}] disconnected peer", addr);
-                        let _ = garbage_sender.send(std::thread::current().id());
-                    });
+// Held by a connection for as long as it is open
+struct ConnectionPermit {
+    limits: Arc<ConnectionLimits>,
+    ip: IpAddr,
+}
 
-                    trace!("[{}] spawned {:?}", addr, spawned.thread().id());
-                    threads.insert(spawned.thread().id(), spawned);
-                    while let Ok(id) = garbage_receiver.try_recv() {
-                        if let Some(thread) = threads.remove(&id) {
-                            trace!("[{}] joining {:?}", addr, id);
-                            if let Err(error) = thread.join() {
-                                error!("failed to join {:?}: {:?}", id, error);
-                            }
+impl ConnectionLimits {
+    fn new(max_connections: usize, max_connections_per_ip: Option<usize>) -> Arc<Self> {
+        Arc::new(ConnectionLimits {
+            max_connections,
+            max_connections_per_ip,
+            open: Mutex::new((0, HashMap::new())),
+        })
+    }
+
+    // Fails with the name of the exceeded limit
+    fn acquire(
+        self: &Arc<Self>,
+        ip: IpAddr,
+    ) -> std::result::Result<ConnectionPermit, &'static str> {
+        let mut open = self.open.lock().unwrap();
+        let (total, per_ip) = &mut *open;
+        if *total >= self.max_connections {
+            return Err("max_connections");
+        }
+        let count = per_ip.get(&ip).cloned().unwrap_or(0);
+        if self
+            .max_connections_per_ip
+            .map_or(false, |max| count >= max)
+        {
+            return Err("max_connections_per_ip");
+        }
+        *total += 1;
+        per_ip.insert(ip, count + 1);
+        Ok(ConnectionPermit {
+            limits: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap();
+        let (total, per_ip) = &mut *open;
+        *total -= 1;
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tokio::main]
+async fn run_server(
+    config: Arc<Config>,
+    query: Arc<Query>,
+    stats: Arc<Stats>,
+    discovery: Option<Arc<DiscoveryManager>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    api_keys: Option<Arc<ApiKeys>>,
+    updates: broadcast::Sender<Arc<HashSet<FullHash>>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let (acceptor, mut accepted) = mpsc::unbounded_channel();
+    for (addr, transport) in RPC::listeners(&config) {
+        let socket = create_socket(&addr);
+        socket.listen(511).expect("setting backlog failed");
+        socket
+            .set_nonblocking(true)
+            .expect("cannot set nonblocking to true");
+        let listener =
+            TcpListener::from_std(socket.into_tcp_listener()).expect("failed to create listener");
+
+        info!("Electrum RPC server running on {} ({})", addr, transport);
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        if acceptor.send((stream, addr, transport.clone())).is_err() {
+                            break;
                         }
                     }
+                    Err(e) => {
+                        // e.g. too many open files, which may be resolved once clients disconnect
+                        warn!("accept failed: {}", e);
+                        time::sleep(ACCEPT_RETRY_DELAY).await;
+                    }
                 }
+            }
+        });
+    }
 
-                trace!("closing {} RPC connections", senders.lock().unwrap().len());
-                for sender in senders.lock().unwrap().iter() {
-                    let _ = sender.send(Message::Done);
+    let limits = ConnectionLimits::new(
+        config.electrum_max_connections,
+        config.electrum_max_connections_per_ip,
+    );
+    let idle_timeout = Duration::from_secs(config.electrum_idle_timeout);
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, addr, transport) = tokio::select! {
+            Some((stream, addr, transport)) = accepted.recv() => (stream, addr, transport),
+            Some(result) = connections.join_next(), if !connections.is_empty() => {
+                if let Err(error) = result {
+                    error!("connection task failed: {}", error);
                 }
-
-                for (id, thread) in threads {
-                    trace!("joining {:?}", id);
-                    if let Err(error) = thread.join() {
-                        error!("failed to join {:?}: {:?}", id, error);
-                    }
+                continue;
+            }
+            _ = shutdown.changed() => break,
+        };
+        let permit = match limits.acquire(addr.ip()) {
+            Ok(permit) => permit,
+            Err(limit) => {
+                warn!("[{}] refused connection: {} reached", addr, limit);
+                stats.limits.with_label_values(&[limit]).inc();
+                continue;
+            }
+        };
+        let conn = Connection::new(
+            Arc::clone(&query),
+            addr,
+            Arc::clone(&stats),
+            &config,
+            discovery.clone(),
+            rate_limiter.clone(),
+            api_keys.clone(),
+        );
+        let updates = updates.subscribe();
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            info!("[{}] connected peer over {}", addr, transport);
+            match time::timeout(HANDSHAKE_TIMEOUT, transport.accept(stream)).await {
+                Ok(Ok((reader, writer))) => {
+                    conn.run(reader, writer, updates, shutdown, idle_timeout)
+                        .await;
+                    info!("[{}] disconnected peer", addr);
                 }
+                Ok(Err(e)) => warn!("[{}] {}", addr, e),
+                Err(_) => warn!("[{}] handshake timed out", addr),
+            }
+        });
+    }
+
+    trace!("closing {} RPC connections", connections.len());
+    while let Some(result) = connections.join_next().await {
+        if let Err(error) = result {
+            error!("connection task failed: {}", error);
+        }
+    }
+    trace!("RPC connections are closed");
+}
 
-                trace!("RPC connections are closed");
+impl RPC {
+    fn listeners(config: &Config) -> Vec<(SocketAddr, Transport)> {
+        let mut listeners = vec![(config.electrum_rpc_addr, Transport::Tcp)];
+        if let (Some(cert), Some(key)) = (&config.electrum_tls_cert, &config.electrum_tls_key) {
+            let acceptor = tls_acceptor(cert, key).expect("failed to load the TLS certificate");
+            if let Some(addr) = config.electrum_tls_addr {
+                listeners.push((addr, Transport::Tls(acceptor.clone())));
+            }
+            if let Some(addr) = config.electrum_wss_addr {
+                listeners.push((addr, Transport::SecureWebSocket(acceptor)));
+            }
+        }
+        if let Some(addr) = config.electrum_ws_addr {
+            listeners.push((addr, Transport::WebSocket));
+        }
+        listeners
+    }
+
+    pub fn start(
+        config: Arc<Config>,
+        query: Arc<Query>,
+        metrics: &Metrics,
+        discovery: Option<Arc<DiscoveryManager>>,
+        rate_limiter: Option<Arc<RateLimiter>>,
+        api_keys: Option<Arc<ApiKeys>>,
+    ) -> RPC {
+        let stats = Stats::new(metrics);
+
+        let (updates, _) = broadcast::channel(UPDATES_CAPACITY);
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        let server_updates = updates.clone();
+
+        RPC {
+            updates,
+            shutdown,
+            server: Some(spawn_thread("rpc", move || {
+                run_server(
+                    config,
+                    query,
+                    stats,
+                    discovery,
+                    rate_limiter,
+                    api_keys,
+                    server_updates,
+                    shutdown_receiver,
+                )
             })),
         }
     }
 
-    pub fn notify(&self) {
-        self.notification.send(Notification::Periodic).unwrap();
+    // Update the subscriptions to the given scripthashes (and the header subscriptions)
+    pub fn notify(&self, touched: HashSet<FullHash>) {
+        // fails only when no client is connected
+        self.updates.send(Arc::new(touched)).ok();
     }
 }
 
 impl Drop for RPC {
     fn drop(&mut self) {
         trace!("stop accepting new RPCs");
-        self.notification.send(Notification::Exit).unwrap();
+        self.shutdown.send(true).ok();
         if let Some(handle) = self.server.take() {
             handle.join().unwrap();
         }
         trace!("RPC server is stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::{Transaction, TxOut};
+    use crate::new_index::compute_script_hash;
+    use crate::new_index::test_helper::{self, TestIndex};
+    use tapyrus::hashes::Hash;
+    use tapyrus::{BlockHash, Script};
+
+    // A connection to the test index, handling commands directly
+    fn connection(index: &TestIndex) -> Connection {
+        let metrics = Metrics::new(index.config.monitoring_addr);
+        Connection::new(
+            Arc::clone(&index.query),
+            "127.0.0.1:1".parse().unwrap(),
+            Stats::new(&metrics),
+            &index.config,
+            None,
+            None,
+            None,
+        )
+    }
+
+    // Mine a block with txdata on top of prev_blockhash and index it, returning its hash
+    fn connect(
+        index: &mut TestIndex,
+        prev_blockhash: BlockHash,
+        txdata: Vec<Transaction>,
+    ) -> BlockHash {
+        let blocks = {
+            let headers = index.store.indexed_headers.read().unwrap();
+            test_helper::blocks(&headers, prev_blockhash, vec![txdata], headers.len() as u32)
+        };
+        index.tapyrusd.lock().unwrap().connect(&blocks);
+        index.indexer.update(&index.daemon).unwrap();
+        *blocks[0].entry.hash()
+    }
+
+    #[test]
+    fn test_error_code() {
+        let daemon_error = ErrorKind::Daemon(
+            "sendrawtransaction".to_owned(),
+            json!({"code": -26, "message": "txn-mempool-conflict"}),
+        );
+        let e = Err::<(), _>(Error::from(daemon_error))
+            .chain_err(|| "failed to broadcast")
+            .unwrap_err();
+        assert_eq!(error_code(&e), DAEMON_ERROR);
+        assert_eq!(error_message(&e), "txn-mempool-conflict");
+
+        let e = Error::from(ErrorKind::TooPopular);
+        assert_eq!(error_code(&e), EXCESSIVE_RESOURCE_USAGE);
+        let e = Error::from(ErrorKind::LimitExceeded("too many".to_owned()));
+        assert_eq!(error_code(&e), EXCESSIVE_RESOURCE_USAGE);
+        let e = Error::from(ErrorKind::RateLimited(2));
+        assert_eq!(error_code(&e), EXCESSIVE_RESOURCE_USAGE);
+        assert_eq!(error_message(&e), "Rate limit exceeded, retry in 2 seconds");
+
+        let e = Error::from("invalid hash");
+        assert_eq!(error_code(&e), BAD_REQUEST);
+        assert_eq!(error_message(&e), "invalid hash");
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        let negotiate = |val: Value| negotiate_version(Some(&val)).map(|v| v.to_string());
+        assert_eq!(negotiate_version(None).unwrap(), PROTOCOL_MIN);
+        assert_eq!(negotiate(json!("1.4")).unwrap(), "1.4");
+        assert_eq!(negotiate(json!("1.4.2")).unwrap(), "1.4");
+        assert_eq!(negotiate(json!(["1.2", "1.6"])).unwrap(), "1.4");
+        assert_eq!(negotiate(json!(["1.4", "1.4.2"])).unwrap(), "1.4");
+        assert!(negotiate(json!("1.2")).is_err());
+        assert!(negotiate(json!(["1.5", "2.0"])).is_err());
+        assert!(negotiate(json!(14)).is_err());
+    }
+
+    #[test]
+    fn test_server_version() {
+        let index = test_helper::index(|_| {});
+        let mut conn = connection(&index);
+        let server_version = |conn: &mut Connection, version: Value| {
+            conn.handle_command("server.version", &[json!("test"), version], &json!(1))
+        };
+
+        // a failed negotiation can be retried
+        let reply = server_version(&mut conn, json!("1.5"));
+        assert_eq!(reply["error"]["code"], BAD_REQUEST);
+        assert_eq!(conn.protocol_version, None);
+
+        let reply = server_version(&mut conn, json!(["1.4", "1.5"]));
+        let server = format!("electrs-esplora {}", ELECTRS_VERSION);
+        assert_eq!(reply["result"], json!([server, "1.4"]));
+        assert_eq!(conn.protocol_version, Some(PROTOCOL_MIN));
+
+        // but only once it succeeded
+        let reply = server_version(&mut conn, json!("1.4"));
+        assert_eq!(reply["error"]["message"], "server.version already sent");
+    }
+
+    #[test]
+    fn test_update_subscriptions() {
+        let mut index = test_helper::index(|_| {});
+        let p2pkh = |hash: &str| Script::from(hex::decode(format!("76a914{}88ac", hash)).unwrap());
+        let (script_a, script_b) = (p2pkh(&"aa".repeat(20)), p2pkh(&"bb".repeat(20)));
+        let hash_a = compute_script_hash(&script_a);
+        let hash_b = compute_script_hash(&script_b);
+        let color_id = ColorIdentifier::reissuable(script_a.clone());
+        let output = |script: &Script| TxOut {
+            value: 1000,
+            script_pubkey: script.clone(),
+        };
+        let genesis_coinbase = test_helper::coinbase(0, vec![output(&script_a), output(&script_b)]);
+        let genesis = connect(
+            &mut index,
+            BlockHash::default(),
+            vec![genesis_coinbase.clone()],
+        );
+
+        let mut conn = connection(&index);
+        let electrum_hash = |hash| json!(Sha256dHash::from_inner(hash));
+        let color_param = json!(hex::encode(serialize(&color_id)));
+        for params in vec![
+            vec![electrum_hash(hash_a)],
+            vec![electrum_hash(hash_a), color_param.clone()],
+            vec![electrum_hash(hash_b)],
+        ] {
+            conn.blockchain_scripthash_subscribe(&params).unwrap();
+        }
+        let counters = |conn: &Connection| {
+            let counter = |label| conn.stats.status_updates.with_label_values(&[label]).get();
+            (counter("computed"), counter("skipped"))
+        };
+        let subscribed = |notification: &Value| notification["params"][0].clone();
+
+        // script_a issues a colored coin, the color-scoped subscription is refreshed through its
+        // uncolored scripthash and the subscription to script_b is skipped
+        let issue = test_helper::tx(
+            &[OutPoint::new(genesis_coinbase.malfix_txid(), 0)],
+            vec![TxOut {
+                value: 1000,
+                script_pubkey: script_a.add_color(color_id.clone()).unwrap(),
+            }],
+        );
+        let block1 = connect(
+            &mut index,
+            genesis,
+            vec![test_helper::coinbase(1, vec![]), issue],
+        );
+        let touched: HashSet<FullHash> = vec![hash_a].into_iter().collect();
+        let notifications = conn.update_subscriptions(Some(&touched)).unwrap();
+        assert_eq!(notifications.len(), 2);
+        assert!(notifications
+            .iter()
+            .all(|n| n["method"] == "blockchain.scripthash.subscribe"));
+        assert!(notifications
+            .iter()
+            .all(|n| subscribed(n) == electrum_hash(hash_a)));
+        let colored: Vec<&Value> = notifications
+            .iter()
+            .filter(|n| n["params"].as_array().unwrap().len() == 3)
+            .collect();
+        assert_eq!(colored.len(), 1);
+        assert_eq!(colored[0]["params"][2], json!(color_id));
+        assert_eq!(counters(&conn), (2, 1));
+
+        // a change of script_b is not seen while its scripthash isn't touched
+        connect(
+            &mut index,
+            block1,
+            vec![test_helper::coinbase(2, vec![output(&script_b)])],
+        );
+        let notifications = conn.update_subscriptions(Some(&touched)).unwrap();
+        assert!(notifications.is_empty());
+        assert_eq!(counters(&conn), (4, 2));
+
+        // but all the subscriptions are recomputed after missed updates
+        let (updates, mut receiver) = broadcast::channel(1);
+        for touched in vec![HashSet::new(), touched.clone()] {
+            updates.send(Arc::new(touched)).unwrap();
+        }
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let touched = match Message::from_update(rt.block_on(receiver.recv())) {
+            Message::PeriodicUpdate(touched) => touched,
+            msg => panic!("unexpected message {:?}", msg),
+        };
+        assert!(touched.is_none());
+        let notifications = conn.update_subscriptions(touched.as_deref()).unwrap();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(subscribed(&notifications[0]), electrum_hash(hash_b));
+        assert_eq!(counters(&conn), (7, 2));
+
+        // nothing changed since
+        let touched: HashSet<FullHash> = vec![hash_a, hash_b].into_iter().collect();
+        let notifications = conn.update_subscriptions(Some(&touched)).unwrap();
+        assert!(notifications.is_empty());
+        assert_eq!(counters(&conn), (10, 2));
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let limits = ConnectionLimits::new(3, Some(2));
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        let first = limits.acquire(a).unwrap();
+        let second = limits.acquire(a).unwrap();
+        assert_eq!(limits.acquire(a).err(), Some("max_connections_per_ip"));
+        let third = limits.acquire(b).unwrap();
+        assert_eq!(limits.acquire(b).err(), Some("max_connections"));
+
+        drop(first);
+        assert!(limits.acquire(a).is_ok()); // released right away
+        drop((second, third));
+        assert_eq!(limits.open.lock().unwrap().0, 0);
+        assert!(limits.open.lock().unwrap().1.is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio_native_tls::native_tls::{self, Identity};
+use tokio_native_tls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::errors::*;
+
+// The protocols spoken by the Electrum listeners: JSON-RPC lines over plain TCP or TLS, and
+// JSON-RPC messages over (secure) WebSocket for browser wallets.
+#[derive(Clone)]
+pub enum Transport {
+    Tcp,
+    Tls(TlsAcceptor),
+    WebSocket,
+    SecureWebSocket(TlsAcceptor),
+}
+
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for S {}
+
+type BoxedStream = Box<dyn AsyncStream>;
+
+pub enum RequestReader {
+    Lines(BufReader<ReadHalf<BoxedStream>>),
+    // each WebSocket message carries a single JSON-RPC request or reply
+    WebSocket(SplitStream<WebSocketStream<BoxedStream>>),
+}
+
+pub enum ReplyWriter {
+    Lines(WriteHalf<BoxedStream>),
+    WebSocket(SplitSink<WebSocketStream<BoxedStream>, Message>),
+}
+
+pub fn tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let cert = fs::read(cert_path).chain_err(|| format!("cannot read {:?}", cert_path))?;
+    let key = fs::read(key_path).chain_err(|| format!("cannot read {:?}", key_path))?;
+    let identity = Identity::from_pkcs8(&cert, &key)
+        .chain_err(|| "expected a PEM certificate chain and a PEM PKCS#8 key")?;
+    let acceptor =
+        native_tls::TlsAcceptor::new(identity).chain_err(|| "failed to create TLS acceptor")?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+impl Transport {
+    // Perform the TLS and WebSocket handshakes
+    pub async fn accept(&self, stream: TcpStream) -> Result<(RequestReader, ReplyWriter)> {
+        let stream: BoxedStream = match self {
+            Transport::Tcp | Transport::WebSocket => Box::new(stream),
+            Transport::Tls(acceptor) | Transport::SecureWebSocket(acceptor) => Box::new(
+                acceptor
+                    .accept(stream)
+                    .await
+                    .chain_err(|| "TLS handshake failed")?,
+            ),
+        };
+        Ok(match self {
+            Transport::Tcp | Transport::Tls(_) => {
+                let (reader, writer) = tokio::io::split(stream);
+                (
+                    RequestReader::Lines(BufReader::new(reader)),
+                    ReplyWriter::Lines(writer),
+                )
+            }
+            Transport::WebSocket | Transport::SecureWebSocket(_) => {
+                let ws = tokio_tungstenite::accept_async(stream)
+                    .await
+                    .chain_err(|| "WebSocket handshake failed")?;
+                let (writer, reader) = ws.split();
+                (
+                    RequestReader::WebSocket(reader),
+                    ReplyWriter::WebSocket(writer),
+                )
+            }
+        })
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Tcp => "tcp",
+            Transport::Tls(_) => "tls",
+            Transport::WebSocket => "ws",
+            Transport::SecureWebSocket(_) => "wss",
+        })
+    }
+}
+
+impl RequestReader {
+    // The next request, or None when the client disconnected
+    pub async fn read_request(&mut self) -> Result<Option<Vec<u8>>> {
+        match self {
+            RequestReader::Lines(reader) => {
+                let mut line = Vec::<u8>::new();
+                reader
+                    .read_until(b'\n', &mut line)
+                    .await
+                    .chain_err(|| "failed to read a request")?;
+                Ok(if line.is_empty() { None } else { Some(line) })
+            }
+            RequestReader::WebSocket(reader) => loop {
+                match reader.next().await {
+                    Some(Ok(Message::Text(text))) => return Ok(Some(text.into_bytes())),
+                    Some(Ok(Message::Binary(data))) => return Ok(Some(data)),
+                    Some(Ok(Message::Close(_))) | None => return Ok(None),
+                    // pings are answered by tungstenite
+                    Some(Ok(_)) => (),
+                    Some(Err(e)) => return Err(e).chain_err(|| "failed to read a request"),
+                }
+            },
+        }
+    }
+}
+
+impl ReplyWriter {
+    pub async fn write_reply(&mut self, reply: &str) -> Result<()> {
+        match self {
+            ReplyWriter::Lines(writer) => {
+                let line = reply.to_owned() + "\n";
+                writer
+                    .write_all(line.as_bytes())
+                    .await
+                    .chain_err(|| "failed to write a reply")
+            }
+            ReplyWriter::WebSocket(writer) => writer
+                .send(Message::Text(reply.to_owned()))
+                .await
+                .chain_err(|| "failed to write a reply"),
+        }
+    }
+
+    pub async fn shutdown(&mut self) {
+        match self {
+            ReplyWriter::Lines(writer) => {
+                let _ = writer.shutdown().await;
+            }
+            ReplyWriter::WebSocket(writer) => {
+                let _ = writer.close().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_websocket() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = tokio::spawn(async move {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let url = format!("ws://{}/", addr);
+                let (mut ws, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+                ws.send(Message::Text("request".into())).await.unwrap();
+                let reply = ws.next().await.unwrap().unwrap();
+                assert_eq!(reply, Message::Text("reply".into()));
+                ws.close(None).await.unwrap();
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = Transport::WebSocket.accept(stream).await.unwrap();
+            let request = reader.read_request().await.unwrap();
+            assert_eq!(request, Some(b"request".to_vec()));
+            writer.write_reply("reply").await.unwrap();
+            assert_eq!(reader.read_request().await.unwrap(), None);
+            writer.shutdown().await;
+            client.await.unwrap();
+        });
+    }
+}
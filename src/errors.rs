@@ -9,6 +9,11 @@ error_chain! {
             display("Connection error: {}", msg)
         }
 
+        Daemon(method: String, err: serde_json::Value) {
+            description("Daemon RPC error")
+            display("{} RPC error: {}", method, err)
+        }
+
         Interrupt(sig: i32) {
             description("Interruption by external signal")
             display("Iterrupted by signal {}", sig)
@@ -19,5 +24,20 @@ error_chain! {
             display("Too many history entries")
         }
 
+        LimitExceeded(msg: String) {
+            description("Limit exceeded")
+            display("{}", msg)
+        }
+
+        RateLimited(retry_after: u64) {
+            description("Rate limit exceeded")
+            display("Rate limit exceeded, retry in {} seconds", retry_after)
+        }
+
+        InvalidBlockProof(blockhash: String, height: usize) {
+            description("Invalid block proof")
+            display("Invalid proof for block {} at height {}", blockhash, height)
+        }
+
     }
 }
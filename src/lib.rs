@@ -16,6 +16,8 @@ extern crate serde_json;
 #[macro_use]
 extern crate lazy_static;
 
+pub mod admin;
+pub mod api_keys;
 pub mod chain;
 pub mod config;
 pub mod daemon;
@@ -24,6 +26,9 @@ pub mod errors;
 pub mod metrics;
 pub mod new_index;
 pub mod open_assets;
+pub mod rate_limit;
 pub mod rest;
 pub mod signal;
 pub mod util;
+pub mod webhooks;
+pub mod zmq;
@@ -1,11 +1,12 @@
 use std::collections::HashMap;
+use std::str::FromStr;
 use tapyrus::consensus::encode::{deserialize, serialize};
 use tapyrus::ColorIdentifier;
-use tapyrus::{BlockHash, OutPoint, Transaction, TxOut, Txid};
+use tapyrus::{BlockHash, OutPoint, Script, Transaction, TxOut, Txid};
 
 use crate::new_index::db::DBRow;
-use crate::new_index::schema::FullHash;
-use crate::util::{full_hash, Bytes};
+use crate::new_index::schema::{compute_script_hash, FullHash};
+use crate::util::{full_hash, BlockId, Bytes};
 
 use super::schema::ColorIdRow;
 
@@ -97,6 +98,80 @@ pub fn deserialize_color_id(token_type: u8, payload: [u8; 32]) -> ColorIdentifie
     ColorIdentifier::from_slice(&v).expect("failed to deserialize color_id")
 }
 
+// The token type, encoded as the first byte of a ColorIdentifier
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenType {
+    Reissuable,
+    NonReissuable,
+    Nft,
+}
+
+impl TokenType {
+    pub fn of(color_id: &ColorIdentifier) -> Option<TokenType> {
+        match serialize_color_id(color_id).0 {
+            0xc1 => Some(TokenType::Reissuable),
+            0xc2 => Some(TokenType::NonReissuable),
+            0xc3 => Some(TokenType::Nft),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for TokenType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reissuable" => Ok(TokenType::Reissuable),
+            "non_reissuable" => Ok(TokenType::NonReissuable),
+            "nft" => Ok(TokenType::Nft),
+            _ => Err(format!("invalid token type: {}", s)),
+        }
+    }
+}
+
+// What a color identifier commits to: the script_pubkey of the issuer for reissuable tokens,
+// or the outpoint spent by the (only) issuance for non-reissuable tokens and NFTs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColorIssuer {
+    Script(Script),
+    OutPoint(OutPoint),
+}
+
+impl ColorIssuer {
+    // Find the issuer of color_id among the outputs spent by one of its issuance txs
+    pub fn find(
+        color_id: &ColorIdentifier,
+        tx: &Transaction,
+        previous_txos_map: &HashMap<OutPoint, TxOut>,
+    ) -> Option<ColorIssuer> {
+        let token_type = TokenType::of(color_id)?;
+        tx.input.iter().find_map(|txin| {
+            let outpoint = txin.previous_output;
+            match token_type {
+                TokenType::Reissuable => {
+                    let script = &previous_txos_map.get(&outpoint)?.script_pubkey;
+                    if ColorIdentifier::reissuable(script.clone()) == *color_id {
+                        Some(ColorIssuer::Script(script.clone()))
+                    } else {
+                        None
+                    }
+                }
+                TokenType::NonReissuable
+                    if ColorIdentifier::non_reissuable(outpoint) == *color_id =>
+                {
+                    Some(ColorIssuer::OutPoint(outpoint))
+                }
+                TokenType::Nft if ColorIdentifier::nft(outpoint) == *color_id => {
+                    Some(ColorIssuer::OutPoint(outpoint))
+                }
+                _ => None,
+            }
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ColoredTxHistoryInfo {
     Issuing(IssuingInfo),
@@ -189,6 +264,18 @@ pub struct ColoredStats {
 }
 
 impl ColoredStats {
+    // An NFT is issued exactly once with an amount of 1, so it can't be issued or burned more
+    // than that. This clamps the stats of malformed NFT histories to that.
+    pub fn enforce_nft_supply(mut self) -> Self {
+        if TokenType::of(&self.color_id) == Some(TokenType::Nft) {
+            self.issued_tx_count = self.issued_tx_count.min(1);
+            self.issued_sum = self.issued_sum.min(1);
+            self.burned_tx_count = self.burned_tx_count.min(1);
+            self.burned_sum = self.burned_sum.min(1);
+        }
+        self
+    }
+
     pub fn new(color_id: &ColorIdentifier) -> Self {
         ColoredStats {
             color_id: color_id.clone(),
@@ -202,7 +289,280 @@ impl ColoredStats {
         }
     }
 }
-// collect histories of issuing/transferring/burining colored coins in specified tx.
+
+// The amounts of a colored coin received and sent by a holder (a colored script_pubkey)
+// in a single transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HolderInfo {
+    pub txid: FullHash,
+    pub script: Bytes,
+    pub received: u64,
+    pub sent: u64,
+}
+
+impl HolderInfo {
+    pub fn get_txid(&self) -> Txid {
+        deserialize(&self.txid).expect("cannot parse Txid")
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ColoredHolderRow {
+    pub color_id: ColorIdentifier,
+    pub confirmed_height: u32,
+    pub info: HolderInfo,
+}
+
+impl ColoredHolderRow {
+    pub fn filter(color_id: &ColorIdentifier) -> Bytes {
+        bincode::serialize(&(b'h', &serialize_color_id(color_id))).unwrap()
+    }
+
+    pub fn prefix_height(color_id: &ColorIdentifier, height: u32) -> Bytes {
+        bincode::serialize(&(b'h', &serialize_color_id(color_id), height.to_be_bytes())).unwrap()
+    }
+
+    fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize(&(
+                b'h',
+                &serialize_color_id(&self.color_id),
+                self.confirmed_height.to_be_bytes(),
+                self.info.txid,
+                &self.info.script,
+            ))
+            .unwrap(),
+            value: bincode::serialize(&(self.info.received, self.info.sent)).unwrap(),
+        }
+    }
+
+    pub fn from_row(row: DBRow) -> Self {
+        let (_prefix, token_type, payload, confirmed_height, txid, script): (
+            u8,
+            u8,
+            [u8; 32],
+            [u8; 4],
+            FullHash,
+            Bytes,
+        ) = bincode::deserialize(&row.key).expect("failed to deserialize ColoredHolderRow");
+        let (received, sent): (u64, u64) =
+            bincode::deserialize(&row.value).expect("failed to deserialize ColoredHolderRow");
+        ColoredHolderRow {
+            color_id: deserialize_color_id(token_type, payload),
+            confirmed_height: u32::from_be_bytes(confirmed_height),
+            info: HolderInfo {
+                txid,
+                script,
+                received,
+                sent,
+            },
+        }
+    }
+}
+
+// script_pubkey -> balance (or balance change for the mempool). balances are signed, since a
+// block may spend the outputs of its own transactions in any order.
+pub type HolderMap = HashMap<Bytes, i64>;
+
+pub struct ColoredHoldersCacheRow {
+    color_id: ColorIdentifier,
+    value: Bytes,
+}
+
+impl ColoredHoldersCacheRow {
+    pub fn new(color_id: &ColorIdentifier, holders: &HolderMap, blockhash: &BlockHash) -> Self {
+        ColoredHoldersCacheRow {
+            color_id: color_id.clone(),
+            value: bincode::serialize(&(holders, blockhash)).unwrap(),
+        }
+    }
+
+    pub fn key(color_id: &ColorIdentifier) -> Bytes {
+        bincode::serialize(&(b'h', &serialize_color_id(&color_id))).unwrap()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: ColoredHoldersCacheRow::key(&self.color_id),
+            value: self.value,
+        }
+    }
+}
+
+// The supply of a colored coin as of some block height
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ColoredSupply {
+    pub issued_sum: u64,
+    pub burned_sum: u64,
+    pub holders: HolderMap,
+}
+
+impl ColoredSupply {
+    pub fn circulating_sum(&self) -> u64 {
+        self.issued_sum.saturating_sub(self.burned_sum)
+    }
+}
+
+pub struct ColoredSupplyCacheRow {
+    color_id: ColorIdentifier,
+    value: Bytes,
+}
+
+impl ColoredSupplyCacheRow {
+    pub fn new(color_id: &ColorIdentifier, supply: &ColoredSupply, blockhash: &BlockHash) -> Self {
+        ColoredSupplyCacheRow {
+            color_id: color_id.clone(),
+            value: bincode::serialize(&(supply, blockhash)).unwrap(),
+        }
+    }
+
+    pub fn key(color_id: &ColorIdentifier) -> Bytes {
+        bincode::serialize(&(b's', &serialize_color_id(&color_id))).unwrap()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: ColoredSupplyCacheRow::key(&self.color_id),
+            value: self.value,
+        }
+    }
+}
+
+// An output carrying an NFT. These are indexed by color, to find the current owner of an NFT
+// without scanning its history, and by the scripthashes they pay to (both the colored script and
+// the uncolored one), to find the NFTs held by an address.
+#[derive(Debug, PartialEq)]
+pub struct NftOutputRow {
+    pub color_id: ColorIdentifier,
+    pub confirmed_height: u32,
+    pub txid: FullHash,
+    pub vout: u16,
+    pub script: Bytes,
+}
+
+impl NftOutputRow {
+    pub fn filter(color_id: &ColorIdentifier) -> Bytes {
+        bincode::serialize(&(b'n', &serialize_color_id(color_id))).unwrap()
+    }
+
+    pub fn prefix_end(color_id: &ColorIdentifier) -> Bytes {
+        bincode::serialize(&(
+            b'n',
+            &serialize_color_id(color_id),
+            std::u32::MAX.to_be_bytes(),
+        ))
+        .unwrap()
+    }
+
+    pub fn scripthash_filter(scripthash: &[u8]) -> Bytes {
+        bincode::serialize(&(b'N', full_hash(scripthash))).unwrap()
+    }
+
+    fn into_rows(self) -> Vec<DBRow> {
+        let color_id = serialize_color_id(&self.color_id);
+        let height = self.confirmed_height.to_be_bytes();
+        let mut rows = vec![DBRow {
+            key: bincode::serialize(&(b'n', &color_id, height, self.txid, self.vout)).unwrap(),
+            value: bincode::serialize(&self.script).unwrap(),
+        }];
+        let script = Script::from(self.script.clone());
+        let mut scripts = vec![script.clone()];
+        if let Some((_, uncolored)) = script.split_color() {
+            scripts.push(uncolored);
+        }
+        for script in scripts {
+            rows.push(DBRow {
+                key: bincode::serialize(&(
+                    b'N',
+                    compute_script_hash(&script),
+                    height,
+                    self.txid,
+                    self.vout,
+                ))
+                .unwrap(),
+                value: bincode::serialize(&(&color_id, &self.script)).unwrap(),
+            });
+        }
+        rows
+    }
+
+    pub fn from_row(row: DBRow) -> Self {
+        let (_prefix, token_type, payload, confirmed_height, txid, vout): (
+            u8,
+            u8,
+            [u8; 32],
+            [u8; 4],
+            FullHash,
+            u16,
+        ) = bincode::deserialize(&row.key).expect("failed to deserialize NftOutputRow");
+        let script: Bytes =
+            bincode::deserialize(&row.value).expect("failed to deserialize NftOutputRow");
+        NftOutputRow {
+            color_id: deserialize_color_id(token_type, payload),
+            confirmed_height: u32::from_be_bytes(confirmed_height),
+            txid,
+            vout,
+            script,
+        }
+    }
+
+    pub fn from_scripthash_row(row: DBRow) -> Self {
+        let (_prefix, _scripthash, confirmed_height, txid, vout): (
+            u8,
+            FullHash,
+            [u8; 4],
+            FullHash,
+            u16,
+        ) = bincode::deserialize(&row.key).expect("failed to deserialize NftOutputRow");
+        let ((token_type, payload), script): ((u8, [u8; 32]), Bytes) =
+            bincode::deserialize(&row.value).expect("failed to deserialize NftOutputRow");
+        NftOutputRow {
+            color_id: deserialize_color_id(token_type, payload),
+            confirmed_height: u32::from_be_bytes(confirmed_height),
+            txid,
+            vout,
+            script,
+        }
+    }
+
+    pub fn get_txid(&self) -> Txid {
+        deserialize(&self.txid).expect("cannot parse Txid")
+    }
+}
+
+// An unspent output carrying an NFT
+#[derive(Debug, Clone)]
+pub struct NftOutput {
+    pub color_id: ColorIdentifier,
+    pub txid: Txid,
+    pub vout: u32,
+    pub script: Script,
+    pub confirmed: Option<BlockId>,
+}
+
+impl From<&NftOutput> for OutPoint {
+    fn from(output: &NftOutput) -> Self {
+        OutPoint::new(output.txid, output.vout)
+    }
+}
+
+// A holder of a colored coin, with its confirmed balance and the change to it in the mempool
+#[derive(Debug)]
+pub struct Holder {
+    pub script: Script,
+    pub scripthash: FullHash,
+    pub chain_balance: u64,
+    pub mempool_balance: i64,
+}
+
+impl Holder {
+    pub fn balance(&self) -> i64 {
+        self.chain_balance as i64 + self.mempool_balance
+    }
+}
+
+// collect histories of issuing/transferring/burining colored coins in specified tx,
+// and the balance changes of their holders.
 pub fn index_confirmed_colored_tx(
     tx: &Transaction,
     confirmed_height: u32,
@@ -215,6 +575,93 @@ pub fn index_confirmed_colored_tx(
         rows.push(colored_history_row(&color_id, confirmed_height, info).into_row());
         rows.push(ColorIdRow::new(confirmed_height, &color_id).into_row());
     });
+
+    for (color_id, info) in colored_tx_holders(tx, previous_txos_map) {
+        let row = ColoredHolderRow {
+            color_id,
+            confirmed_height,
+            info,
+        };
+        rows.push(row.into_row());
+    }
+
+    let txid = full_hash(&tx.malfix_txid()[..]);
+    for (vout, txo) in tx.output.iter().enumerate() {
+        if let Some((color_id, _)) = txo.script_pubkey.split_color() {
+            if TokenType::of(&color_id) != Some(TokenType::Nft) {
+                continue;
+            }
+            let row = NftOutputRow {
+                color_id,
+                confirmed_height,
+                txid,
+                vout: vout as u16,
+                script: txo.script_pubkey.to_bytes(),
+            };
+            rows.extend(row.into_rows());
+        }
+    }
+}
+
+// Return the amounts received and sent by each holder of the colored coins in the tx.
+pub fn colored_tx_holders(
+    tx: &Transaction,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+) -> Vec<(ColorIdentifier, HolderInfo)> {
+    // key: (ColorIdentifier, script_pubkey)
+    // value: tuple of amount received and sent.
+    let mut holders: HashMap<(ColorIdentifier, Bytes), (u64, u64)> = HashMap::new();
+
+    for txo in &tx.output {
+        if let Some((color_id, _)) = txo.script_pubkey.split_color() {
+            let amounts = holders
+                .entry((color_id, txo.script_pubkey.to_bytes()))
+                .or_insert((0, 0));
+            amounts.0 += txo.value;
+        }
+    }
+    for input in tx.input.iter() {
+        if let Some(txo) = previous_txos_map.get(&input.previous_output) {
+            if let Some((color_id, _)) = txo.script_pubkey.split_color() {
+                let amounts = holders
+                    .entry((color_id, txo.script_pubkey.to_bytes()))
+                    .or_insert((0, 0));
+                amounts.1 += txo.value;
+            }
+        }
+    }
+
+    let txid = full_hash(&tx.malfix_txid()[..]);
+    holders
+        .into_iter()
+        .map(|((color_id, script), (received, sent))| {
+            (
+                color_id,
+                HolderInfo {
+                    txid,
+                    script,
+                    received,
+                    sent,
+                },
+            )
+        })
+        .collect()
+}
+
+// Apply the balance changes of holders to the holder map, dropping the ones left empty.
+pub fn update_holders<'a>(
+    init_holders: HolderMap,
+    infos: impl Iterator<Item = &'a HolderInfo>,
+) -> HolderMap {
+    let mut holders = init_holders;
+    for info in infos {
+        let balance = holders.entry(info.script.clone()).or_insert(0);
+        *balance += info.received as i64 - info.sent as i64;
+        if *balance == 0 {
+            holders.remove(&info.script);
+        }
+    }
+    holders
 }
 
 fn colored_history_row(
@@ -288,6 +735,41 @@ pub fn colored_tx_history(
     tx_history
 }
 
+// The amounts of a colored coin issued, transferred and burned by a single tx
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ColoredTxSummary {
+    pub color_id: ColorIdentifier,
+    pub issued: u64,
+    pub transferred: u64,
+    pub burned: u64,
+}
+
+// Summarize the colored coin history of the tx per color, ordered by color id.
+pub fn colored_tx_summary(
+    tx: &Transaction,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+) -> Vec<ColoredTxSummary> {
+    let mut summaries: HashMap<ColorIdentifier, ColoredTxSummary> = HashMap::new();
+    for (color_id, info) in colored_tx_history(tx, previous_txos_map) {
+        let summary = summaries
+            .entry(color_id.clone())
+            .or_insert_with(|| ColoredTxSummary {
+                color_id,
+                issued: 0,
+                transferred: 0,
+                burned: 0,
+            });
+        match info {
+            ColoredTxHistoryInfo::Issuing(info) => summary.issued += info.value,
+            ColoredTxHistoryInfo::Transferring(info) => summary.transferred += info.value,
+            ColoredTxHistoryInfo::Burning(info) => summary.burned += info.value,
+        }
+    }
+    let mut summaries: Vec<ColoredTxSummary> = summaries.into_iter().map(|(_, s)| s).collect();
+    summaries.sort_by_key(|summary| serialize_color_id(&summary.color_id));
+    summaries
+}
+
 pub fn create_history_info(
     txid: FullHash,
     prev_amount: u64,
@@ -528,7 +1010,8 @@ mod tests {
         let mut rows = vec![];
         index_confirmed_colored_tx(&tx, 10, &previous_txos_map, &mut rows);
 
-        assert_eq!(rows.len(), 8);
+        // 8 history rows, followed by 3 holder rows
+        assert_eq!(rows.len(), 11);
 
         rows.sort_by(|a, b| a.key.cmp(&b.key));
         let row0 = rows.get(0).unwrap();
@@ -619,4 +1102,215 @@ mod tests {
         // value               |    8 | 200(0xc800000000000000)                                               |
         assert_eq!(hex, "43c271c99cc3bc21757feed5b712744ebb0f770d5c41d99189f9457495747bf110500000000a0100000059abe954f5636c86484e5e2817d29b915e7f9a9f0294e87c438fd060694a8b1cc800000000000000");
     }
+
+    #[test]
+    fn test_colored_tx_holders() {
+        let p2pkh1 = hex_script("76a914000000000000000000000000000000000000000088ac");
+        let p2pkh2 = hex_script("76a914111111111111111111111111111111111111111188ac");
+        let color_id = ColorIdentifier::reissuable(p2pkh1.clone());
+        let cp2pkh1 = p2pkh1.add_color(color_id.clone()).unwrap();
+        let cp2pkh2 = p2pkh2.add_color(color_id.clone()).unwrap();
+
+        let prev_out = OutPoint::new(Txid::default(), 0);
+        let mut previous_txos_map = HashMap::new();
+        previous_txos_map.insert(
+            prev_out,
+            TxOut {
+                value: 300,
+                script_pubkey: cp2pkh1.clone(),
+            },
+        );
+
+        // cp2pkh1 sends 100 to cp2pkh2 and keeps 200 as change
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: prev_out,
+                script_sig: Script::default(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: vec![
+                TxOut {
+                    value: 100,
+                    script_pubkey: cp2pkh2.clone(),
+                },
+                TxOut {
+                    value: 200,
+                    script_pubkey: cp2pkh1.clone(),
+                },
+                TxOut {
+                    value: 1000,
+                    script_pubkey: p2pkh1.clone(),
+                },
+            ],
+        };
+        let mut holders = colored_tx_holders(&tx, &previous_txos_map);
+        holders.sort_by(|a, b| a.1.script.cmp(&b.1.script));
+        assert_eq!(holders.len(), 2);
+        assert_eq!(holders[0].0, color_id);
+        assert_eq!(holders[0].1.script, cp2pkh1.to_bytes());
+        assert_eq!((holders[0].1.received, holders[0].1.sent), (200, 300));
+        assert_eq!(holders[1].1.script, cp2pkh2.to_bytes());
+        assert_eq!((holders[1].1.received, holders[1].1.sent), (100, 0));
+
+        // issuance of 300 to cp2pkh1, then the transfer
+        let issuance = HolderInfo {
+            txid: full_hash(&Txid::default()[..]),
+            script: cp2pkh1.to_bytes(),
+            received: 300,
+            sent: 0,
+        };
+        let infos: Vec<HolderInfo> = holders.into_iter().map(|(_, info)| info).collect();
+        let balances = update_holders(HolderMap::new(), vec![issuance].iter());
+        let balances = update_holders(balances, infos.iter());
+        assert_eq!(balances.len(), 2);
+        assert_eq!(balances[&cp2pkh1.to_bytes()], 200);
+        assert_eq!(balances[&cp2pkh2.to_bytes()], 100);
+
+        // holders are dropped once their balance is spent
+        let spend = HolderInfo {
+            txid: full_hash(&Txid::default()[..]),
+            script: cp2pkh2.to_bytes(),
+            received: 0,
+            sent: 100,
+        };
+        let balances = update_holders(balances, vec![spend].iter());
+        assert_eq!(balances.len(), 1);
+        assert!(!balances.contains_key(&cp2pkh2.to_bytes()));
+    }
+
+    #[test]
+    fn test_token_type_and_issuer() {
+        let p2pkh = hex_script("76a914000000000000000000000000000000000000000088ac");
+        let prev_out = OutPoint::new(Txid::default(), 1);
+        let mut previous_txos_map = HashMap::new();
+        previous_txos_map.insert(
+            prev_out,
+            TxOut {
+                value: 1000,
+                script_pubkey: p2pkh.clone(),
+            },
+        );
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: prev_out,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: vec![],
+        };
+
+        let reissuable = ColorIdentifier::reissuable(p2pkh.clone());
+        let non_reissuable = ColorIdentifier::non_reissuable(prev_out);
+        let nft = ColorIdentifier::nft(prev_out);
+        assert_eq!(TokenType::of(&reissuable), Some(TokenType::Reissuable));
+        assert_eq!(
+            TokenType::of(&non_reissuable),
+            Some(TokenType::NonReissuable)
+        );
+        assert_eq!(TokenType::of(&nft), Some(TokenType::Nft));
+        assert_eq!(TokenType::from_str("nft"), Ok(TokenType::Nft));
+        assert!(TokenType::from_str("unknown").is_err());
+
+        assert_eq!(
+            ColorIssuer::find(&reissuable, &tx, &previous_txos_map),
+            Some(ColorIssuer::Script(p2pkh))
+        );
+        assert_eq!(
+            ColorIssuer::find(&non_reissuable, &tx, &previous_txos_map),
+            Some(ColorIssuer::OutPoint(prev_out))
+        );
+        assert_eq!(
+            ColorIssuer::find(&nft, &tx, &previous_txos_map),
+            Some(ColorIssuer::OutPoint(prev_out))
+        );
+        let other = ColorIdentifier::nft(OutPoint::new(Txid::default(), 2));
+        assert_eq!(ColorIssuer::find(&other, &tx, &previous_txos_map), None);
+
+        let stats = ColoredStats {
+            issued_tx_count: 2,
+            issued_sum: 2,
+            ..ColoredStats::new(&nft)
+        };
+        let stats = stats.enforce_nft_supply();
+        assert_eq!((stats.issued_tx_count, stats.issued_sum), (1, 1));
+    }
+
+    #[test]
+    fn test_colored_tx_summary() {
+        let p2pkh = hex_script("76a914000000000000000000000000000000000000000088ac");
+        let colored_out = OutPoint::new(Txid::default(), 0);
+        let uncolored_out = OutPoint::new(Txid::default(), 1);
+        let color_id1 = ColorIdentifier::reissuable(p2pkh.clone());
+        let color_id2 = ColorIdentifier::non_reissuable(uncolored_out);
+        let cp2pkh1 = p2pkh.add_color(color_id1.clone()).unwrap();
+        let cp2pkh2 = p2pkh.add_color(color_id2.clone()).unwrap();
+
+        let mut previous_txos_map = HashMap::new();
+        previous_txos_map.insert(
+            colored_out,
+            TxOut {
+                value: 200,
+                script_pubkey: cp2pkh1.clone(),
+            },
+        );
+        previous_txos_map.insert(
+            uncolored_out,
+            TxOut {
+                value: 1000,
+                script_pubkey: p2pkh.clone(),
+            },
+        );
+        let input = |previous_output| TxIn {
+            previous_output,
+            script_sig: Script::new(),
+            sequence: 0xffffffff,
+            witness: vec![],
+        };
+        // color_id2 (non-reissuable): issues 50
+        // color_id1 (reissuable)    : 200 -> 150 (50 burned, 150 transferred)
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![input(uncolored_out), input(colored_out)],
+            output: vec![
+                TxOut {
+                    value: 50,
+                    script_pubkey: cp2pkh2,
+                },
+                TxOut {
+                    value: 100,
+                    script_pubkey: cp2pkh1.clone(),
+                },
+                TxOut {
+                    value: 50,
+                    script_pubkey: cp2pkh1,
+                },
+            ],
+        };
+
+        let summary = colored_tx_summary(&tx, &previous_txos_map);
+        assert_eq!(
+            summary,
+            vec![
+                ColoredTxSummary {
+                    color_id: color_id1,
+                    issued: 0,
+                    transferred: 150,
+                    burned: 50,
+                },
+                ColoredTxSummary {
+                    color_id: color_id2,
+                    issued: 50,
+                    transferred: 0,
+                    burned: 0,
+                },
+            ]
+        );
+    }
 }
@@ -5,9 +5,9 @@ use std::path::Path;
 use crate::config::Config;
 use crate::util::Bytes;
 
-static DB_VERSION: u32 = 1;
+static DB_VERSION: u32 = 5;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct DBRow {
     pub key: Vec<u8>,
     pub value: Vec<u8>,
@@ -175,6 +175,32 @@ impl DB {
         self.db.write_opt(batch, &opts).unwrap();
     }
 
+    pub fn delete(&self, keys: Vec<Bytes>) {
+        self.delete_and_write(keys, vec![]);
+    }
+
+    // Deletes the keys and writes the rows in a single synced batch, so that a crash cannot leave
+    // only some of them applied
+    pub fn delete_and_write(&self, mut keys: Vec<Bytes>, rows: Vec<DBRow>) {
+        debug!(
+            "deleting {} rows and writing {} rows to {:?}",
+            keys.len(),
+            rows.len(),
+            self.db
+        );
+        keys.sort_unstable();
+        let mut batch = rocksdb::WriteBatch::default();
+        for key in keys {
+            batch.delete(&key);
+        }
+        for row in rows {
+            batch.put(&row.key, &row.value);
+        }
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(true);
+        self.db.write_opt(batch, &opts).unwrap();
+    }
+
     pub fn flush(&self) {
         self.db.flush().unwrap();
     }
@@ -194,19 +220,22 @@ impl DB {
     }
 
     fn verify_compatibility(&self, config: &Config) {
-        let mut compatibility_bytes = bincode::serialize(&DB_VERSION).unwrap();
-
-        if config.light_mode {
-            // append a byte to indicate light_mode is enabled.
-            // we're not letting bincode serialize this so that the compatiblity bytes won't change
-            // (and require a reindex) when light_mode is disabled. this should be chagned the next
-            // time we bump DB_VERSION and require a re-index anyway.
-            compatibility_bytes.push(1);
-        }
+        // light mode and open assets change the rows that are written, so switching either of
+        // them requires a reindex just like a DB_VERSION bump does.
+        let compatibility_bytes =
+            bincode::serialize(&(DB_VERSION, config.light_mode, config.enable_open_assets))
+                .unwrap();
 
         match self.get(b"V") {
             None => self.put(b"V", &compatibility_bytes),
             Some(ref x) if x != &compatibility_bytes => {
+                let version: u32 = bincode::deserialize(x).expect("invalid DB version");
+                if version < DB_VERSION {
+                    panic!(
+                        "Outdated database found (version {}, expected {}). Please reindex.",
+                        version, DB_VERSION
+                    )
+                }
                 panic!("Incompatible database found. Please reindex.")
             }
             Some(_) => (),
@@ -0,0 +1,163 @@
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::sync::Arc;
+
+use tapyrus::{BlockHash, ColorIdentifier, Transaction, TxOut, Txid};
+use tokio::sync::broadcast;
+
+use crate::new_index::{compute_script_hash, Query};
+use crate::util::{has_prevout, BlockId, FullHash};
+
+// how many events may be queued for a subscriber before it starts missing them
+const EVENTS_CAPACITY: usize = 10_000;
+// blocks that were already announced, used to find the new blocks after a reorg
+const RECENT_BLOCKS: usize = 100;
+
+#[derive(Debug)]
+pub enum Event {
+    Block(BlockId),
+    Tx(TxEvent),
+}
+
+// A transaction that was added to the mempool or confirmed in a new block, along with the
+// scripthashes and colors it touches through its outputs and the outputs it spends. Colored
+// scripts are reported with both their colored and uncolored scripthash.
+#[derive(Debug, Clone)]
+pub struct TxEvent {
+    pub txid: Txid,
+    pub confirmed: Option<BlockId>,
+    pub scripthashes: HashSet<FullHash>,
+    pub color_ids: HashSet<ColorIdentifier>,
+}
+
+impl TxEvent {
+    fn new(tx: &Transaction, prevouts: &[&TxOut], confirmed: Option<BlockId>) -> Self {
+        let mut scripthashes = HashSet::new();
+        let mut color_ids = HashSet::new();
+        for txo in tx.output.iter().chain(prevouts.iter().cloned()) {
+            scripthashes.insert(compute_script_hash(&txo.script_pubkey));
+            if let Some((color_id, uncolored)) = txo.script_pubkey.split_color() {
+                scripthashes.insert(compute_script_hash(&uncolored));
+                color_ids.insert(color_id);
+            }
+        }
+        TxEvent {
+            txid: tx.malfix_txid(),
+            confirmed,
+            scripthashes,
+            color_ids,
+        }
+    }
+}
+
+// Compares the chain and the mempool against their state at the previous update, and broadcasts
+// the new blocks and transactions to the subscribers.
+pub struct Events {
+    query: Arc<Query>,
+    recent_blocks: VecDeque<BlockHash>,
+    mempool_txids: HashSet<Txid>,
+    sender: broadcast::Sender<Arc<Event>>,
+}
+
+impl Events {
+    pub fn new(query: Arc<Query>) -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+        let recent_blocks = vec![query.chain().best_hash()].into_iter().collect();
+        let mempool_txids = query.mempool().txids().into_iter().cloned().collect();
+        Events {
+            query,
+            recent_blocks,
+            mempool_txids,
+            sender,
+        }
+    }
+
+    // subscribers call subscribe() on the returned sender
+    pub fn sender(&self) -> broadcast::Sender<Arc<Event>> {
+        self.sender.clone()
+    }
+
+    pub fn update(&mut self) {
+        let new_blocks = self.new_blocks();
+        let current_txids: HashSet<Txid> =
+            self.query.mempool().txids().into_iter().cloned().collect();
+        let new_txids: Vec<Txid> = current_txids
+            .difference(&self.mempool_txids)
+            .cloned()
+            .collect();
+        self.mempool_txids = current_txids;
+
+        // skip looking up the transactions when nobody is listening
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+
+        for blockid in new_blocks {
+            let txids: Vec<(Txid, BlockId)> = self
+                .query
+                .chain()
+                .get_block_txids(&blockid.hash)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|txid| (txid, blockid.clone()))
+                .collect();
+            self.send(Event::Block(blockid.clone()));
+            match self.query.chain().lookup_txns(&txids) {
+                Ok(txs) => {
+                    for tx in txs {
+                        self.send_tx(&tx, Some(blockid.clone()));
+                    }
+                }
+                Err(e) => warn!("failed loading txs of block {}: {}", blockid.hash, e),
+            }
+        }
+        for txid in new_txids {
+            // the transaction may have been evicted since the mempool update
+            if let Some(tx) = self.query.mempool().lookup_txn(&txid) {
+                self.send_tx(&tx, None);
+            }
+        }
+    }
+
+    // Walk back from the tip until a block that was already announced is found, returning the
+    // blocks that were not announced yet in chain order.
+    fn new_blocks(&mut self) -> Vec<BlockId> {
+        let chain = self.query.chain();
+        let mut new_blocks = vec![];
+        for height in (0..=chain.best_height()).rev() {
+            let header = match chain.header_by_height(height) {
+                Some(header) => header,
+                None => break,
+            };
+            if self.recent_blocks.contains(header.hash()) || new_blocks.len() == RECENT_BLOCKS {
+                break;
+            }
+            new_blocks.push(BlockId::from(&header));
+        }
+        new_blocks.reverse();
+
+        for blockid in &new_blocks {
+            self.recent_blocks.push_back(blockid.hash);
+        }
+        while self.recent_blocks.len() > RECENT_BLOCKS {
+            self.recent_blocks.pop_front();
+        }
+        new_blocks
+    }
+
+    fn send_tx(&self, tx: &Transaction, confirmed: Option<BlockId>) {
+        let outpoints: BTreeSet<_> = tx
+            .input
+            .iter()
+            .filter(|txin| has_prevout(txin))
+            .map(|txin| txin.previous_output)
+            .collect();
+        let txos = self.query.lookup_txos(&outpoints);
+        let prevouts: Vec<&TxOut> = outpoints.iter().filter_map(|o| txos.get(o)).collect();
+        self.send(Event::Tx(TxEvent::new(tx, &prevouts, confirmed)));
+    }
+
+    fn send(&self, event: Event) {
+        // fails only when there are no subscribers left
+        self.sender.send(Arc::new(event)).ok();
+    }
+}
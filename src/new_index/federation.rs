@@ -0,0 +1,184 @@
+use tapyrus::blockdata::block::XField;
+use tapyrus::consensus::encode::{deserialize, serialize};
+use tapyrus::hashes::Hash;
+use tapyrus::{BlockHash, BlockHeader, PublicKey};
+
+use crate::new_index::db::DBRow;
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::schema::FullHash;
+use crate::util::{full_hash, Bytes};
+
+// The block size limit in force until a block sets one with an xfield
+pub const DEFAULT_MAX_BLOCK_SIZE: u32 = 1_000_000;
+
+// Blocks whose header carries an xfield (a new aggregate public key of the federation, or a new
+// max block size) are indexed by height, so that the history of the federation can be read
+// without replaying every header.
+pub struct XFieldRow {
+    height: u32,
+    blockhash: FullHash,
+    xfield: XField,
+}
+
+impl XFieldRow {
+    pub fn new(block_entry: &BlockEntry) -> Option<Self> {
+        match block_entry.block.header.xfield {
+            XField::None => None,
+            ref xfield => Some(XFieldRow {
+                height: block_entry.entry.height() as u32,
+                blockhash: full_hash(&block_entry.entry.hash()[..]),
+                xfield: xfield.clone(),
+            }),
+        }
+    }
+
+    pub fn filter() -> Bytes {
+        b"x".to_vec()
+    }
+
+    // the key of the last possible row at height
+    pub fn prefix_height_end(height: u32) -> Bytes {
+        bincode::serialize(&(b'x', height.to_be_bytes(), [0xffu8; 32])).unwrap()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize(&(b'x', self.height.to_be_bytes(), self.blockhash)).unwrap(),
+            value: serialize(&self.xfield),
+        }
+    }
+
+    pub fn from_row(row: DBRow) -> Self {
+        let (_prefix, height, blockhash): (u8, [u8; 4], FullHash) =
+            bincode::deserialize(&row.key).expect("failed to deserialize XFieldRow");
+        XFieldRow {
+            height: u32::from_be_bytes(height),
+            blockhash,
+            xfield: deserialize(&row.value).expect("failed to parse XField"),
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        self.height as usize
+    }
+
+    pub fn blockhash(&self) -> BlockHash {
+        deserialize(&self.blockhash).expect("cannot parse BlockHash")
+    }
+
+    pub fn into_change(self) -> XFieldChange {
+        XFieldChange {
+            height: self.height(),
+            blockhash: self.blockhash(),
+            xfield: self.xfield,
+        }
+    }
+}
+
+// An xfield set by a block of the best chain
+#[derive(Debug, Clone)]
+pub struct XFieldChange {
+    pub height: usize,
+    pub blockhash: BlockHash,
+    pub xfield: XField,
+}
+
+impl XFieldChange {
+    pub fn aggregate_pubkey(&self) -> Option<&PublicKey> {
+        match self.xfield {
+            XField::AggregatePublicKey(ref pubkey) => Some(pubkey),
+            _ => None,
+        }
+    }
+
+    pub fn max_block_size(&self) -> Option<u32> {
+        match self.xfield {
+            XField::MaxBlockSize(size) => Some(size),
+            _ => None,
+        }
+    }
+
+    // An xfield applies from the block after the one that set it, except for the genesis block
+    // which is signed with its own aggregate public key.
+    pub fn activation_height(&self) -> usize {
+        match self.height {
+            0 => 0,
+            height => height + 1,
+        }
+    }
+}
+
+// Check the federation signature of the header against the aggregate public key in force
+pub fn verify_block_proof(header: &BlockHeader, pubkey: &PublicKey) -> bool {
+    match header.proof {
+        Some(ref proof) => proof
+            .verify(&header.signature_hash().into_inner(), pubkey)
+            .is_ok(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_index::test_helper;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_xfield_row() {
+        let pubkey = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let row = XFieldRow {
+            height: 10,
+            blockhash: [1; 32],
+            xfield: XField::AggregatePublicKey(pubkey),
+        }
+        .into_row();
+        assert!(row.key.starts_with(&XFieldRow::filter()));
+        assert!(row.key <= XFieldRow::prefix_height_end(10));
+        assert!(row.key > XFieldRow::prefix_height_end(9));
+
+        let change = XFieldRow::from_row(row).into_change();
+        assert_eq!(change.height, 10);
+        assert_eq!(change.aggregate_pubkey(), Some(&pubkey));
+        assert_eq!(change.activation_height(), 11);
+        assert_eq!(change.max_block_size(), None);
+
+        let change = XFieldChange {
+            height: 20,
+            blockhash: BlockHash::default(),
+            xfield: XField::MaxBlockSize(2_000_000),
+        };
+        assert_eq!(change.aggregate_pubkey(), None);
+        assert_eq!(change.max_block_size(), Some(2_000_000));
+    }
+
+    #[test]
+    fn test_verify_block_proof() {
+        let (key, pubkey) = test_helper::federation_key(1);
+        let (other_key, other_pubkey) = test_helper::federation_key(2);
+        let header = test_helper::header(BlockHash::default(), 0);
+        assert!(!verify_block_proof(&header, &pubkey));
+
+        let signed = test_helper::sign(header.clone(), &key);
+        assert!(verify_block_proof(&signed, &pubkey));
+        assert!(!verify_block_proof(&signed, &other_pubkey));
+        assert!(!verify_block_proof(
+            &test_helper::sign(header.clone(), &other_key),
+            &pubkey
+        ));
+
+        // the proof covers the whole header, xfield included
+        let mut tampered = signed.clone();
+        tampered.time += 1;
+        assert!(!verify_block_proof(&tampered, &pubkey));
+        let mut tampered = signed.clone();
+        tampered.xfield = XField::MaxBlockSize(2_000_000);
+        assert!(!verify_block_proof(&tampered, &pubkey));
+        let mut tampered = signed;
+        tampered.proof.as_mut().unwrap().r_x[0] ^= 1;
+        assert!(!verify_block_proof(&tampered, &pubkey));
+    }
+}
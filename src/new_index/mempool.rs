@@ -16,8 +16,10 @@ use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
 use crate::metrics::{GaugeVec, HistogramOpts, HistogramVec, MetricOpts, Metrics};
-use crate::new_index::color::colored_tx_history;
-use crate::new_index::color::{ColoredStats, ColoredTxHistoryInfo};
+use crate::new_index::color::{colored_tx_history, colored_tx_holders, update_holders};
+use crate::new_index::color::{
+    ColoredStats, ColoredTxHistoryInfo, HolderInfo, HolderMap, NftOutput, TokenType,
+};
 use crate::new_index::schema::{update_colored_stats, update_stats};
 use crate::new_index::{
     compute_script_hash, schema::FullHash, ChainQuery, FundingInfo, ScriptStats, SpendingInfo,
@@ -36,10 +38,13 @@ pub struct Mempool {
     feeinfo: HashMap<Txid, TxFeeInfo>,
     history: HashMap<FullHash, Vec<TxHistoryInfo>>, // ScriptHash -> {history_entries}
     colors: HashMap<ColorIdentifier, Vec<ColoredTxHistoryInfo>>,
+    holders: HashMap<ColorIdentifier, Vec<HolderInfo>>,
     edges: HashMap<OutPoint, (Txid, u32)>, // OutPoint -> (spending_txid, spending_vin)
     recent: ArrayDeque<[TxOverview; RECENT_TXS_SIZE], Wrapping>, // The N most recent txs to enter the mempool
     overviews: HashMap<Txid, TxOverview>,
     backlog_stats: (BacklogStats, Instant),
+    // scripthashes whose mempool history changed since take_touched_scripthashes()
+    touched_scripthashes: HashSet<FullHash>,
 
     // monitoring
     latency: HistogramVec, // mempool requests latency
@@ -83,6 +88,7 @@ impl Mempool {
             feeinfo: HashMap::new(),
             history: HashMap::new(),
             colors: HashMap::new(),
+            holders: HashMap::new(),
             edges: HashMap::new(),
             recent: ArrayDeque::new(),
             overviews: HashMap::new(),
@@ -90,6 +96,7 @@ impl Mempool {
                 BacklogStats::default(),
                 Instant::now() - Duration::from_secs(BACKLOG_STATS_TTL),
             ),
+            touched_scripthashes: HashSet::new(),
             latency: metrics.histogram_vec(
                 HistogramOpts::new("mempool_latency", "Mempool requests latency (in seconds)"),
                 &["part"],
@@ -162,6 +169,16 @@ impl Mempool {
     }
 
     pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Vec<Txid> {
+        self.history_txids_after(scripthash, None, limit)
+    }
+
+    // same as history_txids(), but starting after last_seen_txid
+    pub fn history_txids_after(
+        &self,
+        scripthash: &[u8],
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Vec<Txid> {
         let _timer = self
             .latency
             .with_label_values(&["history_txids"])
@@ -172,6 +189,30 @@ impl Mempool {
                 .iter()
                 .map(|e| e.get_txid())
                 .unique()
+                .skip_while(|txid| last_seen_txid.map_or(false, |last_seen| last_seen != txid))
+                .skip(last_seen_txid.map_or(0, |_| 1))
+                .take(limit)
+                .collect(),
+        }
+    }
+
+    pub fn history_txids_by_color(
+        &self,
+        scripthash: &[u8],
+        color_id: &ColorIdentifier,
+        limit: usize,
+    ) -> Vec<Txid> {
+        let _timer = self
+            .latency
+            .with_label_values(&["history_txids_by_color"])
+            .start_timer();
+        match self.history.get(scripthash) {
+            None => vec![],
+            Some(entries) => entries
+                .iter()
+                .filter(|e| e.color_id() == *color_id)
+                .map(|e| e.get_txid())
+                .unique()
                 .take(limit)
                 .collect(),
         }
@@ -231,6 +272,94 @@ impl Mempool {
         Ok(stats)
     }
 
+    // the first issuance of color_id in the mempool
+    pub fn get_color_first_issuance(&self, color_id: &ColorIdentifier) -> Option<Txid> {
+        self.colors
+            .get(color_id)?
+            .iter()
+            .find(|entry| matches!(entry, ColoredTxHistoryInfo::Issuing(_)))
+            .map(|entry| entry.get_txid())
+    }
+
+    // the change to the balance of every holder of color_id
+    pub fn get_colored_holders(&self, color_id: &ColorIdentifier) -> HolderMap {
+        let _timer = self
+            .latency
+            .with_label_values(&["get_colored_holders"])
+            .start_timer();
+        match self.holders.get(color_id) {
+            None => HolderMap::new(),
+            Some(entries) => update_holders(HolderMap::new(), entries.iter()),
+        }
+    }
+
+    // the unspent outputs carrying the NFT color_id
+    pub fn get_nft_outputs(&self, color_id: &ColorIdentifier) -> Vec<NftOutput> {
+        let _timer = self
+            .latency
+            .with_label_values(&["get_nft_outputs"])
+            .start_timer();
+        let entries = match self.holders.get(color_id) {
+            None => return vec![],
+            Some(entries) => entries,
+        };
+        entries
+            .iter()
+            .filter(|info| info.received > 0)
+            .map(|info| info.get_txid())
+            .unique()
+            .flat_map(|txid| {
+                let tx = self.txstore.get(&txid).expect("missing mempool tx");
+                tx.output
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, txo)| {
+                        txo.script_pubkey
+                            .split_color()
+                            .map_or(false, |(color, _)| color == *color_id)
+                    })
+                    .map(|(vout, txo)| NftOutput {
+                        color_id: color_id.clone(),
+                        txid,
+                        vout: vout as u32,
+                        script: txo.script_pubkey.clone(),
+                        confirmed: None,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .filter(|output| !self.has_spend(&OutPoint::from(output)))
+            .collect()
+    }
+
+    // the unspent NFT outputs paying to scripthash
+    pub fn nfts(&self, scripthash: &[u8]) -> Vec<NftOutput> {
+        let _timer = self.latency.with_label_values(&["nfts"]).start_timer();
+        let entries = match self.history.get(scripthash) {
+            None => return vec![],
+            Some(entries) => entries,
+        };
+        entries
+            .iter()
+            .filter_map(|entry| match entry {
+                TxHistoryInfo::Funding(info)
+                    if TokenType::of(&info.color_id) == Some(TokenType::Nft) =>
+                {
+                    let txid = deserialize(&info.txid).expect("invalid txid");
+                    let tx = self.txstore.get(&txid).expect("missing mempool tx");
+                    Some(NftOutput {
+                        color_id: info.color_id.clone(),
+                        txid,
+                        vout: info.vout as u32,
+                        script: tx.output[info.vout as usize].script_pubkey.clone(),
+                        confirmed: None,
+                    })
+                }
+                _ => None,
+            })
+            .filter(|output| !self.has_spend(&OutPoint::from(output)))
+            .collect()
+    }
+
     pub fn get_colored_txs(
         &self,
         color_id: &ColorIdentifier,
@@ -278,6 +407,10 @@ impl Mempool {
         &self.backlog_stats.0
     }
 
+    pub fn take_touched_scripthashes(&mut self) -> HashSet<FullHash> {
+        std::mem::take(&mut self.touched_scripthashes)
+    }
+
     pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
         let _timer = self.latency.with_label_values(&["update"]).start_timer();
         let txs = daemon
@@ -482,6 +615,7 @@ impl Mempool {
 
             // Index funding/spending history entries and spend edges
             for (scripthash, entry) in funding.chain(spending) {
+                self.touched_scripthashes.insert(scripthash);
                 self.history
                     .entry(scripthash)
                     .or_insert_with(Vec::new)
@@ -497,6 +631,12 @@ impl Mempool {
                     .or_insert_with(Vec::new)
                     .push(entry);
             }
+            for (color_id, entry) in colored_tx_holders(&tx, &txos) {
+                self.holders
+                    .entry(color_id)
+                    .or_insert_with(Vec::new)
+                    .push(entry);
+            }
         }
     }
 
@@ -571,9 +711,29 @@ impl Mempool {
             });
         }
 
+        // the children of the removed transactions no longer have unconfirmed parents, which
+        // changes their electrum status
+        let children: HashSet<Txid> = self
+            .edges
+            .iter()
+            .filter(|(outpoint, (txid, _vin))| {
+                to_remove.contains(&outpoint.txid) && !to_remove.contains(txid)
+            })
+            .map(|(_outpoint, (txid, _vin))| *txid)
+            .collect();
+
         // TODO: make it more efficient (currently it takes O(|mempool|) time)
-        self.history.retain(|_scripthash, entries| {
+        let touched_scripthashes = &mut self.touched_scripthashes;
+        self.history.retain(|scripthash, entries| {
+            let len = entries.len();
             entries.retain(|entry| !to_remove.contains(&entry.get_txid()));
+            if entries.len() != len
+                || entries
+                    .iter()
+                    .any(|entry| children.contains(&entry.get_txid()))
+            {
+                touched_scripthashes.insert(*scripthash);
+            }
             !entries.is_empty()
         });
 
@@ -584,6 +744,11 @@ impl Mempool {
             entries.retain(|entry| !to_remove.contains(&entry.get_txid()));
             !entries.is_empty()
         });
+
+        self.holders.retain(|_color_id, entries| {
+            entries.retain(|entry| !to_remove.contains(&entry.get_txid()));
+            !entries.is_empty()
+        });
     }
 }
 
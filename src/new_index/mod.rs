@@ -1,10 +1,16 @@
 pub mod color;
 pub mod db;
+pub mod events;
+pub mod federation;
 mod fetch;
 pub mod mempool;
+pub mod open_assets;
 pub mod precache;
 mod query;
 pub mod schema;
+#[cfg(test)]
+pub mod test_helper;
+pub mod xpub;
 
 pub use self::db::{DBRow, DB};
 pub use self::fetch::{BlockEntry, FetchFrom};
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use openassets_tapyrus::openassets::asset_id::AssetId;
+use tapyrus::consensus::encode::{deserialize, serialize};
+use tapyrus::hashes::{hash160, Hash};
+use tapyrus::network::constants::Network as BNetwork;
+use tapyrus::{ColorIdentifier, OutPoint, Transaction, TxOut, Txid};
+
+use crate::new_index::db::{DBRow, DB};
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::schema::{
+    FullHash, FundingInfo, SpendingInfo, TxHistoryInfo, TxHistoryKey, TxHistoryRow,
+};
+use crate::open_assets::{color_transaction, OpenAsset};
+use crate::util::{full_hash, has_prevout, Bytes};
+
+pub type AssetMap = HashMap<OutPoint, OpenAsset>;
+
+/// The confirmed history of an asset, summed from its history rows and the assets of the
+/// outputs they fund and spend
+#[derive(Debug, Default, PartialEq)]
+pub struct OpenAssetStats {
+    pub issuances: Vec<(Txid, u32, u64)>, // txid, height and quantity issued, in chain order
+    pub issued_sum: u64,
+    pub tx_count: usize,
+    pub utxo_count: usize,
+    pub unspent_sum: u64,
+    pub holder_count: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenAssetKey {
+    code: u8,
+    txid: FullHash,
+    vout: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenAssetValue {
+    asset_id: Bytes, // hash160 of the issuance script
+    asset_quantity: u64,
+    metadata: Bytes, // consensus-serialized metadata
+}
+
+pub struct OpenAssetRow {
+    key: OpenAssetKey,
+    value: OpenAssetValue,
+}
+
+impl OpenAssetRow {
+    pub fn new(txid: FullHash, vout: u16, asset: &OpenAsset) -> Self {
+        OpenAssetRow {
+            key: OpenAssetKey {
+                code: b'A',
+                txid,
+                vout,
+            },
+            value: OpenAssetValue {
+                asset_id: asset.asset_id.hash[..].to_vec(),
+                asset_quantity: asset.asset_quantity,
+                metadata: serialize(&asset.metadata),
+            },
+        }
+    }
+
+    pub fn key(outpoint: &OutPoint) -> Bytes {
+        bincode::serialize(&OpenAssetKey {
+            code: b'A',
+            txid: full_hash(&outpoint.txid[..]),
+            vout: outpoint.vout as u16,
+        })
+        .unwrap()
+    }
+
+    pub fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: bincode::serialize(&self.value).unwrap(),
+        }
+    }
+
+    pub fn asset(value: &[u8], network: BNetwork) -> OpenAsset {
+        let value: OpenAssetValue =
+            bincode::deserialize(value).expect("failed to parse OpenAssetValue");
+        OpenAsset {
+            asset_id: AssetId {
+                hash: hash160::Hash::from_slice(&value.asset_id).expect("invalid asset id"),
+                network,
+            },
+            asset_quantity: value.asset_quantity,
+            metadata: deserialize(&value.metadata).expect("failed to parse Metadata"),
+        }
+    }
+}
+
+// asset ids are only 160 bits long, pad them to fit into TxHistoryKey.hash
+pub fn asset_history_hash(asset_id: &AssetId) -> FullHash {
+    let mut hash = [0u8; 32];
+    hash[..20].copy_from_slice(&asset_id.hash[..]);
+    hash
+}
+
+pub fn lookup_open_asset(
+    history_db: &DB,
+    outpoint: &OutPoint,
+    network: BNetwork,
+) -> Option<OpenAsset> {
+    history_db
+        .get(&OpenAssetRow::key(outpoint))
+        .map(|value| OpenAssetRow::asset(&value, network))
+}
+
+// Color the outputs of the given blocks. This has to be done sequentially in chain order, since
+// the assets of an output depend on the assets of the outputs it spends, which may be part of
+// the same batch. Returns the assets of the new outputs along with those of the spent outputs.
+pub fn color_blocks(
+    history_db: &DB,
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    network: BNetwork,
+) -> AssetMap {
+    let mut assets = AssetMap::new();
+    for tx in block_entries.iter().flat_map(|b| b.block.txdata.iter()) {
+        let mut prev_outs = vec![];
+        for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+            let outpoint = txin.previous_output;
+            let prev_txo = previous_txos_map
+                .get(&outpoint)
+                .unwrap_or_else(|| panic!("missing previous txo {}", outpoint));
+            let asset = match assets.get(&outpoint) {
+                Some(asset) => Some(asset.clone()),
+                None => lookup_open_asset(history_db, &outpoint, network),
+            };
+            if let Some(ref asset) = asset {
+                assets.insert(outpoint, asset.clone());
+            }
+            prev_outs.push((prev_txo.clone(), asset));
+        }
+
+        let txid = tx.malfix_txid();
+        for (vout, asset) in color_transaction(tx, prev_outs, network)
+            .into_iter()
+            .enumerate()
+        {
+            if let Some(asset) = asset {
+                assets.insert(OutPoint::new(txid, vout as u32), asset);
+            }
+        }
+    }
+    assets
+}
+
+// collect the open assets of the outputs created by the specified tx, and the history of the
+// assets it receives and spends.
+pub fn index_confirmed_open_assets_tx(
+    tx: &Transaction,
+    confirmed_height: u32,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    assets: &AssetMap,
+    rows: &mut Vec<DBRow>,
+) {
+    let txid = tx.malfix_txid();
+    let txid_hash = full_hash(&txid[..]);
+    for (txo_index, txo) in tx.output.iter().enumerate() {
+        if let Some(asset) = assets.get(&OutPoint::new(txid, txo_index as u32)) {
+            rows.push(OpenAssetRow::new(txid_hash, txo_index as u16, asset).into_row());
+            let history = asset_history_row(
+                asset,
+                confirmed_height,
+                TxHistoryInfo::Funding(FundingInfo {
+                    txid: txid_hash,
+                    vout: txo_index as u16,
+                    color_id: color_id(txo),
+                    value: txo.value,
+                    open_asset: Some(asset.clone()),
+                }),
+            );
+            rows.push(history.into_row());
+        }
+    }
+    for (txi_index, txi) in tx.input.iter().enumerate() {
+        if !has_prevout(txi) {
+            continue;
+        }
+        if let Some(asset) = assets.get(&txi.previous_output) {
+            let prev_txo = previous_txos_map
+                .get(&txi.previous_output)
+                .unwrap_or_else(|| panic!("missing previous txo {}", txi.previous_output));
+            let history = asset_history_row(
+                asset,
+                confirmed_height,
+                TxHistoryInfo::Spending(SpendingInfo {
+                    txid: txid_hash,
+                    vin: txi_index as u16,
+                    prev_txid: full_hash(&txi.previous_output.txid[..]),
+                    prev_vout: txi.previous_output.vout as u16,
+                    color_id: color_id(prev_txo),
+                    value: prev_txo.value,
+                }),
+            );
+            rows.push(history.into_row());
+        }
+    }
+}
+
+fn asset_history_row(
+    asset: &OpenAsset,
+    confirmed_height: u32,
+    txinfo: TxHistoryInfo,
+) -> TxHistoryRow {
+    let key = TxHistoryKey {
+        code: b'I',
+        hash: asset_history_hash(&asset.asset_id),
+        confirmed_height,
+        txinfo,
+    };
+    TxHistoryRow { key }
+}
+
+fn color_id(txo: &TxOut) -> ColorIdentifier {
+    txo.script_pubkey
+        .split_color()
+        .map_or_else(ColorIdentifier::default, |(color_id, _)| color_id)
+}
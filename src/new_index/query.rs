@@ -1,21 +1,21 @@
 use rayon::prelude::*;
 
-use openassets_tapyrus::openassets::marker_output::TxOutExt;
 use std::collections::{BTreeSet, HashMap};
 use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::time::{Duration, Instant};
 
 use crate::chain::{Network, NetworkType, OutPoint, Transaction, TxOut};
 use crate::config::Config;
-use crate::daemon::Daemon;
+use crate::daemon::{Daemon, MempoolInfo};
 use crate::errors::*;
-use crate::new_index::color::ColoredStats;
-use crate::new_index::schema::StatsMap;
-use crate::new_index::{ChainQuery, Mempool, SpendingInput, Utxo};
-use crate::open_assets::{compute_assets, OpenAsset};
-use crate::util::{is_spendable, BlockId, Bytes, TransactionStatus};
+use crate::new_index::color::{ColorIssuer, ColoredStats, Holder, NftOutput, TokenType};
+use crate::new_index::schema::{FullHash, StatsMap};
+use crate::new_index::{compute_script_hash, ChainQuery, Mempool, SpendingInput, Utxo};
+use crate::open_assets::{color_transaction, OpenAsset};
+use crate::util::{has_prevout, is_spendable, BlockId, Bytes, TransactionStatus};
 
-use tapyrus::{ColorIdentifier, Txid};
+use serde_json::Value;
+use tapyrus::{ColorIdentifier, Script, Txid};
 
 const FEE_ESTIMATES_TTL: u64 = 60; // seconds
 
@@ -83,14 +83,72 @@ impl Query {
         Ok(utxos)
     }
 
+    pub fn utxo_by_color(
+        &self,
+        scripthash: &[u8],
+        color_id: &ColorIdentifier,
+    ) -> Result<Vec<Utxo>> {
+        let mut utxos = self
+            .chain
+            .utxo_by_color(scripthash, color_id, self.config.utxos_limit)?;
+        let mempool = self.mempool();
+        utxos.retain(|utxo| !mempool.has_spend(&OutPoint::from(utxo)));
+        utxos.extend(
+            mempool
+                .utxo(scripthash)
+                .into_iter()
+                .filter(|utxo| utxo.color_id == *color_id),
+        );
+        Ok(utxos)
+    }
+
     pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Vec<(Txid, Option<BlockId>)> {
-        let confirmed_txids = self.chain.history_txids(scripthash, limit);
+        self.history_txids_after(scripthash, None, limit)
+    }
+
+    // same as history_txids(), but starting after last_seen_txid, which may be confirmed or
+    // still in the mempool
+    pub fn history_txids_after(
+        &self,
+        scripthash: &[u8],
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Vec<(Txid, Option<BlockId>)> {
+        let mempool = self.mempool();
+        let unconfirmed_seen = last_seen_txid.filter(|txid| mempool.lookup_raw_txn(txid).is_some());
+
+        let confirmed_txids = match unconfirmed_seen {
+            Some(_) => vec![],
+            None => self
+                .chain
+                .history_txids_after(scripthash, last_seen_txid, limit),
+        };
+        let confirmed_len = confirmed_txids.len();
+        let confirmed_txids = confirmed_txids.into_iter().map(|(tx, b)| (tx, Some(b)));
+
+        let mempool_txids = mempool
+            .history_txids_after(scripthash, unconfirmed_seen, limit - confirmed_len)
+            .into_iter()
+            .map(|tx| (tx, None));
+
+        confirmed_txids.chain(mempool_txids).collect()
+    }
+
+    pub fn history_txids_by_color(
+        &self,
+        scripthash: &[u8],
+        color_id: &ColorIdentifier,
+        limit: usize,
+    ) -> Vec<(Txid, Option<BlockId>)> {
+        let confirmed_txids = self
+            .chain
+            .history_txids_by_color(scripthash, color_id, limit);
         let confirmed_len = confirmed_txids.len();
         let confirmed_txids = confirmed_txids.into_iter().map(|(tx, b)| (tx, Some(b)));
 
         let mempool_txids = self
             .mempool()
-            .history_txids(scripthash, limit - confirmed_len)
+            .history_txids_by_color(scripthash, color_id, limit - confirmed_len)
             .into_iter()
             .map(|tx| (tx, None));
 
@@ -115,6 +173,17 @@ impl Query {
             .or_else(|| self.mempool().lookup_raw_txn(txid))
     }
 
+    // The transaction as decoded by tapyrusd
+    pub fn lookup_txn_verbose(&self, txid: &Txid) -> Result<Value> {
+        let blockhash = self.chain.tx_confirming_block(txid).map(|b| b.hash);
+        self.daemon
+            .gettransaction_raw(txid, blockhash.as_ref(), true)
+    }
+
+    pub fn get_mempool_info(&self) -> Result<MempoolInfo> {
+        self.daemon.getmempoolinfo()
+    }
+
     pub fn lookup_txos(&self, outpoints: &BTreeSet<OutPoint>) -> HashMap<OutPoint, TxOut> {
         // the mempool lookup_txos() internally looks up confirmed txos as well
         self.mempool()
@@ -148,49 +217,39 @@ impl Query {
     }
 
     pub fn load_assets(&self, tx: &Transaction) -> Result<Vec<Option<OpenAsset>>> {
+        let txid = tx.malfix_txid();
+        if self.chain.tx_confirming_block(&txid).is_some() {
+            // confirmed transactions were colored by the indexer
+            return Ok((0..tx.output.len())
+                .map(|vout| self.chain.open_asset(&OutPoint::new(txid, vout as u32)))
+                .collect());
+        }
         let network = tapyrus::network::constants::Network::from(self.config.network);
-        Ok(self.get_open_assets_colored_outputs(network, &tx))
+        let prev_outs = tx
+            .input
+            .iter()
+            .filter(|txin| has_prevout(txin))
+            .map(|txin| self.get_output(&txin.previous_output))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(color_transaction(tx, prev_outs, network))
     }
 
-    fn get_open_assets_colored_outputs(
-        &self,
-        network_type: tapyrus::network::constants::Network,
-        txn: &Transaction,
-    ) -> Vec<Option<OpenAsset>> {
-        if txn.is_coin_base() {
-            txn.output.iter().map(|_| None).collect()
-        } else {
-            for (i, val) in txn.output.iter().enumerate() {
-                let payload = val.get_oa_payload();
-                if let Ok(marker) = payload {
-                    let prev_outs = txn
-                        .input
-                        .iter()
-                        .map(|input| {
-                            self.get_output(&input.previous_output.txid, input.previous_output.vout)
-                        })
-                        .collect();
-                    return compute_assets(
-                        prev_outs,
-                        i,
-                        txn,
-                        marker.quantities,
-                        network_type,
-                        &marker.metadata,
-                    );
-                }
-            }
-            txn.output.iter().map(|_| None).collect()
+    fn get_output(&self, outpoint: &OutPoint) -> Result<(TxOut, Option<OpenAsset>)> {
+        if let Some(txo) = self.chain.lookup_txo(outpoint) {
+            return Ok((txo, self.chain.open_asset(outpoint)));
         }
-    }
-
-    fn get_output(&self, txid: &Txid, index: u32) -> (TxOut, Option<OpenAsset>) {
-        let txn = self.lookup_txn(txid).expect("txn not found");
-        let colored_outputs = self.load_assets(&txn).expect("asset not found");
-        (
-            txn.output[index as usize].clone(),
-            colored_outputs[index as usize].clone(),
-        )
+        // unconfirmed parents have to be colored on the fly
+        let txn = self
+            .mempool()
+            .lookup_txn(&outpoint.txid)
+            .chain_err(|| format!("missing previous tx {}", outpoint.txid))?;
+        let mut assets = self.load_assets(&txn)?;
+        let txo = txn
+            .output
+            .get(outpoint.vout as usize)
+            .cloned()
+            .chain_err(|| format!("missing previous txo {}", outpoint))?;
+        Ok((txo, assets.swap_remove(outpoint.vout as usize)))
     }
 
     pub fn get_tx_status(&self, txid: &Txid) -> TransactionStatus {
@@ -257,25 +316,26 @@ impl Query {
     }
 
     pub fn open_assets(&self, utxos: &Vec<Utxo>) -> Result<HashMap<OutPoint, OpenAsset>> {
-        let network = tapyrus::network::constants::Network::from(self.config.network);
         let mut map: HashMap<OutPoint, OpenAsset> = HashMap::new();
-        utxos.iter().for_each(|o| match self.lookup_txn(&o.txid) {
-            Some(tx) => {
-                let outputs = self.get_open_assets_colored_outputs(network, &tx);
-                match outputs.get(o.vout as usize) {
-                    Some(Some(asset)) => {
-                        map.insert(OutPoint::new(o.txid, o.vout), asset.clone());
-                    }
-                    Some(_) => {}
-                    None => {}
-                }
+        for utxo in utxos {
+            let outpoint = OutPoint::from(utxo);
+            let asset = match utxo.confirmed {
+                Some(_) => self.chain.open_asset(&outpoint),
+                None => self.get_output(&outpoint)?.1,
+            };
+            if let Some(asset) = asset {
+                map.insert(outpoint, asset);
             }
-            None => {}
-        });
+        }
         Ok(map)
     }
 
-    pub fn get_colors(&self, last_seen_color_id: Option<ColorIdentifier>, limit: usize) -> Vec<(ColorIdentifier, u32)> {
+    pub fn get_colors(
+        &self,
+        last_seen_color_id: Option<ColorIdentifier>,
+        token_type: Option<TokenType>,
+        limit: usize,
+    ) -> Vec<(ColorIdentifier, u32)> {
         let (block_height, color_id) = if let Some(color_id) = last_seen_color_id {
             match self.chain.get_height_by_color_id(&color_id) {
                 Some(height) => (height, Some(color_id)),
@@ -287,7 +347,7 @@ impl Query {
 
         let colors = self
             .chain
-            .get_colors(block_height, &color_id, limit)
+            .get_colors(block_height, &color_id, token_type, limit)
             .expect("failed to get colors");
         colors
     }
@@ -303,6 +363,101 @@ impl Query {
         )
     }
 
+    // the first issuance of color_id (confirmed if any), along with the issuer it commits to
+    pub fn get_color_issuance(
+        &self,
+        color_id: &ColorIdentifier,
+    ) -> Option<(Txid, Option<BlockId>, Option<ColorIssuer>)> {
+        let (txid, blockid) = match self.chain.get_color_first_issuance(color_id) {
+            Some((txid, blockid)) => (txid, Some(blockid)),
+            None => (self.mempool().get_color_first_issuance(color_id)?, None),
+        };
+        let tx = self.lookup_txn(&txid)?;
+        let prevouts = self.lookup_txos(
+            &tx.input
+                .iter()
+                .filter(|txin| has_prevout(txin))
+                .map(|txin| txin.previous_output)
+                .collect(),
+        );
+        Some((txid, blockid, ColorIssuer::find(color_id, &tx, &prevouts)))
+    }
+
+    // the unspent output currently carrying the NFT color_id, including the mempool
+    pub fn get_nft_owner(&self, color_id: &ColorIdentifier) -> Option<NftOutput> {
+        let mempool = self.mempool();
+        match self.chain.get_nft_owner(color_id) {
+            Some(output) if !mempool.has_spend(&OutPoint::from(&output)) => Some(output),
+            _ => mempool.get_nft_outputs(color_id).into_iter().next(),
+        }
+    }
+
+    // the NFTs held by scripthash, including the mempool
+    pub fn nfts(&self, scripthash: &[u8]) -> Vec<NftOutput> {
+        let mempool = self.mempool();
+        let mut nfts: Vec<NftOutput> = self
+            .chain
+            .nfts(scripthash)
+            .into_iter()
+            .filter(|output| !mempool.has_spend(&OutPoint::from(output)))
+            .collect();
+        nfts.extend(mempool.nfts(scripthash));
+        nfts
+    }
+
+    // the holders of color_id ordered by balance (largest first, including the mempool), then
+    // by scripthash. pages start after the holder with the last_seen_scripthash.
+    pub fn get_colored_holders(
+        &self,
+        color_id: &ColorIdentifier,
+        last_seen_scripthash: Option<&FullHash>,
+        limit: usize,
+    ) -> Vec<Holder> {
+        let mut chain_holders = self.chain.get_colored_holders(color_id);
+        let mempool_holders = self.mempool().get_colored_holders(color_id);
+
+        let mut holders: Vec<Holder> = mempool_holders
+            .into_iter()
+            .map(|(script, mempool_balance)| {
+                let chain_balance = chain_holders.remove(&script).unwrap_or(0);
+                (script, chain_balance, mempool_balance)
+            })
+            .chain(
+                chain_holders
+                    .into_iter()
+                    .map(|(script, chain_balance)| (script, chain_balance, 0)),
+            )
+            .map(|(script, chain_balance, mempool_balance)| {
+                let script = Script::from(script);
+                Holder {
+                    scripthash: compute_script_hash(&script),
+                    script,
+                    chain_balance: chain_balance as u64,
+                    mempool_balance,
+                }
+            })
+            .filter(|holder| holder.balance() > 0)
+            .collect();
+        holders.sort_unstable_by(|a, b| {
+            b.balance()
+                .cmp(&a.balance())
+                .then_with(|| a.scripthash.cmp(&b.scripthash))
+        });
+
+        holders
+            .into_iter()
+            .skip_while(|holder| {
+                // skip until we reach the last_seen_scripthash
+                last_seen_scripthash.map_or(false, |last_seen| *last_seen != holder.scripthash)
+            })
+            .skip(match last_seen_scripthash {
+                Some(_) => 1, // skip the last_seen_scripthash itself
+                None => 0,
+            })
+            .take(limit)
+            .collect()
+    }
+
     pub fn get_colored_txs(
         &self,
         color_id: &ColorIdentifier,
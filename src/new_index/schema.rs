@@ -1,11 +1,13 @@
 use bincode::config::Options;
 use sha2::{Digest, Sha256};
 use itertools::Itertools;
+use openassets_tapyrus::openassets::asset_id::AssetId;
 use rayon::prelude::*;
 use serde::Deserialize;
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::Path;
 use std::sync::{Arc, RwLock};
+use tapyrus::blockdata::block::XField;
 use tapyrus::blockdata::script::{ColorIdentifier, Script};
 use tapyrus::consensus::encode::{deserialize, serialize};
 use tapyrus::hashes::sha256d::Hash as Sha256dHash;
@@ -16,10 +18,18 @@ use crate::chain::{BlockHeader, Network, OutPoint, Transaction, TxOut, Value};
 use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::metrics::{HistogramOpts, HistogramTimer, HistogramVec, Metrics};
+use crate::metrics::{Counter, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics};
 use crate::new_index::color::{
-    index_confirmed_colored_tx, ColoredStats, ColoredStatsCacheRow, ColoredTxHistoryInfo,
-    ColoredTxHistoryRow,
+    index_confirmed_colored_tx, update_holders, ColoredHolderRow, ColoredHoldersCacheRow,
+    ColoredStats, ColoredStatsCacheRow, ColoredSupply, ColoredSupplyCacheRow, ColoredTxHistoryInfo,
+    ColoredTxHistoryRow, HolderInfo, HolderMap, NftOutput, NftOutputRow, TokenType,
+};
+use crate::new_index::federation::{
+    verify_block_proof, XFieldChange, XFieldRow, DEFAULT_MAX_BLOCK_SIZE,
+};
+use crate::new_index::open_assets::{
+    asset_history_hash, color_blocks, index_confirmed_open_assets_tx, lookup_open_asset, AssetMap,
+    OpenAssetStats,
 };
 use crate::open_assets::OpenAsset;
 use crate::util::{
@@ -153,12 +163,19 @@ pub struct Indexer {
     from: FetchFrom,
     iconfig: IndexerConfig,
     duration: HistogramVec,
+    invalid_block_proofs: Counter,
+    // the blocks refused for their proof, which are neither verified nor counted again
+    rejected_blockhashes: HashSet<BlockHash>,
+    // scripthashes whose confirmed history changed since take_touched_scripthashes()
+    touched_scripthashes: HashSet<FullHash>,
 }
 
 struct IndexerConfig {
     light_mode: bool,
     address_search: bool,
     index_unspendables: bool,
+    open_assets: bool,
+    verify_block_proofs: bool,
     network: Network,
 }
 
@@ -168,6 +185,8 @@ impl From<&Config> for IndexerConfig {
             light_mode: config.light_mode,
             address_search: config.address_search,
             index_unspendables: config.index_unspendables,
+            open_assets: config.enable_open_assets,
+            verify_block_proofs: config.verify_block_proofs,
             network: config.network,
         }
     }
@@ -193,6 +212,24 @@ impl Indexer {
                 HistogramOpts::new("index_duration", "Index update duration (in seconds)"),
                 &["step"],
             ),
+            invalid_block_proofs: metrics.counter(MetricOpts::new(
+                "index_invalid_block_proofs",
+                "# of block headers refused for having an invalid proof",
+            )),
+            rejected_blockhashes: HashSet::new(),
+            touched_scripthashes: HashSet::new(),
+        }
+    }
+
+    pub fn take_touched_scripthashes(&mut self) -> HashSet<FullHash> {
+        std::mem::take(&mut self.touched_scripthashes)
+    }
+
+    // scripthashes are not tracked during the initial sync, when nobody is subscribed yet
+    fn track_touched_scripthashes(&self) -> bool {
+        match self.flush {
+            DBFlush::Enable => true,
+            DBFlush::Disable => false,
         }
     }
 
@@ -242,7 +279,24 @@ impl Indexer {
     pub fn update(&mut self, daemon: &Daemon) -> Result<BlockHash> {
         let daemon = daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
-        let new_headers = self.get_new_headers(&daemon, &tip)?;
+        let mut new_headers = self.get_new_headers(&daemon, &tip)?;
+        let mut rejected = false;
+        if self.iconfig.verify_block_proofs {
+            if let Err(Error(ErrorKind::InvalidBlockProof(_, height), _)) =
+                self.verify_block_proofs(&new_headers)
+            {
+                self.reject_from(&mut new_headers, height);
+                rejected = true;
+            }
+        }
+
+        let orphaned = self.orphaned_headers(&new_headers);
+        if !orphaned.is_empty() {
+            let mut blocks = vec![];
+            start_fetcher(FetchFrom::Tapyrusd, &daemon, orphaned)?
+                .map(|mut entries| blocks.append(&mut entries));
+            self.rollback(&blocks, &new_headers);
+        }
 
         let to_add = self.headers_to_add(&new_headers);
         debug!(
@@ -253,13 +307,19 @@ impl Indexer {
         start_fetcher(self.from, &daemon, to_add)?.map(|blocks| self.add(&blocks));
         self.start_auto_compactions(&self.store.txstore_db);
 
+        // open assets are colored in chain order, which blk files don't guarantee
+        let index_from = if self.iconfig.open_assets {
+            FetchFrom::Tapyrusd
+        } else {
+            self.from
+        };
         let to_index = self.headers_to_index(&new_headers);
         debug!(
             "indexing history from {} blocks using {:?}",
             to_index.len(),
-            self.from
+            index_from
         );
-        start_fetcher(self.from, &daemon, to_index)?.map(|blocks| self.index(&blocks));
+        start_fetcher(index_from, &daemon, to_index)?.map(|blocks| self.index(&blocks));
         self.start_auto_compactions(&self.store.history_db);
 
         if let DBFlush::Disable = self.flush {
@@ -270,12 +330,17 @@ impl Indexer {
         }
 
         // update the synced tip *after* the new data is flushed to disk
-        debug!("updating synced tip to {:?}", tip);
-        self.store.txstore_db.put_sync(b"t", &serialize(&tip));
+        if let Some(synced_tip) = new_headers.last() {
+            debug!("updating synced tip to {:?}", synced_tip.hash());
+            self.store
+                .txstore_db
+                .put_sync(b"t", &serialize(synced_tip.hash()));
+        }
 
         let mut headers = self.store.indexed_headers.write().unwrap();
-        headers.apply(new_headers);
-        assert_eq!(tip, *headers.tip());
+        let removed = headers.apply(new_headers);
+        assert!(removed.is_empty(), "orphaned blocks were not rolled back");
+        assert!(rejected || tip == *headers.tip());
 
         if let FetchFrom::BlkFiles = self.from {
             self.from = FetchFrom::Tapyrusd;
@@ -284,6 +349,190 @@ impl Indexer {
         Ok(tip)
     }
 
+    // Check the proof of every new header against the aggregate public key in force at its
+    // height, following the keys set by the new headers themselves. The headers that were
+    // already rejected are refused without being verified again.
+    fn verify_block_proofs(&self, new_headers: &[HeaderEntry]) -> Result<()> {
+        let _timer = self.start_timer("verify_block_proofs");
+        let first_height = match new_headers.first() {
+            Some(entry) => entry.height(),
+            None => return Ok(()),
+        };
+        let mut pubkey = match first_height {
+            0 => None,
+            height => {
+                let headers = self.store.indexed_headers.read().unwrap();
+                lookup_xfield_change(&self.store.txstore_db, &headers, height - 1, |change| {
+                    change.aggregate_pubkey().is_some()
+                })
+                .and_then(|change| change.aggregate_pubkey().cloned())
+            }
+        };
+        for entry in new_headers {
+            let header = entry.header();
+            let new_pubkey = match header.xfield {
+                XField::AggregatePublicKey(ref pubkey) => Some(pubkey.clone()),
+                _ => None,
+            };
+            // the genesis block is signed with its own aggregate public key
+            if entry.height() == 0 {
+                pubkey = new_pubkey.clone();
+            }
+            let valid = !self.rejected_blockhashes.contains(entry.hash())
+                && pubkey
+                    .as_ref()
+                    .map_or(false, |pubkey| verify_block_proof(header, pubkey));
+            if !valid {
+                bail!(ErrorKind::InvalidBlockProof(
+                    entry.hash().to_string(),
+                    entry.height()
+                ));
+            }
+            if new_pubkey.is_some() {
+                pubkey = new_pubkey;
+            }
+        }
+        Ok(())
+    }
+
+    // Leave out the block with an invalid proof at height, and the blocks after it, from the new
+    // headers. The update still indexes the blocks before it, unless they are a branch that is
+    // not longer than the indexed best chain, which keeps being served instead.
+    fn reject_from(&mut self, new_headers: &mut Vec<HeaderEntry>, height: usize) {
+        let index = height - new_headers[0].height();
+        let blockhash = *new_headers[index].hash();
+        if self.rejected_blockhashes.insert(blockhash) {
+            self.invalid_block_proofs.inc();
+            error!(
+                "not indexing past block {} at height {}: invalid block proof",
+                blockhash, height
+            );
+        }
+        new_headers.truncate(index);
+        let headers = self.store.indexed_headers.read().unwrap();
+        let replaces_best_chain = new_headers
+            .last()
+            .map_or(false, |entry| entry.height() >= headers.len());
+        if headers.reorg_height(new_headers).is_some() && !replaces_best_chain {
+            new_headers.clear();
+        }
+    }
+
+    // The blocks of the best chain that new_headers are about to replace (tip last). They stay
+    // part of the indexed headers until rollback() is done with them.
+    fn orphaned_headers(&self, new_headers: &[HeaderEntry]) -> Vec<HeaderEntry> {
+        let headers = self.store.indexed_headers.read().unwrap();
+        let fork_height = match headers.reorg_height(new_headers) {
+            Some(height) => height,
+            None => return vec![],
+        };
+        let orphaned: Vec<HeaderEntry> = headers.iter().skip(fork_height).cloned().collect();
+        warn!(
+            "reorg detected: disconnecting {} blocks from height {}",
+            orphaned.len(),
+            fork_height,
+        );
+        orphaned
+    }
+
+    // Delete every row written for the orphaned blocks and disconnect them, moving the synced
+    // tip back to the fork point, so that the new best chain can be added and indexed from a
+    // clean state.
+    fn rollback(&mut self, blocks: &[BlockEntry], new_headers: &[HeaderEntry]) {
+        let _timer = self.start_timer("rollback");
+
+        // regenerate the rows that were written by add() and index(). this has to happen
+        // before anything is deleted, since the orphaned blocks may spend each other's outputs.
+        let previous_txos_map =
+            lookup_txos(&self.store.txstore_db, &get_previous_txos(blocks), false);
+        let open_assets = self.color_open_assets(blocks, &previous_txos_map);
+        let history_rows = index_blocks(blocks, &previous_txos_map, &open_assets, &self.iconfig);
+        let txstore_rows = add_blocks(blocks, &self.iconfig);
+
+        let mut cache_keys = vec![];
+        let mut scripthashes = HashSet::new();
+        let mut color_ids = HashSet::new();
+        let history_keys: Vec<Bytes> = history_rows
+            .into_iter()
+            // address search rows are shared with other transactions and are never removed
+            .filter(|row| row.key[0] != b'a')
+            .map(|row| {
+                match row.key[0] {
+                    b'H' => {
+                        scripthashes.insert(TxHistoryRow::from_row(row.clone()).key.hash);
+                    }
+                    b'C' => {
+                        color_ids.insert(ColoredTxHistoryRow::from_row(row.clone()).key.color_id);
+                    }
+                    _ => (),
+                }
+                row.key
+            })
+            .collect();
+        for scripthash in &scripthashes {
+            cache_keys.push(UtxoCacheRow::key(scripthash));
+            cache_keys.extend(
+                self.store
+                    .cache_db
+                    .iter_scan(&StatsCacheRow::key(scripthash))
+                    .map(|row| row.key),
+            );
+        }
+        for color_id in &color_ids {
+            cache_keys.push(ColoredStatsCacheRow::key(color_id));
+            cache_keys.push(ColoredHoldersCacheRow::key(color_id));
+            cache_keys.push(ColoredSupplyCacheRow::key(color_id));
+        }
+        debug!(
+            "rolling back {} blocks ({} history rows, {} scripthashes, {} colors)",
+            blocks.len(),
+            history_keys.len(),
+            scripthashes.len(),
+            color_ids.len()
+        );
+
+        // the new best chain may share transactions with the orphaned blocks, so it has to be
+        // (re)written even if some of its blocks were seen before.
+        let blockhashes: Vec<BlockHash> = blocks
+            .iter()
+            .map(|b| *b.entry.hash())
+            .chain(new_headers.iter().map(|e| *e.hash()))
+            .collect();
+
+        // the txstore rows are deleted last, as they are needed to regenerate the other rows if
+        // the rollback is interrupted, and along with moving the synced tip back to the fork
+        // point: until then, the next start still sees the orphaned blocks and rolls them back.
+        self.store.history_db.delete(history_keys);
+        self.store.cache_db.delete(cache_keys);
+        {
+            let mut headers = self.store.indexed_headers.write().unwrap();
+            headers.rewind(blocks[0].entry.height());
+            self.store.txstore_db.delete_and_write(
+                txstore_rows.into_iter().map(|row| row.key).collect(),
+                vec![DBRow {
+                    key: b"t".to_vec(),
+                    value: serialize(headers.tip()),
+                }],
+            );
+            debug!("synced tip moved back to {:?}", headers.tip());
+        }
+        {
+            let mut indexed_blockhashes = self.store.indexed_blockhashes.write().unwrap();
+            for blockhash in &blockhashes {
+                indexed_blockhashes.remove(blockhash);
+            }
+        }
+        {
+            let mut added_blockhashes = self.store.added_blockhashes.write().unwrap();
+            for blockhash in &blockhashes {
+                added_blockhashes.remove(blockhash);
+            }
+        }
+        if self.track_touched_scripthashes() {
+            self.touched_scripthashes.extend(scripthashes);
+        }
+    }
+
     fn add(&self, blocks: &[BlockEntry]) {
         // TODO: skip orphaned blocks?
         let rows = {
@@ -302,11 +551,15 @@ impl Indexer {
             .extend(blocks.iter().map(|b| b.entry.hash()));
     }
 
-    fn index(&self, blocks: &[BlockEntry]) {
+    fn index(&mut self, blocks: &[BlockEntry]) {
         let previous_txos_map = {
             let _timer = self.start_timer("index_lookup");
             lookup_txos(&self.store.txstore_db, &get_previous_txos(blocks), false)
         };
+        let open_assets = {
+            let _timer = self.start_timer("index_open_assets");
+            self.color_open_assets(blocks, &previous_txos_map)
+        };
         let rows = {
             let _timer = self.start_timer("index_process");
             let added_blockhashes = self.store.added_blockhashes.read().unwrap();
@@ -317,10 +570,33 @@ impl Indexer {
                     panic!("cannot index block {} (missing from store)", blockhash);
                 }
             }
-            index_blocks(blocks, &previous_txos_map, &self.iconfig)
+            index_blocks(blocks, &previous_txos_map, &open_assets, &self.iconfig)
         };
+        if self.track_touched_scripthashes() {
+            self.touched_scripthashes.extend(
+                rows.iter()
+                    .filter(|row| row.key[0] == b'H')
+                    .map(|row| TxHistoryRow::from_row(row.clone()).key.hash),
+            );
+        }
         self.store.history_db.write(rows, self.flush);
     }
+
+    fn color_open_assets(
+        &self,
+        blocks: &[BlockEntry],
+        previous_txos_map: &HashMap<OutPoint, TxOut>,
+    ) -> AssetMap {
+        if !self.iconfig.open_assets {
+            return AssetMap::new();
+        }
+        color_blocks(
+            &self.store.history_db,
+            blocks,
+            previous_txos_map,
+            self.iconfig.network.into(),
+        )
+    }
 }
 
 impl ChainQuery {
@@ -453,6 +729,17 @@ impl ChainQuery {
         )
     }
 
+    pub fn colored_holders_iter_scan(
+        &self,
+        color_id: &ColorIdentifier,
+        start_height: usize,
+    ) -> ScanIterator<'_> {
+        self.store.history_db.iter_scan_from(
+            &ColoredHolderRow::filter(color_id),
+            &ColoredHolderRow::prefix_height(color_id, start_height as u32),
+        )
+    }
+
     pub fn history(
         &self,
         scripthash: &[u8],
@@ -497,16 +784,123 @@ impl ChainQuery {
             .collect()
     }
 
+    pub fn asset_history(
+        &self,
+        asset_id: &AssetId,
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Vec<(Transaction, BlockId)> {
+        // open assets lookup
+        self._history(b'I', &asset_history_hash(asset_id), last_seen_txid, limit)
+    }
+
+    // Scans the whole history of the asset. Outputs are issuances when a transaction funds more
+    // of the asset than it spends, as transfers have to keep the quantities.
+    pub fn asset_stats(&self, asset_id: &AssetId) -> OpenAssetStats {
+        let _timer = self.start_timer("asset_stats");
+        let network = self.network.into();
+        let quantity = |txid: &FullHash, vout: u16| {
+            let outpoint = OutPoint::new(deserialize(txid).unwrap(), vout as u32);
+            let asset = lookup_open_asset(&self.store.history_db, &outpoint, network);
+            (outpoint, asset.map_or(0, |asset| asset.asset_quantity))
+        };
+
+        // the height and the quantities funded and spent by each transaction
+        let mut txs: HashMap<Txid, (u32, u64, u64)> = HashMap::new();
+        let mut utxos: HashMap<OutPoint, u64> = HashMap::new();
+        let mut spent_outpoints = HashSet::new();
+        for row in self.history_iter_scan(b'I', &asset_history_hash(asset_id), 0) {
+            let history = TxHistoryRow::from_row(row);
+            let height = history.key.confirmed_height;
+            let tx = txs.entry(history.get_txid()).or_insert((height, 0, 0));
+            match history.key.txinfo {
+                TxHistoryInfo::Funding(info) => {
+                    let (outpoint, quantity) = quantity(&info.txid, info.vout);
+                    tx.1 += quantity;
+                    utxos.insert(outpoint, quantity);
+                }
+                TxHistoryInfo::Spending(info) => {
+                    let (outpoint, quantity) = quantity(&info.prev_txid, info.prev_vout);
+                    tx.2 += quantity;
+                    spent_outpoints.insert(outpoint);
+                }
+            }
+        }
+        utxos.retain(|outpoint, _| !spent_outpoints.contains(outpoint));
+
+        let mut issuances: Vec<(Txid, u32, u64)> = txs
+            .iter()
+            .filter(|(_, (_, funded, spent))| funded > spent)
+            .map(|(txid, (height, funded, spent))| (*txid, *height, funded - spent))
+            .collect();
+        issuances.sort_by_key(|(txid, height, _)| (*height, *txid));
+        let holders: HashSet<Script> = self
+            .lookup_txos(&utxos.keys().cloned().collect())
+            .into_iter()
+            .map(|(_, txo)| txo.script_pubkey)
+            .collect();
+        OpenAssetStats {
+            issued_sum: issuances.iter().map(|(_, _, quantity)| quantity).sum(),
+            issuances,
+            tx_count: txs.len(),
+            utxo_count: utxos.len(),
+            unspent_sum: utxos.values().sum(),
+            holder_count: holders.len(),
+        }
+    }
+
     pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Vec<(Txid, BlockId)> {
+        self.history_txids_after(scripthash, None, limit)
+    }
+
+    // same as history_txids(), but starting after last_seen_txid
+    pub fn history_txids_after(
+        &self,
+        scripthash: &[u8],
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Vec<(Txid, BlockId)> {
         // scripthash lookup
-        self._history_txids(b'H', scripthash, limit)
+        self._history_txids(b'H', scripthash, last_seen_txid, limit)
     }
 
-    fn _history_txids(&self, code: u8, hash: &[u8], limit: usize) -> Vec<(Txid, BlockId)> {
+    fn _history_txids(
+        &self,
+        code: u8,
+        hash: &[u8],
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Vec<(Txid, BlockId)> {
         let _timer = self.start_timer("history_txids");
         self.history_iter_scan(code, hash, 0)
             .map(|row| TxHistoryRow::from_row(row).get_txid())
             .unique()
+            .skip_while(|txid| {
+                // skip until we reach the last_seen_txid
+                last_seen_txid.map_or(false, |last_seen_txid| last_seen_txid != txid)
+            })
+            .skip(match last_seen_txid {
+                Some(_) => 1, // skip the last_seen_txid itself
+                None => 0,
+            })
+            .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
+            .take(limit)
+            .collect()
+    }
+
+    // same as history_txids(), but only for the entries that fund or spend outputs of color_id
+    pub fn history_txids_by_color(
+        &self,
+        scripthash: &[u8],
+        color_id: &ColorIdentifier,
+        limit: usize,
+    ) -> Vec<(Txid, BlockId)> {
+        let _timer = self.start_timer("history_txids_by_color");
+        self.history_iter_scan(b'H', scripthash, 0)
+            .map(TxHistoryRow::from_row)
+            .filter(|history| history.key.txinfo.color_id() == *color_id)
+            .map(|history| history.get_txid())
+            .unique()
             .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
             .take(limit)
             .collect()
@@ -516,24 +910,15 @@ impl ChainQuery {
     pub fn utxo(&self, scripthash: &[u8], limit: usize) -> Result<Vec<Utxo>> {
         let _timer = self.start_timer("utxo");
 
-        // get the last known utxo set and the blockhash it was updated for.
-        // invalidates the cache if the block was orphaned.
-        let cache: Option<(UtxoMap, usize)> = self
-            .store
-            .cache_db
-            .get(&UtxoCacheRow::key(scripthash))
-            .map(|c| bincode::deserialize(&c).unwrap())
-            .and_then(|(utxos_cache, blockhash)| {
-                self.height_by_hash(&blockhash)
-                    .map(|height| (utxos_cache, height))
-            })
-            .map(|(utxos_cache, height)| (from_utxo_cache(utxos_cache, self), height));
+        let cache = self.utxo_cache(scripthash);
         let had_cache = cache.is_some();
 
         // update utxo set with new transactions since
         let (newutxos, lastblock, processed_items) = cache.map_or_else(
-            || self.utxo_delta(scripthash, HashMap::new(), 0, limit),
-            |(oldutxos, blockheight)| self.utxo_delta(scripthash, oldutxos, blockheight + 1, limit),
+            || self.utxo_delta(scripthash, None, HashMap::new(), 0, limit),
+            |(oldutxos, blockheight)| {
+                self.utxo_delta(scripthash, None, oldutxos, blockheight + 1, limit)
+            },
         )?;
 
         // save updated utxo set to cache
@@ -546,22 +931,48 @@ impl ChainQuery {
             }
         }
 
-        // format as Utxo objects
-        Ok(newutxos
-            .into_iter()
-            .map(|(outpoint, (blockid, color_id, value))| Utxo {
-                txid: outpoint.txid,
-                vout: outpoint.vout,
-                color_id,
-                value,
-                confirmed: Some(blockid),
+        Ok(to_utxos(newutxos))
+    }
+
+    // same as utxo(), but only for the outputs of color_id. the limit applies to the outputs of
+    // that color alone. the cache holds all colors, so it is read but never written here.
+    pub fn utxo_by_color(
+        &self,
+        scripthash: &[u8],
+        color_id: &ColorIdentifier,
+        limit: usize,
+    ) -> Result<Vec<Utxo>> {
+        let _timer = self.start_timer("utxo_by_color");
+
+        let (newutxos, _, _) = match self.utxo_cache(scripthash) {
+            None => self.utxo_delta(scripthash, Some(color_id), HashMap::new(), 0, limit)?,
+            Some((mut oldutxos, blockheight)) => {
+                oldutxos.retain(|_, (_, utxo_color_id, _)| utxo_color_id == color_id);
+                self.utxo_delta(scripthash, Some(color_id), oldutxos, blockheight + 1, limit)?
+            }
+        };
+
+        Ok(to_utxos(newutxos))
+    }
+
+    // get the last known utxo set and the block height it was updated for.
+    // invalidates the cache if the block was orphaned.
+    fn utxo_cache(&self, scripthash: &[u8]) -> Option<(UtxoMap, usize)> {
+        self.store
+            .cache_db
+            .get(&UtxoCacheRow::key(scripthash))
+            .map(|c| bincode::deserialize(&c).unwrap())
+            .and_then(|(utxos_cache, blockhash)| {
+                self.height_by_hash(&blockhash)
+                    .map(|height| (utxos_cache, height))
             })
-            .collect())
+            .map(|(utxos_cache, height)| (from_utxo_cache(utxos_cache, self), height))
     }
 
     fn utxo_delta(
         &self,
         scripthash: &[u8],
+        color_id: Option<&ColorIdentifier>,
         init_utxos: UtxoMap,
         start_height: usize,
         limit: usize,
@@ -570,6 +981,9 @@ impl ChainQuery {
         let history_iter = self
             .history_iter_scan(b'H', scripthash, start_height)
             .map(TxHistoryRow::from_row)
+            .filter(|history| {
+                color_id.map_or(true, |color_id| history.key.txinfo.color_id() == *color_id)
+            })
             .filter_map(|history| {
                 self.tx_confirming_block(&history.get_txid())
                     .map(|b| (history, b))
@@ -675,7 +1089,7 @@ impl ChainQuery {
         update_stats(init_stats, &histories)
     }
 
-    pub fn get_colors(&self, block_height: u32, last_seen_color_id: &Option<ColorIdentifier>, limit: usize) -> Result<Vec<(ColorIdentifier, u32)>> {
+    pub fn get_colors(&self, block_height: u32, last_seen_color_id: &Option<ColorIdentifier>, token_type: Option<TokenType>, limit: usize) -> Result<Vec<(ColorIdentifier, u32)>> {
         let colors = self.store.history_db().iter_scan_reverse(
                 &ColorIdRow::prefix(),
                 &ColorIdRow::filter_end(block_height, last_seen_color_id)
@@ -690,11 +1104,24 @@ impl ChainQuery {
             })
             // Skip the first element only when paginating (last_seen_color_id is specified)
             .skip(if last_seen_color_id.is_some() { 1 } else { 0 })
+            .filter(|(color_id, _)| token_type.map_or(true, |t| TokenType::of(color_id) == Some(t)))
             .take(limit)
             .collect::<Vec<_>>();
         Ok(colors)
     }
 
+    // the first confirmed issuance of color_id
+    pub fn get_color_first_issuance(&self, color_id: &ColorIdentifier) -> Option<(Txid, BlockId)> {
+        self.colored_history_iter_scan(color_id, 0)
+            .map(ColoredTxHistoryRow::from_row)
+            .filter(|history| matches!(history.key.txinfo, ColoredTxHistoryInfo::Issuing(_)))
+            .filter_map(|history| {
+                let txid = history.get_txid();
+                self.tx_confirming_block(&txid).map(|b| (txid, b))
+            })
+            .next()
+    }
+
     pub fn get_height_by_color_id(&self, color_id: &ColorIdentifier) -> Option<u32> {
         self.colored_history_iter_scan_reverse(color_id).map(|c| ColoredTxHistoryRow::from_row(c).key.confirmed_height).next()
     }
@@ -743,6 +1170,186 @@ impl ChainQuery {
         update_colored_stats(init_cache, &histories)
     }
 
+    // the confirmed unspent output carrying the NFT color_id, if it wasn't burned
+    pub fn get_nft_owner(&self, color_id: &ColorIdentifier) -> Option<NftOutput> {
+        let _timer = self.start_timer("get_nft_owner");
+        let mut rows = self
+            .store
+            .history_db
+            .iter_scan_reverse(
+                &NftOutputRow::filter(color_id),
+                &NftOutputRow::prefix_end(color_id),
+            )
+            .map(NftOutputRow::from_row);
+
+        // the NFT may have moved several times within its last block, so look through all the
+        // outputs created there for the one that is still unspent
+        let last = rows.next()?;
+        let height = last.confirmed_height;
+        std::iter::once(last)
+            .chain(rows.take_while(|row| row.confirmed_height == height))
+            .filter_map(|row| self.nft_output(row))
+            .find(|output| self.lookup_spend(&OutPoint::from(output)).is_none())
+    }
+
+    // the confirmed unspent NFT outputs paying to scripthash
+    pub fn nfts(&self, scripthash: &[u8]) -> Vec<NftOutput> {
+        let _timer = self.start_timer("nfts");
+        self.store
+            .history_db
+            .iter_scan(&NftOutputRow::scripthash_filter(scripthash))
+            .map(NftOutputRow::from_scripthash_row)
+            .filter_map(|row| self.nft_output(row))
+            .filter(|output| self.lookup_spend(&OutPoint::from(output)).is_none())
+            .collect()
+    }
+
+    fn nft_output(&self, row: NftOutputRow) -> Option<NftOutput> {
+        let txid = row.get_txid();
+        let blockid = self.tx_confirming_block(&txid)?;
+        Some(NftOutput {
+            color_id: row.color_id,
+            txid,
+            vout: row.vout as u32,
+            script: Script::from(row.script),
+            confirmed: Some(blockid),
+        })
+    }
+
+    // the confirmed balance of every holder of color_id
+    pub fn get_colored_holders(&self, color_id: &ColorIdentifier) -> HolderMap {
+        let _timer = self.start_timer("colored_holders");
+
+        let cache: Option<(HolderMap, usize)> = self
+            .store
+            .cache_db
+            .get(&ColoredHoldersCacheRow::key(color_id))
+            .map(|c| bincode::deserialize(&c).unwrap())
+            .and_then(|(holders_cache, blockhash)| {
+                self.height_by_hash(&blockhash)
+                    .map(|height| (holders_cache, height))
+            });
+
+        let (newcache, lastblock) = cache.map_or_else(
+            || self.colored_holders_delta(color_id, HolderMap::new(), 0),
+            |(oldcache, blockheight)| {
+                self.colored_holders_delta(color_id, oldcache, blockheight + 1)
+            },
+        );
+
+        // save updated holders to cache
+        if let Some(lastblock) = lastblock {
+            self.store.cache_db.write(
+                vec![ColoredHoldersCacheRow::new(color_id, &newcache, &lastblock).into_row()],
+                DBFlush::Enable,
+            );
+        }
+
+        newcache
+    }
+
+    fn colored_holders_delta(
+        &self,
+        color_id: &ColorIdentifier,
+        init_holders: HolderMap,
+        start_height: usize,
+    ) -> (HolderMap, Option<BlockHash>) {
+        let mut lastblock = None;
+        let infos: Vec<_> = self
+            .colored_holders_iter_scan(color_id, start_height)
+            .map(ColoredHolderRow::from_row)
+            .filter_map(|row| {
+                let blockid = self.tx_confirming_block(&row.info.get_txid())?;
+                lastblock = Some(blockid.hash);
+                Some(row.info)
+            })
+            .collect();
+        (update_holders(init_holders, infos.iter()), lastblock)
+    }
+
+    // the supply of color_id as of the block at the given height. a single checkpoint is kept
+    // per color, and moved forward as later heights are requested.
+    pub fn get_colored_supply(
+        &self,
+        color_id: &ColorIdentifier,
+        height: usize,
+    ) -> Result<ColoredSupply> {
+        let _timer = self.start_timer("colored_supply");
+        let blockhash = self
+            .hash_by_height(height)
+            .chain_err(|| format!("no block at height {}", height))?;
+
+        // invalidates the checkpoint if its block was orphaned
+        let checkpoint: Option<(ColoredSupply, usize)> = self
+            .store
+            .cache_db
+            .get(&ColoredSupplyCacheRow::key(color_id))
+            .map(|c| bincode::deserialize(&c).unwrap())
+            .and_then(|(supply, blockhash)| {
+                self.height_by_hash(&blockhash).map(|height| (supply, height))
+            });
+
+        let (supply, save_checkpoint) = match checkpoint {
+            Some((supply, checkpoint_height)) if checkpoint_height == height => (supply, false),
+            Some((supply, checkpoint_height)) if checkpoint_height < height => (
+                self.colored_supply_delta(color_id, supply, checkpoint_height + 1, height),
+                true,
+            ),
+            // keep the later checkpoint, it is more useful for the next requests
+            Some(_) => (
+                self.colored_supply_delta(color_id, ColoredSupply::default(), 0, height),
+                false,
+            ),
+            None => (
+                self.colored_supply_delta(color_id, ColoredSupply::default(), 0, height),
+                true,
+            ),
+        };
+
+        if save_checkpoint {
+            self.store.cache_db.write(
+                vec![ColoredSupplyCacheRow::new(color_id, &supply, &blockhash).into_row()],
+                DBFlush::Enable,
+            );
+        }
+
+        Ok(supply)
+    }
+
+    fn colored_supply_delta(
+        &self,
+        color_id: &ColorIdentifier,
+        init_supply: ColoredSupply,
+        start_height: usize,
+        end_height: usize,
+    ) -> ColoredSupply {
+        let mut supply = init_supply;
+
+        let histories = self
+            .colored_history_iter_scan(color_id, start_height)
+            .map(ColoredTxHistoryRow::from_row)
+            .take_while(|history| history.key.confirmed_height as usize <= end_height)
+            .filter(|history| self.tx_confirming_block(&history.get_txid()).is_some());
+        for history in histories {
+            match history.key.txinfo {
+                ColoredTxHistoryInfo::Issuing(info) => supply.issued_sum += info.value,
+                ColoredTxHistoryInfo::Burning(info) => supply.burned_sum += info.value,
+                ColoredTxHistoryInfo::Transferring(_) => (),
+            }
+        }
+
+        let infos: Vec<HolderInfo> = self
+            .colored_holders_iter_scan(color_id, start_height)
+            .map(ColoredHolderRow::from_row)
+            .take_while(|row| row.confirmed_height as usize <= end_height)
+            .filter(|row| self.tx_confirming_block(&row.info.get_txid()).is_some())
+            .map(|row| row.info)
+            .collect();
+        supply.holders = update_holders(supply.holders, infos.iter());
+
+        supply
+    }
+
     pub fn get_colored_txs(
         &self,
         color_id: &ColorIdentifier,
@@ -839,6 +1446,67 @@ impl ChainQuery {
             .map(BlockId::from)
     }
 
+    // the xfields set by the blocks of the best chain, ordered by height
+    pub fn xfield_changes(&self) -> Vec<XFieldChange> {
+        let _timer = self.start_timer("xfield_changes");
+        let headers = self.store.indexed_headers.read().unwrap();
+        self.store
+            .txstore_db
+            .iter_scan(&XFieldRow::filter())
+            .map(XFieldRow::from_row)
+            .filter(|row| is_best_chain(&headers, row.height(), &row.blockhash()))
+            .map(XFieldRow::into_change)
+            .collect()
+    }
+
+    // the last aggregate public key set by a block of the best chain at or below height
+    pub fn aggregate_pubkey_change(&self, height: usize) -> Option<XFieldChange> {
+        let _timer = self.start_timer("aggregate_pubkey_change");
+        let headers = self.store.indexed_headers.read().unwrap();
+        lookup_xfield_change(&self.store.txstore_db, &headers, height, |change| {
+            change.aggregate_pubkey().is_some()
+        })
+    }
+
+    // the aggregate public key change that the block at height was signed with
+    pub fn aggregate_pubkey_at(&self, height: usize) -> Option<XFieldChange> {
+        // blocks are signed with the key set before them, except for the genesis block
+        self.aggregate_pubkey_change(height.saturating_sub(1))
+    }
+
+    // the block size limit in force for the block at height
+    pub fn max_block_size_at(&self, height: usize) -> u32 {
+        let _timer = self.start_timer("max_block_size_at");
+        if height == 0 {
+            return DEFAULT_MAX_BLOCK_SIZE;
+        }
+        let headers = self.store.indexed_headers.read().unwrap();
+        lookup_xfield_change(&self.store.txstore_db, &headers, height - 1, |change| {
+            change.max_block_size().is_some()
+        })
+        .and_then(|change| change.max_block_size())
+        .unwrap_or(DEFAULT_MAX_BLOCK_SIZE)
+    }
+
+    // the block size limits in force for the blocks from start_height to end_height (inclusive)
+    pub fn max_block_sizes(&self, start_height: usize, end_height: usize) -> Vec<u32> {
+        let mut changes = self
+            .xfield_changes()
+            .into_iter()
+            .filter(|change| change.max_block_size().is_some())
+            .filter(|change| change.activation_height() > start_height)
+            .peekable();
+        let mut max_block_size = self.max_block_size_at(start_height);
+        (start_height..=end_height)
+            .map(|height| {
+                while let Some(change) = changes.next_if(|c| c.activation_height() <= height) {
+                    max_block_size = change.max_block_size().unwrap();
+                }
+                max_block_size
+            })
+            .collect()
+    }
+
     pub fn best_height(&self) -> usize {
         self.store.indexed_headers.read().unwrap().len() - 1
     }
@@ -887,7 +1555,7 @@ impl ChainQuery {
             // TODO fetch transaction as binary from REST API instead of as hex
             let txhex = self
                 .daemon
-                .gettransaction_raw(txid, blockhash, false)
+                .gettransaction_raw(txid, Some(blockhash), false)
                 .ok()?;
             Some(hex::decode(txhex.as_str().unwrap()).unwrap())
         } else {
@@ -900,6 +1568,11 @@ impl ChainQuery {
         lookup_txo(&self.store.txstore_db, outpoint)
     }
 
+    pub fn open_asset(&self, outpoint: &OutPoint) -> Option<OpenAsset> {
+        let _timer = self.start_timer("open_asset");
+        lookup_open_asset(&self.store.history_db, outpoint, self.network.into())
+    }
+
     pub fn lookup_txos(&self, outpoints: &BTreeSet<OutPoint>) -> HashMap<OutPoint, TxOut> {
         let _timer = self.start_timer("lookup_txos");
         lookup_txos(&self.store.txstore_db, outpoints, false)
@@ -1003,6 +1676,8 @@ fn add_blocks(block_entries: &[BlockEntry], iconfig: &IndexerConfig) -> Vec<DBRo
     //      B{blockhash} → {header}
     //      X{blockhash} → {txid1}...{txidN}
     //      M{blockhash} → {tx_count}{size}{weight}
+    // persist xfield changes:
+    //      x{height}{blockhash} → {xfield}
     block_entries
         .par_iter() // serialization is CPU-intensive
         .map(|b| {
@@ -1019,6 +1694,9 @@ fn add_blocks(block_entries: &[BlockEntry], iconfig: &IndexerConfig) -> Vec<DBRo
             }
 
             rows.push(BlockRow::new_header(&b).into_row());
+            if let Some(row) = XFieldRow::new(b) {
+                rows.push(row.into_row());
+            }
             rows.push(BlockRow::new_done(blockhash).into_row()); // mark block as "added"
             rows
         })
@@ -1086,6 +1764,30 @@ fn lookup_txos(
     })
 }
 
+// the last xfield matching filter set by a block of the (indexed) best chain at or below height
+fn lookup_xfield_change(
+    txstore_db: &DB,
+    headers: &HeaderList,
+    height: usize,
+    filter: impl Fn(&XFieldChange) -> bool,
+) -> Option<XFieldChange> {
+    txstore_db
+        .iter_scan_reverse(
+            &XFieldRow::filter(),
+            &XFieldRow::prefix_height_end(height as u32),
+        )
+        .map(XFieldRow::from_row)
+        .filter(|row| is_best_chain(headers, row.height(), &row.blockhash()))
+        .map(XFieldRow::into_change)
+        .find(filter)
+}
+
+fn is_best_chain(headers: &HeaderList, height: usize, blockhash: &BlockHash) -> bool {
+    headers
+        .header_by_height(height)
+        .map_or(false, |header| header.hash() == blockhash)
+}
+
 fn lookup_txo(txstore_db: &DB, outpoint: &OutPoint) -> Option<TxOut> {
     txstore_db
         .get(&TxOutRow::key(&outpoint))
@@ -1095,6 +1797,7 @@ fn lookup_txo(txstore_db: &DB, outpoint: &OutPoint) -> Option<TxOut> {
 fn index_blocks(
     block_entries: &[BlockEntry],
     previous_txos_map: &HashMap<OutPoint, TxOut>,
+    open_assets: &AssetMap,
     iconfig: &IndexerConfig,
 ) -> Vec<DBRow> {
     block_entries
@@ -1104,6 +1807,15 @@ fn index_blocks(
             for tx in &b.block.txdata {
                 let height = b.entry.height() as u32;
                 index_transaction(tx, height, previous_txos_map, &mut rows, iconfig);
+                if iconfig.open_assets {
+                    index_confirmed_open_assets_tx(
+                        tx,
+                        height,
+                        previous_txos_map,
+                        open_assets,
+                        &mut rows,
+                    );
+                }
             }
             rows.push(BlockRow::new_done(full_hash(&b.entry.hash()[..])).into_row()); // mark block as "indexed"
             rows
@@ -1567,8 +2279,8 @@ impl TxHistoryInfo {
 
 #[derive(Serialize, Deserialize)]
 pub struct TxHistoryKey {
-    pub code: u8,              // H for script history or I for asset history (elements only)
-    pub hash: FullHash, // either a scripthash (always on tapyrus) or an asset id (elements only)
+    pub code: u8,              // H for script history or I for open assets history
+    pub hash: FullHash, // either a scripthash or an (open assets) asset id
     pub confirmed_height: u32, // MUST be serialized as big-endian (for correct scans).
     pub txinfo: TxHistoryInfo,
 }
@@ -1800,6 +2512,20 @@ fn from_utxo_cache(utxos_cache: CachedUtxoMap, chain: &ChainQuery) -> UtxoMap {
         .collect()
 }
 
+// format as Utxo objects
+fn to_utxos(utxos: UtxoMap) -> Vec<Utxo> {
+    utxos
+        .into_iter()
+        .map(|(outpoint, (blockid, color_id, value))| Utxo {
+            txid: outpoint.txid,
+            vout: outpoint.vout,
+            color_id,
+            value,
+            confirmed: Some(blockid),
+        })
+        .collect()
+}
+
 pub fn update_stats(
     init_stats: StatsMap,
     histories: &Vec<(TxHistoryInfo, Option<BlockId>)>,
@@ -2213,6 +2939,7 @@ mod tests {
             light_mode: false,
             address_search: false,
             index_unspendables: false,
+            open_assets: false,
             network: Network::new("dev", 1),
         };
 
@@ -2282,6 +3009,751 @@ mod tests {
         );
     }
 
+    fn connect(indexer: &mut Indexer, blocks: &[BlockEntry]) {
+        indexer.add(blocks);
+        indexer.index(blocks);
+        let entries = blocks.iter().map(|b| b.entry.clone()).collect();
+        let removed = indexer.store.indexed_headers.write().unwrap().apply(entries);
+        assert!(removed.is_empty());
+    }
+
+    /// Test that a reorg removes every row written by the orphaned blocks, so that
+    /// colored stats, colors and utxos only reflect the new best chain.
+    #[test]
+    fn test_reorg_rolls_back_orphaned_blocks() {
+        use crate::new_index::test_helper;
+
+        let index = test_helper::index(|_| {});
+        let (store, mut indexer, chain) = (index.store, index.indexer, index.chain);
+
+        let script = hex_script("76a914000000000000000000000000000000000000000088ac");
+        let color_id = ColorIdentifier::reissuable(script.clone());
+        let colored_script = script.add_color(color_id.clone()).unwrap();
+        let scripthash = compute_script_hash(&script);
+
+        // the genesis block funds `script`, which issues a colored coin at height 1
+        let genesis_coinbase = test_helper::coinbase(
+            0,
+            vec![TxOut {
+                value: 1000,
+                script_pubkey: script.clone(),
+            }],
+        );
+        let issue = test_helper::tx(
+            &[OutPoint::new(genesis_coinbase.malfix_txid(), 0)],
+            vec![
+                TxOut {
+                    value: 500,
+                    script_pubkey: colored_script.clone(),
+                },
+                TxOut {
+                    value: 900,
+                    script_pubkey: script.clone(),
+                },
+            ],
+        );
+        // shared by both branches
+        let coinbase1 = test_helper::coinbase(1, vec![]);
+
+        let chain_a = test_helper::blocks(
+            &store.indexed_headers.read().unwrap(),
+            BlockHash::default(),
+            vec![vec![genesis_coinbase.clone()], vec![coinbase1.clone(), issue.clone()]],
+            0,
+        );
+        connect(&mut indexer, &chain_a);
+        // nothing is tracked during the initial sync
+        assert!(indexer.take_touched_scripthashes().is_empty());
+
+        let stats = chain.get_colored_stats(&color_id).unwrap();
+        assert_eq!(stats.tx_count, 1);
+        assert_eq!(stats.issued_sum, 500);
+        assert_eq!(chain.utxo(&scripthash, 100).unwrap().len(), 2);
+        let holders = chain.get_colored_holders(&color_id);
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[&colored_script.to_bytes()], 500);
+        assert_eq!(
+            chain.get_colors(chain.best_height() as u32, &None, None, 10).unwrap().len(),
+            1
+        );
+
+        // a longer branch from genesis replaces block 1, without the issuance
+        let chain_b = test_helper::blocks(
+            &store.indexed_headers.read().unwrap(),
+            *chain_a[0].entry.hash(),
+            vec![vec![coinbase1.clone()], vec![test_helper::coinbase(2, vec![])]],
+            100,
+        );
+        let new_headers: Vec<HeaderEntry> = chain_b.iter().map(|b| b.entry.clone()).collect();
+        let orphaned = indexer.orphaned_headers(&new_headers);
+        assert_eq!(orphaned.len(), 1);
+        assert_eq!(orphaned[0].hash(), chain_a[1].entry.hash());
+        indexer.flush = DBFlush::Enable;
+        indexer.rollback(&chain_a[1..], &new_headers);
+        connect(&mut indexer, &chain_b);
+        assert_eq!(chain.best_height(), 2);
+
+        // the orphaned issuance touched both the colored and the uncolored scripthash
+        let touched = indexer.take_touched_scripthashes();
+        assert!(touched.contains(&scripthash));
+        assert!(touched.contains(&compute_script_hash(&colored_script)));
+        assert!(indexer.take_touched_scripthashes().is_empty());
+
+        let stats = chain.get_colored_stats(&color_id).unwrap();
+        assert_eq!(stats.tx_count, 0);
+        assert_eq!(stats.issued_tx_count, 0);
+        assert_eq!(stats.issued_sum, 0);
+        assert!(chain.get_colored_holders(&color_id).is_empty());
+
+        let utxos = chain.utxo(&scripthash, 100).unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].txid, genesis_coinbase.malfix_txid());
+        assert_eq!(utxos[0].value, 1000);
+        assert!(utxos[0].color_id.is_default());
+
+        assert!(chain
+            .get_colors(chain.best_height() as u32, &None, None, 10)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store
+                .history_db
+                .iter_scan(&ColoredTxHistoryRow::filter(&color_id))
+                .count(),
+            0
+        );
+        assert!(chain.lookup_txn(&issue.malfix_txid(), None).is_none());
+        assert_eq!(
+            chain.tx_confirming_block(&coinbase1.malfix_txid()).unwrap().hash,
+            *chain_b[0].entry.hash()
+        );
+    }
+
+    /// Test that `Indexer::update()` detects a reorg from the daemon's best chain, fetches the
+    /// orphaned blocks to roll them back and indexes the new best chain.
+    #[test]
+    fn test_update_across_reorg() {
+        use crate::new_index::test_helper;
+
+        let index = test_helper::index(|_| {});
+        let (daemon, tapyrusd) = (index.daemon, index.tapyrusd);
+        let (store, mut indexer, chain) = (index.store, index.indexer, index.chain);
+
+        let script = hex_script("76a914000000000000000000000000000000000000000088ac");
+        let color_id = ColorIdentifier::reissuable(script.clone());
+        let colored_script = script.add_color(color_id.clone()).unwrap();
+        let scripthash = compute_script_hash(&script);
+        let genesis_coinbase = test_helper::coinbase(
+            0,
+            vec![TxOut {
+                value: 1000,
+                script_pubkey: script.clone(),
+            }],
+        );
+        let issue = test_helper::tx(
+            &[OutPoint::new(genesis_coinbase.malfix_txid(), 0)],
+            vec![TxOut {
+                value: 500,
+                script_pubkey: colored_script,
+            }],
+        );
+
+        let chain_a = test_helper::blocks(
+            &store.indexed_headers.read().unwrap(),
+            BlockHash::default(),
+            vec![
+                vec![genesis_coinbase.clone()],
+                vec![test_helper::coinbase(1, vec![]), issue.clone()],
+            ],
+            0,
+        );
+        tapyrusd.lock().unwrap().connect(&chain_a);
+        assert_eq!(indexer.update(&daemon).unwrap(), *chain_a[1].entry.hash());
+        assert_eq!(chain.get_colored_stats(&color_id).unwrap().issued_sum, 500);
+        assert_eq!(chain.utxo(&scripthash, 100).unwrap().len(), 0);
+
+        // the daemon switches to a longer branch from genesis, without the issuance
+        let chain_b = test_helper::blocks(
+            &store.indexed_headers.read().unwrap(),
+            *chain_a[0].entry.hash(),
+            vec![
+                vec![test_helper::coinbase(1, vec![])],
+                vec![test_helper::coinbase(2, vec![])],
+            ],
+            100,
+        );
+        tapyrusd.lock().unwrap().connect(&chain_b);
+        let tip = *chain_b[1].entry.hash();
+        assert_eq!(indexer.update(&daemon).unwrap(), tip);
+        assert_eq!(chain.best_height(), 2);
+        assert_eq!(chain.best_hash(), tip);
+        assert_eq!(store.txstore_db.get(b"t").unwrap(), serialize(&tip));
+
+        assert_eq!(chain.get_colored_stats(&color_id).unwrap().issued_sum, 0);
+        assert!(chain.lookup_txn(&issue.malfix_txid(), None).is_none());
+        let utxos = chain.utxo(&scripthash, 100).unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].txid, genesis_coinbase.malfix_txid());
+        assert!(indexer
+            .take_touched_scripthashes()
+            .contains(&compute_script_hash(&script)));
+
+        // nothing is left to do for the next update
+        assert_eq!(indexer.update(&daemon).unwrap(), tip);
+    }
+
+    /// Test that `Indexer::update()` indexes the blocks signed with the aggregate public key in
+    /// force, and stops before a block with an invalid proof, which is counted once.
+    #[test]
+    fn test_update_verifies_block_proofs() {
+        use crate::new_index::test_helper;
+
+        let index = test_helper::index(|config| config.verify_block_proofs = true);
+        let (daemon, tapyrusd) = (index.daemon, index.tapyrusd);
+        let (store, mut indexer, chain) = (index.store, index.indexer, index.chain);
+        let (old_key, old_pubkey) = test_helper::federation_key(1);
+        let (new_key, new_pubkey) = test_helper::federation_key(2);
+        let coinbases = |heights: std::ops::Range<u32>| {
+            heights
+                .map(|height| vec![test_helper::coinbase(height, vec![])])
+                .collect::<Vec<_>>()
+        };
+
+        // the genesis block is signed with the key it sets, and block 1 sets a new key that
+        // applies from block 2
+        let chain_a = test_helper::blocks_with(
+            &store.indexed_headers.read().unwrap(),
+            BlockHash::default(),
+            coinbases(0..2),
+            0,
+            |i, mut header| {
+                let pubkey = if i == 0 { old_pubkey } else { new_pubkey };
+                header.xfield = XField::AggregatePublicKey(pubkey);
+                test_helper::sign(header, &old_key)
+            },
+        );
+        tapyrusd.lock().unwrap().connect(&chain_a);
+        assert_eq!(indexer.update(&daemon).unwrap(), *chain_a[1].entry.hash());
+        assert_eq!(chain.best_hash(), *chain_a[1].entry.hash());
+        assert_eq!(indexer.invalid_block_proofs.get(), 0.0);
+
+        // a block signed with the replaced key is refused, and the index is left unchanged
+        let old_signed = test_helper::blocks_with(
+            &store.indexed_headers.read().unwrap(),
+            *chain_a[1].entry.hash(),
+            coinbases(2..3),
+            10,
+            |_, header| test_helper::sign(header, &old_key),
+        );
+        let new_headers: Vec<HeaderEntry> = old_signed.iter().map(|b| b.entry.clone()).collect();
+        match indexer.verify_block_proofs(&new_headers) {
+            Err(Error(ErrorKind::InvalidBlockProof(blockhash, 2), _)) => {
+                assert_eq!(blockhash, old_signed[0].entry.hash().to_string())
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        tapyrusd.lock().unwrap().connect(&old_signed);
+        assert_eq!(
+            indexer.update(&daemon).unwrap(),
+            *old_signed[0].entry.hash()
+        );
+        assert_eq!(chain.best_hash(), *chain_a[1].entry.hash());
+        assert_eq!(
+            store.txstore_db.get(b"t").unwrap(),
+            serialize(chain_a[1].entry.hash())
+        );
+        assert_eq!(indexer.invalid_block_proofs.get(), 1.0);
+
+        // the daemon switches to blocks signed with the new key, the second one with a tampered
+        // proof: the block before it is indexed
+        let branch = test_helper::blocks_with(
+            &store.indexed_headers.read().unwrap(),
+            *chain_a[1].entry.hash(),
+            coinbases(2..5),
+            20,
+            |i, header| {
+                let mut header = test_helper::sign(header, &new_key);
+                if i == 1 {
+                    header.proof.as_mut().unwrap().r_x[0] ^= 1;
+                }
+                header
+            },
+        );
+        tapyrusd.lock().unwrap().connect(&branch[..2]);
+        assert_eq!(indexer.update(&daemon).unwrap(), *branch[1].entry.hash());
+        assert_eq!(chain.best_hash(), *branch[0].entry.hash());
+        assert_eq!(chain.best_height(), 2);
+        assert_eq!(indexer.invalid_block_proofs.get(), 2.0);
+
+        // the tampered block is still refused once the daemon builds on it, without being
+        // counted again
+        tapyrusd.lock().unwrap().connect(&branch[2..]);
+        assert_eq!(indexer.update(&daemon).unwrap(), *branch[2].entry.hash());
+        assert_eq!(chain.best_hash(), *branch[0].entry.hash());
+        assert_eq!(indexer.invalid_block_proofs.get(), 2.0);
+    }
+
+    /// Test that an aggregate public key set by a block signs the blocks from the next one, and
+    /// that the federation history follows the best chain across a reorg.
+    #[test]
+    fn test_aggregate_pubkey_at() {
+        use crate::new_index::test_helper;
+
+        let index = test_helper::index(|_| {});
+        let (daemon, tapyrusd) = (index.daemon, index.tapyrusd);
+        let (store, mut indexer, chain) = (index.store, index.indexer, index.chain);
+        let pubkeys: Vec<tapyrus::PublicKey> = (1..4)
+            .map(|seed| test_helper::federation_key(seed).1)
+            .collect();
+        let coinbases = |heights: std::ops::Range<u32>| {
+            heights
+                .map(|height| vec![test_helper::coinbase(height, vec![])])
+                .collect::<Vec<_>>()
+        };
+        let key_at = |height| {
+            let change = chain.aggregate_pubkey_at(height).unwrap();
+            (*change.aggregate_pubkey().unwrap(), change.height)
+        };
+
+        // the genesis block sets the first key, and block 2 the second one
+        let chain_a = test_helper::blocks_with(
+            &store.indexed_headers.read().unwrap(),
+            BlockHash::default(),
+            coinbases(0..4),
+            0,
+            |i, mut header| {
+                match i {
+                    0 => header.xfield = XField::AggregatePublicKey(pubkeys[0]),
+                    2 => header.xfield = XField::AggregatePublicKey(pubkeys[1]),
+                    _ => (),
+                }
+                header
+            },
+        );
+        tapyrusd.lock().unwrap().connect(&chain_a);
+        indexer.update(&daemon).unwrap();
+
+        assert_eq!(key_at(0), (pubkeys[0], 0));
+        assert_eq!(key_at(1), (pubkeys[0], 0));
+        // block 2 is still signed with the key in force before it
+        assert_eq!(key_at(2), (pubkeys[0], 0));
+        assert_eq!(key_at(3), (pubkeys[1], 2));
+        let heights: Vec<usize> = chain.xfield_changes().iter().map(|c| c.height).collect();
+        assert_eq!(heights, vec![0, 2]);
+
+        // the daemon switches to a longer branch from block 1, which sets the third key at
+        // height 3 instead
+        let chain_b = test_helper::blocks_with(
+            &store.indexed_headers.read().unwrap(),
+            *chain_a[1].entry.hash(),
+            coinbases(2..5),
+            100,
+            |i, mut header| {
+                if i == 1 {
+                    header.xfield = XField::AggregatePublicKey(pubkeys[2]);
+                }
+                header
+            },
+        );
+        tapyrusd.lock().unwrap().connect(&chain_b);
+        indexer.update(&daemon).unwrap();
+        assert_eq!(chain.best_height(), 4);
+
+        assert_eq!(key_at(2), (pubkeys[0], 0));
+        assert_eq!(key_at(3), (pubkeys[0], 0));
+        assert_eq!(key_at(4), (pubkeys[2], 3));
+        let changes = chain.xfield_changes();
+        let heights: Vec<usize> = changes.iter().map(|c| c.height).collect();
+        assert_eq!(heights, vec![0, 3]);
+        assert_eq!(changes[1].blockhash, *chain_b[1].entry.hash());
+        assert_eq!(
+            chain.aggregate_pubkey_change(2).unwrap().blockhash,
+            *chain_a[0].entry.hash()
+        );
+    }
+
+    /// Test that a max block size set by a block applies from the next block, and that blocks
+    /// before any MaxBlockSize xfield have the default limit.
+    #[test]
+    fn test_max_block_size_at() {
+        use crate::new_index::test_helper;
+
+        let mut index = test_helper::index(|_| {});
+        // block 1 sets a smaller limit, block 3 a larger one
+        let blocks = test_helper::blocks_with(
+            &index.store.indexed_headers.read().unwrap(),
+            BlockHash::default(),
+            (0..5)
+                .map(|height| vec![test_helper::coinbase(height, vec![])])
+                .collect(),
+            0,
+            |i, mut header| {
+                header.xfield = match i {
+                    1 => XField::MaxBlockSize(500_000),
+                    3 => XField::MaxBlockSize(2_000_000),
+                    _ => XField::None,
+                };
+                header
+            },
+        );
+        index.tapyrusd.lock().unwrap().connect(&blocks);
+        index.indexer.update(&index.daemon).unwrap();
+        let chain = index.chain;
+
+        let sizes: Vec<u32> = (0..5)
+            .map(|height| chain.max_block_size_at(height))
+            .collect();
+        let expected = vec![
+            DEFAULT_MAX_BLOCK_SIZE,
+            DEFAULT_MAX_BLOCK_SIZE,
+            500_000,
+            500_000,
+            2_000_000,
+        ];
+        assert_eq!(sizes, expected);
+        assert_eq!(chain.max_block_sizes(0, 4), expected);
+        assert_eq!(chain.max_block_sizes(3, 4), expected[3..]);
+        assert_eq!(chain.max_block_sizes(2, 2), vec![500_000]);
+    }
+
+    /// Test that open assets are colored and indexed when their issuance and transfer are
+    /// confirmed in the same batch of blocks.
+    #[test]
+    fn test_index_open_assets() {
+        use crate::new_index::test_helper;
+        use openassets_tapyrus::openassets::marker_output::Metadata;
+
+        let index = test_helper::index(|config| config.enable_open_assets = true);
+        let (store, mut indexer, chain) = (index.store, index.indexer, index.chain);
+
+        let issuer = hex_script("76a914000000000000000000000000000000000000000088ac");
+        let holder = hex_script("76a914111111111111111111111111111111111111111188ac");
+        // OP_RETURN with a marker for a single quantity of 100 and no metadata
+        let marker = TxOut {
+            value: 0,
+            script_pubkey: hex_script("6a074f410100016400"),
+        };
+
+        let coinbase = test_helper::coinbase(
+            0,
+            vec![TxOut {
+                value: 1000,
+                script_pubkey: issuer.clone(),
+            }],
+        );
+        // issues 100 units to output #0 (before the marker)
+        let issue = test_helper::tx(
+            &[OutPoint::new(coinbase.malfix_txid(), 0)],
+            vec![
+                TxOut {
+                    value: 600,
+                    script_pubkey: holder.clone(),
+                },
+                marker.clone(),
+            ],
+        );
+        // transfers the 100 units to output #1 (after the marker)
+        let transfer = test_helper::tx(
+            &[OutPoint::new(issue.malfix_txid(), 0)],
+            vec![
+                marker.clone(),
+                TxOut {
+                    value: 500,
+                    script_pubkey: holder.clone(),
+                },
+            ],
+        );
+        let blocks = test_helper::blocks(
+            &store.indexed_headers.read().unwrap(),
+            BlockHash::default(),
+            vec![
+                vec![coinbase.clone()],
+                vec![test_helper::coinbase(1, vec![]), issue.clone(), transfer.clone()],
+            ],
+            0,
+        );
+        connect(&mut indexer, &blocks);
+
+        let expected = OpenAsset {
+            asset_id: AssetId::new(&issuer, tapyrus::network::constants::Network::Dev),
+            asset_quantity: 100,
+            metadata: Metadata::new(vec![]),
+        };
+        let issued = OutPoint::new(issue.malfix_txid(), 0);
+        let transfer_txid = transfer.malfix_txid();
+        let transferred = OutPoint::new(transfer_txid, 1);
+        assert_eq!(chain.open_asset(&issued), Some(expected.clone()));
+        assert_eq!(chain.open_asset(&transferred), Some(expected.clone()));
+        assert_eq!(chain.open_asset(&OutPoint::new(issue.malfix_txid(), 1)), None);
+        assert_eq!(chain.open_asset(&OutPoint::new(coinbase.malfix_txid(), 0)), None);
+
+        let mut txids: Vec<Txid> = chain
+            .asset_history(&expected.asset_id, None, 10)
+            .into_iter()
+            .map(|(tx, _)| tx.malfix_txid())
+            .collect();
+        txids.sort();
+        let mut expected_txids = vec![issue.malfix_txid(), transfer_txid];
+        expected_txids.sort();
+        assert_eq!(txids, expected_txids);
+
+        assert_eq!(
+            chain.asset_stats(&expected.asset_id),
+            OpenAssetStats {
+                issuances: vec![(issue.malfix_txid(), 1, 100)],
+                issued_sum: 100,
+                tx_count: 2,
+                utxo_count: 1,
+                unspent_sum: 100,
+                holder_count: 1,
+            }
+        );
+        let unknown = AssetId::new(&holder, tapyrus::network::constants::Network::Dev);
+        assert_eq!(chain.asset_stats(&unknown), OpenAssetStats::default());
+    }
+
+    /// Test that history and utxos can be scoped to a single color, with the utxo limit applying
+    /// to the outputs of that color only.
+    #[test]
+    fn test_history_and_utxo_by_color() {
+        use crate::new_index::test_helper;
+
+        let index = test_helper::index(|_| {});
+        let (store, mut indexer, chain) = (index.store, index.indexer, index.chain);
+
+        let script = hex_script("76a914000000000000000000000000000000000000000088ac");
+        let color_id = ColorIdentifier::reissuable(script.clone());
+        let colored_script = script.add_color(color_id.clone()).unwrap();
+        let scripthash = compute_script_hash(&script);
+
+        let coinbase = test_helper::coinbase(
+            0,
+            vec![TxOut {
+                value: 1000,
+                script_pubkey: script.clone(),
+            }],
+        );
+        let issue = test_helper::tx(
+            &[OutPoint::new(coinbase.malfix_txid(), 0)],
+            vec![
+                TxOut {
+                    value: 500,
+                    script_pubkey: colored_script.clone(),
+                },
+                TxOut {
+                    value: 900,
+                    script_pubkey: script.clone(),
+                },
+            ],
+        );
+        let blocks = test_helper::blocks(
+            &store.indexed_headers.read().unwrap(),
+            BlockHash::default(),
+            vec![
+                vec![coinbase.clone()],
+                vec![test_helper::coinbase(1, vec![]), issue.clone()],
+            ],
+            0,
+        );
+        connect(&mut indexer, &blocks);
+
+        let txids = |color_id: &ColorIdentifier| -> Vec<Txid> {
+            chain
+                .history_txids_by_color(&scripthash, color_id, 10)
+                .into_iter()
+                .map(|(txid, _)| txid)
+                .collect()
+        };
+        assert_eq!(txids(&color_id), vec![issue.malfix_txid()]);
+        assert_eq!(
+            txids(&ColorIdentifier::default()),
+            vec![coinbase.malfix_txid(), issue.malfix_txid()]
+        );
+
+        let utxos = chain.utxo_by_color(&scripthash, &color_id, 1).unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].value, 500);
+        assert_eq!(utxos[0].color_id, color_id);
+
+        let utxos = chain
+            .utxo_by_color(&scripthash, &ColorIdentifier::default(), 1)
+            .unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].value, 900);
+
+        // both colors together exceed the limit
+        assert!(chain.utxo(&scripthash, 1).is_err());
+    }
+
+    /// Test that the supply of a color can be computed at past heights, in any order, from the
+    /// supply checkpoint.
+    #[test]
+    fn test_colored_supply_at_height() {
+        use crate::new_index::test_helper;
+
+        let index = test_helper::index(|_| {});
+        let (store, mut indexer, chain) = (index.store, index.indexer, index.chain);
+
+        let script = hex_script("76a914000000000000000000000000000000000000000088ac");
+        let other = hex_script("76a914111111111111111111111111111111111111111188ac");
+        let color_id = ColorIdentifier::reissuable(script.clone());
+        let colored_script = script.add_color(color_id.clone()).unwrap();
+        let other_colored_script = other.add_color(color_id.clone()).unwrap();
+
+        let coinbase = test_helper::coinbase(
+            0,
+            vec![TxOut {
+                value: 1000,
+                script_pubkey: script.clone(),
+            }],
+        );
+        // issues 500 at height 1
+        let issue = test_helper::tx(
+            &[OutPoint::new(coinbase.malfix_txid(), 0)],
+            vec![TxOut {
+                value: 500,
+                script_pubkey: colored_script.clone(),
+            }],
+        );
+        // burns 200 and sends 100 to another holder at height 2
+        let burn = test_helper::tx(
+            &[OutPoint::new(issue.malfix_txid(), 0)],
+            vec![
+                TxOut {
+                    value: 200,
+                    script_pubkey: colored_script.clone(),
+                },
+                TxOut {
+                    value: 100,
+                    script_pubkey: other_colored_script.clone(),
+                },
+            ],
+        );
+        let blocks = test_helper::blocks(
+            &store.indexed_headers.read().unwrap(),
+            BlockHash::default(),
+            vec![
+                vec![coinbase.clone()],
+                vec![test_helper::coinbase(1, vec![]), issue.clone()],
+                vec![test_helper::coinbase(2, vec![]), burn.clone()],
+                vec![test_helper::coinbase(3, vec![])],
+            ],
+            0,
+        );
+        connect(&mut indexer, &blocks);
+
+        let supply_at = |height| {
+            let supply = chain.get_colored_supply(&color_id, height).unwrap();
+            (
+                supply.issued_sum,
+                supply.burned_sum,
+                supply.circulating_sum(),
+                supply.holders.len(),
+            )
+        };
+        assert_eq!(supply_at(3), (500, 200, 300, 2));
+        assert_eq!(supply_at(0), (0, 0, 0, 0));
+        assert_eq!(supply_at(1), (500, 0, 500, 1));
+        assert_eq!(supply_at(2), (500, 200, 300, 2));
+        assert_eq!(supply_at(3), (500, 200, 300, 2));
+        assert!(chain.get_colored_supply(&color_id, 4).is_err());
+    }
+
+    #[test]
+    fn test_nft_owner() {
+        use crate::new_index::test_helper;
+
+        let index = test_helper::index(|_| {});
+        let (store, mut indexer, chain) = (index.store, index.indexer, index.chain);
+
+        let script = hex_script("76a914000000000000000000000000000000000000000088ac");
+        let other = hex_script("76a914111111111111111111111111111111111111111188ac");
+        let coinbase = test_helper::coinbase(
+            0,
+            vec![TxOut {
+                value: 1000,
+                script_pubkey: script.clone(),
+            }],
+        );
+        let color_id = ColorIdentifier::nft(OutPoint::new(coinbase.malfix_txid(), 0));
+        let colored_script = script.add_color(color_id.clone()).unwrap();
+        let other_colored_script = other.add_color(color_id.clone()).unwrap();
+
+        // issued at height 1
+        let issue = test_helper::tx(
+            &[OutPoint::new(coinbase.malfix_txid(), 0)],
+            vec![TxOut {
+                value: 1,
+                script_pubkey: colored_script.clone(),
+            }],
+        );
+        // sent to another holder and back within the block at height 2
+        let send = test_helper::tx(
+            &[OutPoint::new(issue.malfix_txid(), 0)],
+            vec![TxOut {
+                value: 1,
+                script_pubkey: other_colored_script.clone(),
+            }],
+        );
+        let send_back = test_helper::tx(
+            &[OutPoint::new(send.malfix_txid(), 0)],
+            vec![TxOut {
+                value: 1,
+                script_pubkey: colored_script.clone(),
+            }],
+        );
+        // burned at height 3
+        let burn = test_helper::tx(
+            &[OutPoint::new(send_back.malfix_txid(), 0)],
+            vec![TxOut {
+                value: 1,
+                script_pubkey: script.clone(),
+            }],
+        );
+        let blocks = test_helper::blocks(
+            &store.indexed_headers.read().unwrap(),
+            BlockHash::default(),
+            vec![
+                vec![coinbase.clone()],
+                vec![test_helper::coinbase(1, vec![]), issue.clone()],
+                vec![
+                    test_helper::coinbase(2, vec![]),
+                    send.clone(),
+                    send_back.clone(),
+                ],
+            ],
+            0,
+        );
+        connect(&mut indexer, &blocks);
+
+        let owner = chain.get_nft_owner(&color_id).unwrap();
+        assert_eq!(owner.txid, send_back.malfix_txid());
+        assert_eq!(owner.vout, 0);
+        assert_eq!(owner.script, colored_script);
+        assert_eq!(owner.confirmed.unwrap().height, 2);
+
+        let nfts = chain.nfts(&compute_script_hash(&script));
+        assert_eq!(nfts.len(), 1);
+        assert_eq!(nfts[0].color_id, color_id);
+        assert_eq!(nfts[0].txid, send_back.malfix_txid());
+        assert_eq!(chain.nfts(&compute_script_hash(&colored_script)).len(), 1);
+        assert!(chain.nfts(&compute_script_hash(&other)).is_empty());
+        assert!(chain
+            .nfts(&compute_script_hash(&other_colored_script))
+            .is_empty());
+
+        let blocks = test_helper::blocks(
+            &store.indexed_headers.read().unwrap(),
+            *blocks.last().unwrap().entry.hash(),
+            vec![vec![test_helper::coinbase(3, vec![]), burn.clone()]],
+            3,
+        );
+        connect(&mut indexer, &blocks);
+
+        assert!(chain.get_nft_owner(&color_id).is_none());
+        assert!(chain.nfts(&compute_script_hash(&script)).is_empty());
+    }
+
     /// Test that the skip logic for pagination in get_colors works correctly.
     /// - Initial request (last_seen_color_id = None): should NOT skip any elements
     /// - Pagination request (last_seen_color_id = Some): should skip the first element
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use serde_json::Value;
+use tapyrus::consensus::encode::{deserialize, serialize};
+use tapyrus::hashes::hex::FromHex;
+use tapyrus::hashes::Hash;
+use tapyrus::secp256k1::{Secp256k1, SecretKey};
+use tapyrus::util::signature::Signature;
+use tapyrus::{BlockHash, PrivateKey, PublicKey, Txid};
+use tempfile::TempDir;
+
+use crate::chain::{Block, BlockHeader, Network, OutPoint, Script, Transaction, TxIn, TxOut};
+use crate::config::Config;
+use crate::daemon::Daemon;
+use crate::metrics::Metrics;
+use crate::new_index::{BlockEntry, ChainQuery, FetchFrom, Indexer, Mempool, Query, Store};
+use crate::signal::Waiter;
+use crate::util::HeaderList;
+
+pub fn config(db_path: &Path) -> Config {
+    let localhost = "127.0.0.1:0".parse().unwrap();
+    Config {
+        log: stderrlog::new(),
+        network: Network::new("dev", 1),
+        db_path: db_path.to_path_buf(),
+        daemon_dir: db_path.to_path_buf(),
+        blocks_dir: db_path.join("blocks"),
+        daemon_rpc_addr: localhost,
+        cookie: Some("user:password".to_string()),
+        electrum_rpc_addr: localhost,
+        electrum_tls_addr: None,
+        electrum_ws_addr: None,
+        electrum_wss_addr: None,
+        electrum_tls_cert: None,
+        electrum_tls_key: None,
+        http_addr: localhost,
+        http_socket_file: None,
+        monitoring_addr: localhost,
+        jsonrpc_import: false,
+        light_mode: false,
+        address_search: false,
+        index_unspendables: false,
+        cors: None,
+        precache_scripts: None,
+        utxos_limit: 500,
+        rest_batch_limit: 100,
+        electrum_txs_limit: 500,
+        electrum_max_connections: 10000,
+        electrum_max_connections_per_ip: None,
+        electrum_max_subscriptions: 50000,
+        electrum_idle_timeout: 600,
+        electrum_banner: String::new(),
+        enable_open_assets: false,
+        electrum_public_hosts: None,
+        electrum_announce: false,
+        tor_proxy: None,
+        verify_block_proofs: false,
+        enable_webhooks: false,
+        zmq_hashblock_addr: None,
+        zmq_rawtx_addr: None,
+        rate_limit: None,
+        rate_limit_burst: 100,
+        trusted_proxy: false,
+        api_keys_file: None,
+        require_api_key: false,
+    }
+}
+
+/// The blocks served by the stand-in tapyrusd: every block it was given (including the orphaned
+/// ones, which tapyrusd keeps serving too) and its best chain.
+#[derive(Default)]
+pub struct StandInChain {
+    blocks: HashMap<BlockHash, Block>,
+    best_chain: Vec<BlockHash>,
+}
+
+impl StandInChain {
+    // Make `blocks` the tip of the best chain, disconnecting the blocks above their parent
+    pub fn connect(&mut self, blocks: &[BlockEntry]) {
+        let first = match blocks.first() {
+            Some(first) => first,
+            None => return,
+        };
+        let parent = first.block.header.prev_blockhash;
+        let height = match self.best_chain.iter().position(|hash| *hash == parent) {
+            Some(parent_height) => parent_height + 1,
+            None => {
+                assert_eq!(parent, BlockHash::default(), "unknown parent block");
+                0
+            }
+        };
+        self.best_chain.truncate(height);
+        for entry in blocks {
+            let hash = entry.block.block_hash();
+            self.best_chain.push(hash);
+            self.blocks.insert(hash, entry.block.clone());
+        }
+    }
+
+    fn block(&self, hash: &Value) -> &Block {
+        &self.blocks[&BlockHash::from_hex(hash.as_str().unwrap()).unwrap()]
+    }
+
+    fn height(&self, hash: &BlockHash) -> usize {
+        self.best_chain.iter().position(|h| h == hash).unwrap()
+    }
+}
+
+// Starts a stand-in tapyrusd that answers just enough JSONRPC for `Daemon::new()` to succeed
+// and serves the blocks of the returned chain, for `Indexer::update()`.
+pub fn daemon(config: &Config, metrics: &Metrics) -> (Arc<Daemon>, Arc<Mutex<StandInChain>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let chain = Arc::new(Mutex::new(StandInChain::default()));
+    let served = Arc::clone(&chain);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let chain = Arc::clone(&served);
+            thread::spawn(move || serve_jsonrpc(stream, &chain));
+        }
+    });
+    let daemon = Arc::new(
+        Daemon::new(
+            &config.daemon_dir,
+            &config.blocks_dir,
+            addr,
+            config.cookie_getter(),
+            config.network,
+            Waiter::start(),
+            metrics,
+        )
+        .expect("failed to connect to the stand-in daemon"),
+    );
+    (daemon, chain)
+}
+
+/// A fresh store in a temporary directory, with its indexer and queries over a stand-in
+/// tapyrusd and an empty mempool.
+pub struct TestIndex {
+    // removed with the store when dropped
+    pub dir: TempDir,
+    pub config: Arc<Config>,
+    pub daemon: Arc<Daemon>,
+    pub tapyrusd: Arc<Mutex<StandInChain>>,
+    pub store: Arc<Store>,
+    pub indexer: Indexer,
+    pub chain: Arc<ChainQuery>,
+    pub query: Arc<Query>,
+}
+
+// Opens a `TestIndex` with the test `config()`, as changed by `configure`.
+pub fn index(configure: impl FnOnce(&mut Config)) -> TestIndex {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = config(dir.path());
+    configure(&mut config);
+    let metrics = Metrics::new(config.monitoring_addr);
+    let (daemon, tapyrusd) = daemon(&config, &metrics);
+    let store = Arc::new(Store::open(dir.path(), &config));
+    let indexer = Indexer::open(Arc::clone(&store), FetchFrom::Tapyrusd, &config, &metrics);
+    let config = Arc::new(config);
+    let chain = Arc::new(ChainQuery::new(
+        Arc::clone(&store),
+        Arc::clone(&daemon),
+        &config,
+        &metrics,
+    ));
+    let mempool = Mempool::new(Arc::clone(&chain), &metrics, Arc::clone(&config));
+    let query = Arc::new(Query::new(
+        Arc::clone(&chain),
+        Arc::new(RwLock::new(mempool)),
+        Arc::clone(&daemon),
+        Arc::clone(&config),
+    ));
+    TestIndex {
+        dir,
+        config,
+        daemon,
+        tapyrusd,
+        store,
+        indexer,
+        chain,
+        query,
+    }
+}
+
+fn serve_jsonrpc(stream: TcpStream, chain: &Mutex<StandInChain>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    loop {
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return; // disconnected
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(len) = line.strip_prefix("Content-Length: ") {
+                content_length = len.parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        let requests: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        let replies: Vec<Value> = {
+            let chain = chain.lock().unwrap();
+            requests.iter().map(|r| jsonrpc_reply(r, &chain)).collect()
+        };
+        let reply = Value::Array(replies).to_string();
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}\n",
+            reply.len() + 1, // including the trailing EOL
+            reply
+        )
+        .unwrap();
+    }
+}
+
+fn jsonrpc_reply(request: &Value, chain: &StandInChain) -> Value {
+    let params = &request["params"];
+    let result = match request["method"].as_str().unwrap_or_default() {
+        "getnetworkinfo" => json!({"version": 0, "subversion": "/Tapyrus:test/", "relayfee": 0.00001}),
+        "getblockchaininfo" => json!({
+            "chain": "dev",
+            "blocks": 0,
+            "headers": 0,
+            "bestblockhash": BlockHash::default(),
+            "pruned": false,
+            "verificationprogress": 1.0,
+            "initialblockdownload": false,
+        }),
+        "getbestblockhash" => json!(chain.best_chain.last().cloned().unwrap_or_default()),
+        "getblockhash" => json!(chain.best_chain[params[0].as_u64().unwrap() as usize]),
+        "getblockheader" => {
+            let block = chain.block(&params[0]);
+            match params[1].as_bool() {
+                Some(false) => json!(hex::encode(serialize(&block.header))),
+                _ => json!({"height": chain.height(&block.block_hash())}),
+            }
+        }
+        "getblock" => json!(hex::encode(serialize(chain.block(&params[0])))),
+        method => {
+            return json!({
+                "id": request["id"],
+                "result": null,
+                "error": {"code": -32601, "message": format!("Method not found: {}", method)},
+            })
+        }
+    };
+    json!({"id": request["id"], "result": result, "error": null})
+}
+
+pub fn header(prev_blockhash: BlockHash, time: u32) -> BlockHeader {
+    // version, prev_blockhash, merkle_root, im_merkle_root, time, no xfield and an empty proof
+    let mut raw = 1u32.to_le_bytes().to_vec();
+    raw.extend_from_slice(&prev_blockhash[..]);
+    raw.extend_from_slice(&[0; 64]);
+    raw.extend_from_slice(&time.to_le_bytes());
+    raw.extend_from_slice(&[0, 0]);
+    deserialize(&raw).expect("failed to parse BlockHeader")
+}
+
+pub fn coinbase(height: u32, output: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::default(), u32::max_value()),
+            script_sig: Script::from(height.to_le_bytes().to_vec()),
+            sequence: 0xffffffff,
+            witness: vec![],
+        }],
+        output,
+    }
+}
+
+pub fn tx(prevouts: &[OutPoint], output: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: 0,
+        input: prevouts
+            .iter()
+            .map(|prevout| TxIn {
+                previous_output: *prevout,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            })
+            .collect(),
+        output,
+    }
+}
+
+// Builds a chain of blocks on top of prev_blockhash (which must be part of headers, or the null
+// hash for a new chain), one block per entry of txdata.
+pub fn blocks(
+    headers: &HeaderList,
+    prev_blockhash: BlockHash,
+    txdata: Vec<Vec<Transaction>>,
+    time: u32,
+) -> Vec<BlockEntry> {
+    blocks_with(headers, prev_blockhash, txdata, time, |_, header| header)
+}
+
+// Like blocks(), with the header of the i-th block changed by `finish` (e.g. given an xfield and
+// signed) before the next block points to it.
+pub fn blocks_with(
+    headers: &HeaderList,
+    prev_blockhash: BlockHash,
+    txdata: Vec<Vec<Transaction>>,
+    time: u32,
+    mut finish: impl FnMut(usize, BlockHeader) -> BlockHeader,
+) -> Vec<BlockEntry> {
+    let mut prev_blockhash = prev_blockhash;
+    let block_headers: Vec<BlockHeader> = (0..txdata.len())
+        .map(|i| {
+            let header = finish(i, header(prev_blockhash, time + i as u32));
+            prev_blockhash = header.block_hash();
+            header
+        })
+        .collect();
+    headers
+        .order(block_headers)
+        .into_iter()
+        .zip(txdata)
+        .map(|(entry, txdata)| BlockEntry {
+            block: Block {
+                header: entry.header().clone(),
+                txdata,
+            },
+            entry,
+            size: 0,
+        })
+        .collect()
+}
+
+// The aggregate private key of a stand-in federation
+pub fn federation_key(seed: u8) -> (PrivateKey, PublicKey) {
+    let key = PrivateKey {
+        compressed: true,
+        network: tapyrus::Network::Dev,
+        key: SecretKey::from_slice(&[seed; 32]).unwrap(),
+    };
+    (key, key.public_key(&Secp256k1::signing_only()))
+}
+
+// Set the block proof of the header, as the federation holding key would
+pub fn sign(mut header: BlockHeader, key: &PrivateKey) -> BlockHeader {
+    let sighash = header.signature_hash().into_inner();
+    header.proof = Some(Signature::sign(key, &sighash).unwrap());
+    header
+}
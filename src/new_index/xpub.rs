@@ -0,0 +1,161 @@
+use rayon::prelude::*;
+use tapyrus::secp256k1::Secp256k1;
+use tapyrus::util::bip32::{ChildNumber, ExtendedPubKey};
+use tapyrus::Script;
+
+use crate::errors::*;
+use crate::new_index::schema::StatsMap;
+use crate::new_index::{compute_script_hash, Query, ScriptStats};
+use crate::util::FullHash;
+
+// The external (receive) and internal (change) chains of BIP44 wallets
+pub const CHAINS: [u32; 2] = [0, 1];
+
+// Scanning stops there even when the gap limit is not reached, as every address costs a lookup.
+// The scan is then reported as truncated.
+pub const MAX_ADDRESSES_PER_CHAIN: u32 = 1000;
+
+/// An address derived from an extended public key, at `chain/index` below it
+pub struct DerivedAddress {
+    pub chain: u32,
+    pub index: u32,
+    pub script: Script,
+    pub scripthash: FullHash,
+    pub stats: (StatsMap, StatsMap),
+}
+
+impl DerivedAddress {
+    // The confirmed and unconfirmed stats include the colored (cp2pkh) outputs of the script,
+    // which are indexed under the uncolored scripthash as well
+    fn is_used(&self) -> bool {
+        let (chain_stats, mempool_stats) = &self.stats;
+        chain_stats
+            .values()
+            .chain(mempool_stats.values())
+            .any(|stats| stats.tx_count > 0)
+    }
+}
+
+/// The addresses with a history found by scanning an xpub, by chain and index
+pub struct Scan {
+    pub addresses: Vec<DerivedAddress>,
+    // whether a chain reached MAX_ADDRESSES_PER_CHAIN before the gap limit
+    pub truncated: bool,
+}
+
+/// Derive the p2pkh addresses of the receive and change chains of `xpub` and look up their
+/// stats, until `gap_limit` consecutive addresses of each chain have no history.
+pub fn scan(query: &Query, xpub: &ExtendedPubKey, gap_limit: u32) -> Result<Scan> {
+    let secp = Secp256k1::verification_only();
+    let mut used = vec![];
+    let mut truncated = false;
+    for &chain in &CHAINS {
+        let chain_xpub = xpub
+            .ckd_pub(&secp, ChildNumber::from_normal_idx(chain).unwrap())
+            .chain_err(|| "failed to derive the xpub chain")?;
+        let mut index = 0;
+        let mut unused = 0; // the number of consecutive addresses without history
+        while unused < gap_limit {
+            if index >= MAX_ADDRESSES_PER_CHAIN {
+                truncated = true;
+                break;
+            }
+            // the addresses that may still be needed to reach the gap limit are looked up at once
+            let end = (index + gap_limit - unused).min(MAX_ADDRESSES_PER_CHAIN);
+            let addresses = (index..end)
+                .into_par_iter()
+                .map(|index| {
+                    let child = chain_xpub
+                        .ckd_pub(&secp, ChildNumber::from_normal_idx(index).unwrap())
+                        .chain_err(|| "failed to derive the xpub address")?;
+                    let script =
+                        tapyrus::Address::p2pkh(&child.to_pub(), xpub.network).script_pubkey();
+                    let scripthash = compute_script_hash(&script);
+                    let stats = query.stats(&scripthash[..]);
+                    Ok(DerivedAddress {
+                        chain,
+                        index,
+                        script,
+                        scripthash,
+                        stats,
+                    })
+                })
+                .collect::<Result<Vec<DerivedAddress>>>()?;
+            for address in addresses {
+                if address.is_used() {
+                    unused = 0;
+                    used.push(address);
+                } else {
+                    unused += 1;
+                }
+            }
+            index = end;
+        }
+    }
+    Ok(Scan {
+        addresses: used,
+        truncated,
+    })
+}
+
+/// The stats of the addresses, summed by color. Transactions touching several addresses are
+/// counted once per address.
+pub fn total_stats<'a, I>(stats: I) -> StatsMap
+where
+    I: IntoIterator<Item = &'a StatsMap>,
+{
+    let mut total = StatsMap::new();
+    for stats in stats {
+        for (color_id, stats) in stats {
+            let sum = total
+                .entry(color_id.clone())
+                .or_insert_with(ScriptStats::default);
+            sum.tx_count += stats.tx_count;
+            sum.funded_txo_count += stats.funded_txo_count;
+            sum.spent_txo_count += stats.spent_txo_count;
+            sum.funded_txo_sum += stats.funded_txo_sum;
+            sum.spent_txo_sum += stats.spent_txo_sum;
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::FromHex;
+    use tapyrus::ColorIdentifier;
+
+    fn hex_script(hex: &str) -> Script {
+        Script::from(Vec::from_hex(hex).unwrap())
+    }
+
+    fn stats(tx_count: usize, funded_txo_sum: u64) -> ScriptStats {
+        ScriptStats {
+            tx_count,
+            funded_txo_count: tx_count,
+            spent_txo_count: 0,
+            funded_txo_sum,
+            spent_txo_sum: 0,
+        }
+    }
+
+    #[test]
+    fn test_total_stats() {
+        let color_id = ColorIdentifier::reissuable(hex_script(
+            "76a914000000000000000000000000000000000000000088ac",
+        ));
+        let mut first = StatsMap::new();
+        first.insert(ColorIdentifier::default(), stats(2, 1000));
+        first.insert(color_id.clone(), stats(1, 50));
+        let mut second = StatsMap::new();
+        second.insert(ColorIdentifier::default(), stats(3, 500));
+
+        let total = total_stats(vec![&first, &second]);
+        assert_eq!(total.len(), 2);
+        assert_eq!(total[&ColorIdentifier::default()].tx_count, 5);
+        assert_eq!(total[&ColorIdentifier::default()].funded_txo_sum, 1500);
+        assert_eq!(total[&color_id].funded_txo_count, 1);
+        assert!(total_stats(Vec::<&StatsMap>::new()).is_empty());
+    }
+}
@@ -1,6 +1,6 @@
 use crate::new_index::FundingInfo;
 use openassets_tapyrus::openassets::asset_id::AssetId;
-use openassets_tapyrus::openassets::marker_output::Metadata;
+use openassets_tapyrus::openassets::marker_output::{Metadata, TxOutExt};
 use serde::ser::SerializeStruct;
 use serde::{Serialize, Serializer};
 use std::str::FromStr;
@@ -131,6 +131,9 @@ impl OpenAssetOutput for FundingInfo {
     }
 }
 
+// Assign assets to the outputs of txn, according to the quantities in its marker output.
+// Invalid transactions (which have more asset quantities than outputs, transfer more units than
+// their inputs hold or mix different assets within an output) leave all the outputs uncolored.
 pub fn compute_assets(
     prev_outs: Vec<(TxOut, Option<OpenAsset>)>,
     marker_output_index: usize,
@@ -139,9 +142,12 @@ pub fn compute_assets(
     network_type: Network,
     metadata: &Metadata,
 ) -> Vec<Option<OpenAsset>> {
-    assert!(quantities.len() <= txn.output.len() - 1);
     assert!(!prev_outs.is_empty());
 
+    if quantities.len() > txn.output.len() - 1 {
+        return uncolored(txn);
+    }
+
     let mut result = Vec::new();
 
     //Issuance outputs
@@ -180,8 +186,11 @@ pub fn compute_assets(
         while output_units_left > 0 {
             if input_units_left == 0 {
                 current_input = input_enum.next();
-                if let Some((_, Some(asset))) = current_input {
-                    input_units_left = asset.asset_quantity;
+                match current_input {
+                    Some((_, Some(asset))) => input_units_left = asset.asset_quantity,
+                    Some((_, None)) => (),
+                    // not enough asset units in the inputs
+                    None => return uncolored(txn),
                 }
             }
             if let Some((_, Some(asset))) = current_input {
@@ -195,7 +204,7 @@ pub fn compute_assets(
                 if asset_id.is_none() {
                     asset_id = Some(asset.asset_id.clone());
                 } else if asset_id != Some(asset.asset_id.clone()) {
-                    panic!("invalid asset");
+                    return uncolored(txn);
                 }
             }
         }
@@ -218,6 +227,35 @@ pub fn compute_assets(
     result
 }
 
+// Color the outputs of txn using its marker output (the first output with a valid Open Assets
+// payload), given the spent outputs and their assets in input order.
+pub fn color_transaction(
+    txn: &Transaction,
+    prev_outs: Vec<(TxOut, Option<OpenAsset>)>,
+    network_type: Network,
+) -> Vec<Option<OpenAsset>> {
+    if txn.is_coin_base() || prev_outs.is_empty() {
+        return uncolored(txn);
+    }
+    for (i, val) in txn.output.iter().enumerate() {
+        if let Ok(marker) = val.get_oa_payload() {
+            return compute_assets(
+                prev_outs,
+                i,
+                txn,
+                marker.quantities,
+                network_type,
+                &marker.metadata,
+            );
+        }
+    }
+    uncolored(txn)
+}
+
+fn uncolored(txn: &Transaction) -> Vec<Option<OpenAsset>> {
+    txn.output.iter().map(|_| None).collect()
+}
+
 #[cfg(test)]
 pub mod test_helper {
     use super::*;
@@ -525,4 +563,68 @@ mod tests {
         assert_eq!(assets[4], None);
         assert_eq!(assets[5], None);
     }
+
+    fn transfer_txn(inputs: u32) -> Transaction {
+        let valid_marker = TxOut {
+            value: 0,
+            script_pubkey: Builder::from(
+                hex::decode(
+                    "6a244f410100030a01131b753d68747470733a2f2f6370722e736d2f35596753553150672d71",
+                )
+                .unwrap(),
+            )
+            .into_script(),
+        };
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: (0..inputs).map(default_input).collect(),
+            output: vec![valid_marker, TxOut::default(), TxOut::default()],
+        }
+    }
+
+    #[test]
+    fn test_compute_assets_insufficient_units() {
+        let prev_outs = vec![(TxOut::default(), asset_1(5, empty_metadata()))];
+        let assets = compute_assets(
+            prev_outs,
+            0,
+            &transfer_txn(1),
+            vec![10],
+            Network::Prod,
+            &empty_metadata(),
+        );
+        assert_eq!(assets, vec![None, None, None]);
+    }
+
+    #[test]
+    fn test_compute_assets_mixed_assets() {
+        let prev_outs = vec![
+            (TxOut::default(), asset_1(5, empty_metadata())),
+            (TxOut::default(), asset_2(5, empty_metadata())),
+        ];
+        let assets = compute_assets(
+            prev_outs,
+            0,
+            &transfer_txn(2),
+            vec![10],
+            Network::Prod,
+            &empty_metadata(),
+        );
+        assert_eq!(assets, vec![None, None, None]);
+    }
+
+    #[test]
+    fn test_compute_assets_too_many_quantities() {
+        let prev_outs = vec![(TxOut::default(), asset_1(5, empty_metadata()))];
+        let assets = compute_assets(
+            prev_outs,
+            0,
+            &transfer_txn(1),
+            vec![1, 1, 1],
+            Network::Prod,
+            &empty_metadata(),
+        );
+        assert_eq!(assets, vec![None, None, None]);
+    }
 }
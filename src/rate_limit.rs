@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics::{CounterVec, MetricOpts, Metrics};
+
+// how often the buckets that were refilled to the brim are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// The tokens spent by the requests of both servers, by how much work they may take
+pub const COST_DEFAULT: u32 = 1;
+pub const COST_LIST: u32 = 2; // lists of blocks or transactions
+pub const COST_SCRIPT: u32 = 5; // stats and utxos of an address
+pub const COST_HISTORY: u32 = 10; // history scans of an address or color
+pub const COST_XPUB: u32 = 50; // scans of the addresses derived from an xpub
+
+/// The client a request is accounted to: requests made with an API key share the key's
+/// bucket, whatever their IP address.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ClientId {
+    Ip(IpAddr),
+    ApiKey(String),
+}
+
+/// The size of a token bucket, and the number of tokens per second it is refilled with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub rate: u32,
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    rate: f64,
+    burst: f64,
+}
+
+impl Bucket {
+    // The tokens of the bucket after refilling it up to now
+    fn refill(&mut self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+        self.tokens
+    }
+}
+
+/// A token bucket per client. Each request spends tokens according to its cost, so that
+/// expensive requests (e.g. history scans) exhaust the bucket sooner than cheap ones.
+///
+/// Clients get the quota of their API key, or the default one. Clients without a quota are not
+/// limited.
+pub struct RateLimiter {
+    default: Option<Quota>,
+    buckets: Mutex<(HashMap<ClientId, Bucket>, Instant)>, // with the time of the last pruning
+    rejected: CounterVec,
+}
+
+impl RateLimiter {
+    pub fn new(default: Option<Quota>, metrics: &Metrics) -> Self {
+        RateLimiter {
+            default,
+            buckets: Mutex::new((HashMap::new(), Instant::now())),
+            rejected: metrics.counter_vec(
+                MetricOpts::new(
+                    "rate_limit_rejected",
+                    "# of requests rejected by the rate limiter",
+                ),
+                &["server"],
+            ),
+        }
+    }
+
+    /// Spend `cost` tokens of the client's bucket, sized by `quota` or by the default quota.
+    /// Fails with the number of seconds until enough tokens are available when the bucket is
+    /// exhausted, counting the rejection for `server`.
+    pub fn check(
+        &self,
+        client: &ClientId,
+        cost: u32,
+        quota: Option<&Quota>,
+        server: &str,
+    ) -> Result<(), u64> {
+        let quota = match quota.or(self.default.as_ref()) {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+        self.check_at(client, cost, quota, Instant::now())
+            .map_err(|wait| {
+                self.rejected.with_label_values(&[server]).inc();
+                wait.as_secs() + (wait.subsec_nanos() > 0) as u64
+            })
+    }
+
+    fn check_at(
+        &self,
+        client: &ClientId,
+        cost: u32,
+        quota: &Quota,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let (rate, burst) = (quota.rate as f64, quota.burst.max(quota.rate) as f64);
+        let mut buckets = self.buckets.lock().unwrap();
+        let (buckets, pruned) = &mut *buckets;
+
+        if now.saturating_duration_since(*pruned) >= PRUNE_INTERVAL {
+            buckets.retain(|_, bucket| bucket.refill(now) < bucket.burst);
+            *pruned = now;
+        }
+
+        let bucket = buckets.entry(client.clone()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            rate,
+            burst,
+        });
+        let tokens = bucket.refill(now);
+        // the quota of an API key may have changed since the bucket was created
+        bucket.rate = rate;
+        bucket.burst = burst;
+
+        // requests costing more than the bucket can hold would never pass otherwise
+        let cost = (cost as f64).min(burst);
+        if tokens < cost {
+            return Err(Duration::from_secs_f64((cost - tokens) / rate));
+        }
+        bucket.tokens = tokens - cost;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let limiter = RateLimiter::new(None, &metrics);
+        let quota = Quota {
+            rate: 10,
+            burst: 20,
+        };
+        let alice = ClientId::Ip("10.0.0.1".parse().unwrap());
+        let bob = ClientId::ApiKey("bob".to_string());
+        let start = Instant::now();
+
+        assert!(limiter.check_at(&alice, 15, &quota, start).is_ok());
+        assert_eq!(
+            limiter.check_at(&alice, 10, &quota, start),
+            Err(Duration::from_millis(500))
+        );
+        // the buckets are independent
+        assert!(limiter.check_at(&bob, 20, &quota, start).is_ok());
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check_at(&alice, 10, &quota, later).is_ok());
+        assert!(limiter.check_at(&alice, 1, &quota, later).is_err());
+
+        // a cost above the burst size spends the whole bucket
+        let refilled = later + Duration::from_secs(2);
+        assert!(limiter.check_at(&alice, 100, &quota, refilled).is_ok());
+        assert!(limiter.check_at(&alice, 1, &quota, refilled).is_err());
+
+        // full buckets are forgotten
+        let idle = refilled + PRUNE_INTERVAL;
+        assert!(limiter.check_at(&bob, 1, &quota, idle).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().0.len(), 1);
+
+        // clients without a quota are not limited
+        assert_eq!(limiter.check(&alice, 100, None, "rest"), Ok(()));
+        let limited = Quota { rate: 1, burst: 1 };
+        assert_eq!(limiter.check(&alice, 1, Some(&limited), "rest"), Ok(()));
+        assert_eq!(limiter.check(&alice, 1, Some(&limited), "rest"), Err(1));
+    }
+}
@@ -1,36 +1,58 @@
+use crate::admin::Admin;
+use crate::api_keys::{is_admin_route, ApiKey, ApiKeys};
 use crate::chain::{address, Network, NetworkType, OutPoint, Transaction, TxIn, TxOut};
 use crate::config::Config;
 use crate::errors;
-use crate::new_index::color::ColoredStats;
+use crate::new_index::color::{
+    colored_tx_summary, serialize_color_id, ColorIssuer, ColoredStats, ColoredTxSummary, Holder,
+    NftOutput, TokenType,
+};
+use crate::new_index::events::Event;
+use crate::new_index::federation::{verify_block_proof, XFieldChange};
 use crate::new_index::mempool::TxOverview;
+use crate::new_index::xpub;
 use crate::new_index::{compute_script_hash, Query, SpendingInput, Utxo};
+use crate::open_assets::OpenAsset;
+use crate::rate_limit::{
+    ClientId, RateLimiter, COST_DEFAULT, COST_HISTORY, COST_LIST, COST_SCRIPT, COST_XPUB,
+};
 use crate::util::{
     create_socket, electrum_merkle, extract_tx_prevouts, full_hash, get_innerscripts,
     get_script_asm, get_script_type, get_tx_fee, has_prevout, is_coinbase, script_to_address,
     BlockHeaderMeta, BlockId, FullHash, TransactionStatus,
 };
+use crate::webhooks::{Webhook, WebhookRegistry};
 
 use hex::{self, FromHexError};
+use hyper::body::HttpBody;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Response, Server, StatusCode};
+use hyper::{Body, HeaderMap, Method, Request, Response, Server, StatusCode};
 use tapyrus::blockdata::block::XField;
 use tapyrus::consensus::encode;
 use tapyrus::hashes::hex::{FromHex, ToHex};
 use tapyrus::hashes::Error as HashError;
+use tapyrus::util::bip32::ExtendedPubKey;
 use tapyrus::{BlockHash, ColorIdentifier, Script, Txid};
-use tokio::sync::oneshot;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{oneshot, watch};
+use tokio::time;
 
 use hyperlocal::UnixServerExt;
+use openassets_tapyrus::openassets::asset_id::AssetId;
+use rayon::prelude::*;
 use std::fs;
 
 use serde::Serialize;
 use serde_json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::num::ParseIntError;
 use std::os::unix::fs::FileTypeExt;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 use url::form_urlencoded;
 
 const CHAIN_TXS_PER_PAGE: usize = 25;
@@ -39,6 +61,18 @@ const MEMPOOL_TXS_PER_PAGE: usize = 25;
 const BLOCK_LIMIT: usize = 10;
 const ADDRESS_SEARCH_LIMIT: usize = 10;
 const COLOR_IDS_PER_PAGE: usize = 25;
+const HOLDERS_PER_PAGE: usize = 25;
+const MAX_HOLDERS_PER_PAGE: usize = 100;
+const BLOCK_FILL_DEFAULT_RANGE: usize = 144;
+const BLOCK_FILL_MAX_RANGE: usize = 2016;
+const MAX_EVENT_SUBSCRIPTIONS: usize = 100;
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(15);
+const WEBHOOK_DEFAULT_CONFIRMATIONS: u32 = 6;
+const MAX_BATCH_TXS: usize = 1000;
+const XPUB_GAP_LIMIT: u32 = 20;
+// enough for the hex of the largest standard transactions, and for batches of addresses
+const MAX_BODY_SIZE: usize = 4 << 20;
+const MAX_XPUB_GAP_LIMIT: u32 = 100;
 
 const TTL_LONG: u32 = 157_784_630; // ttl for static resources (5 years)
 const TTL_SHORT: u32 = 10; // ttl for volatie resources
@@ -60,10 +94,12 @@ struct BlockValue {
     signature: Option<String>,
     xfield_type: u8,
     xfield: Option<String>,
+    max_block_size: u32,
+    fill_ratio: f64,
 }
 
 impl BlockValue {
-    fn new(blockhm: BlockHeaderMeta, _network: Network) -> Self {
+    fn new(blockhm: BlockHeaderMeta, max_block_size: u32, _network: Network) -> Self {
         let header = blockhm.header_entry.header();
         let xfield = match header.xfield {
             XField::None => None,
@@ -87,6 +123,8 @@ impl BlockValue {
             signature: header.proof.map(|p| encode::serialize_hex(&p)),
             xfield_type: header.xfield.field_type(),
             xfield: xfield,
+            max_block_size,
+            fill_ratio: fill_ratio(blockhm.meta.size, max_block_size),
         }
     }
 }
@@ -101,6 +139,8 @@ struct TransactionValue {
     size: u32,
     weight: u32,
     fee: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    colored_summary: Vec<ColoredTxSummary>,
     #[serde(skip_serializing_if = "Option::is_none")]
     status: Option<TransactionStatus>,
 }
@@ -128,6 +168,7 @@ impl TransactionValue {
             .collect();
 
         let fee = get_tx_fee(&tx, &prevouts, config.network);
+        let colored_summary = colored_tx_summary(&tx, txos);
 
         TransactionValue {
             txid: tx.malfix_txid(),
@@ -138,6 +179,7 @@ impl TransactionValue {
             size: tx.get_size() as u32,
             weight: tx.get_weight() as u32,
             fee,
+            colored_summary,
             status: Some(TransactionStatus::from(blockid)),
         }
     }
@@ -206,6 +248,11 @@ struct TxOutValue {
     #[serde(skip_serializing_if = "Option::is_none")]
     scriptpubkey_address: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color_id: Option<ColorIdentifier>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uncolored_scriptpubkey_address: Option<String>,
+
     value: u64,
 }
 
@@ -220,11 +267,21 @@ impl TxOutValue {
         // TODO should the following something to put inside rust-elements lib?
         let script_type = get_script_type(&script);
 
+        let (color_id, uncolored_script_addr) = match script.split_color() {
+            Some((color_id, uncolored)) => (
+                Some(color_id),
+                script_to_address(&uncolored, config.network),
+            ),
+            None => (None, None),
+        };
+
         TxOutValue {
             scriptpubkey: script.clone(),
             scriptpubkey_asm: script_asm,
             scriptpubkey_address: script_addr,
             scriptpubkey_type: script_type.to_string(),
+            color_id,
+            uncolored_scriptpubkey_address: uncolored_script_addr,
             value,
         }
     }
@@ -250,6 +307,46 @@ impl From<Utxo> for UtxoValue {
     }
 }
 
+#[derive(Serialize)]
+struct OpenAssetUtxoValue {
+    #[serde(flatten)]
+    utxo: UtxoValue,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asset: Option<OpenAsset>,
+}
+
+// A utxo of an address derived from an xpub
+#[derive(Serialize)]
+struct XpubUtxoValue {
+    #[serde(flatten)]
+    utxo: UtxoValue,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scriptpubkey_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>, // the derived address, i.e. of the uncolored script
+    chain: u32,
+    index: u32,
+}
+
+impl XpubUtxoValue {
+    fn new(utxo: Utxo, address: &xpub::DerivedAddress, config: &Config) -> Self {
+        // colored coins are held by the cp2pkh variant of the derived p2pkh script
+        let script = if utxo.color_id == ColorIdentifier::default() {
+            Some(address.script.clone())
+        } else {
+            address.script.add_color(utxo.color_id.clone()).ok()
+        };
+        XpubUtxoValue {
+            scriptpubkey_address: script
+                .and_then(|script| script_to_address(&script, config.network)),
+            address: script_to_address(&address.script, config.network),
+            chain: address.chain,
+            index: address.index,
+            utxo: UtxoValue::from(utxo),
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct SpendingValue {
     spent: bool,
@@ -295,6 +392,7 @@ struct ColoredStatsValue {
 
 impl From<ColoredStats> for ColoredStatsValue {
     fn from(stats: ColoredStats) -> Self {
+        let stats = stats.enforce_nft_supply();
         ColoredStatsValue {
             color_id: stats.color_id,
             tx_count: stats.tx_count,
@@ -308,6 +406,96 @@ impl From<ColoredStats> for ColoredStatsValue {
     }
 }
 
+#[derive(Serialize)]
+struct HolderValue {
+    scripthash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    chain_balance: u64,
+    mempool_balance: i64,
+}
+
+impl HolderValue {
+    fn new(holder: Holder, config: &Config) -> Self {
+        HolderValue {
+            scripthash: hex::encode(holder.scripthash),
+            address: script_to_address(&holder.script, config.network),
+            chain_balance: holder.chain_balance,
+            mempool_balance: holder.mempool_balance,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct NftValue {
+    color_id: ColorIdentifier,
+    txid: Txid,
+    vout: u32,
+    scriptpubkey: Script,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scriptpubkey_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>, // the address of the uncolored script
+    status: TransactionStatus,
+}
+
+impl NftValue {
+    fn new(output: NftOutput, config: &Config) -> Self {
+        let address = output
+            .script
+            .split_color()
+            .and_then(|(_, script)| script_to_address(&script, config.network));
+        NftValue {
+            scriptpubkey_address: script_to_address(&output.script, config.network),
+            address,
+            color_id: output.color_id,
+            txid: output.txid,
+            vout: output.vout,
+            scriptpubkey: output.script,
+            status: TransactionStatus::from(output.confirmed),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FederationKeyValue {
+    height: usize,
+    block_hash: BlockHash,
+    aggregate_pubkey: String,
+    activation_height: usize,
+}
+
+impl FederationKeyValue {
+    fn new(change: &XFieldChange) -> Option<Self> {
+        Some(FederationKeyValue {
+            height: change.height,
+            block_hash: change.blockhash,
+            aggregate_pubkey: change.aggregate_pubkey()?.to_string(),
+            activation_height: change.activation_height(),
+        })
+    }
+}
+
+fn fill_ratio(size: u32, max_block_size: u32) -> f64 {
+    if max_block_size == 0 {
+        return 0.0;
+    }
+    size as f64 / max_block_size as f64
+}
+
+fn color_issuer_value(issuer: ColorIssuer, config: &Config) -> serde_json::Value {
+    match issuer {
+        ColorIssuer::Script(script) => json!({
+            "scriptpubkey": script,
+            "scriptpubkey_address": script_to_address(&script, config.network),
+        }),
+        ColorIssuer::OutPoint(outpoint) => json!({
+            "txid": outpoint.txid,
+            "vout": outpoint.vout,
+        }),
+    }
+}
+
 fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
     height.map_or(TTL_SHORT, |height| {
         if query.chain().best_height() - height >= CONF_FINAL {
@@ -341,36 +529,102 @@ fn prepare_txs(
 }
 
 #[tokio::main]
-async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receiver<()>) {
+async fn run_server(
+    config: Arc<Config>,
+    query: Arc<Query>,
+    events: broadcast::Sender<Arc<Event>>,
+    webhooks: Option<Arc<WebhookRegistry>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    api_keys: Option<Arc<ApiKeys>>,
+    rx: oneshot::Receiver<()>,
+) {
     let addr = &config.http_addr;
     let socket_file = &config.http_socket_file;
 
     let config = Arc::clone(&config);
     let query = Arc::clone(&query);
+    // the admin API requires API keys
+    let admin = api_keys
+        .as_ref()
+        .map(|_| Arc::new(Admin::new(Arc::clone(&query))));
+
+    // event streams never end on their own, so they have to be closed for the graceful
+    // shutdown to complete
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
-    let make_service_fn_inn = || {
+    // the remote address is unknown for connections over the unix socket
+    let make_service_fn_inn = |remote_ip: Option<IpAddr>| {
         let query = Arc::clone(&query);
         let config = Arc::clone(&config);
+        let events = events.clone();
+        let webhooks = webhooks.clone();
+        let rate_limiter = rate_limiter.clone();
+        let api_keys = api_keys.clone();
+        let admin = admin.clone();
+        let shutdown_rx = shutdown_rx.clone();
 
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
                 let query = Arc::clone(&query);
                 let config = Arc::clone(&config);
+                let events = events.clone();
+                let webhooks = webhooks.clone();
+                let rate_limiter = rate_limiter.clone();
+                let api_keys = api_keys.clone();
+                let admin = admin.clone();
+                let shutdown_rx = shutdown_rx.clone();
 
                 async move {
                     let method = req.method().clone();
                     let uri = req.uri().clone();
-                    let body = hyper::body::to_bytes(req.into_body()).await?;
-
-                    let mut resp = handle_request(method, uri, body, &query, &config)
-                        .unwrap_or_else(|err| {
-                            warn!("{:?}", err);
-                            Response::builder()
-                                .status(err.0)
-                                .header("Content-Type", "text/plain")
-                                .body(Body::from(err.1))
-                                .unwrap()
-                        });
+                    let client_ip = client_ip(remote_ip, req.headers(), config.trusted_proxy);
+
+                    // the limits are checked on the headers, before the body is read, so that
+                    // rejected clients cannot make the server buffer it
+                    let resp = async {
+                        let api_key =
+                            authenticate(api_keys.as_deref(), config.require_api_key, &req)?;
+                        let api_key = api_key.as_deref();
+                        let rate_limiter = rate_limiter.as_deref();
+                        let cost = request_cost(uri.path());
+                        if let Err(retry_after) =
+                            check_rate_limit(rate_limiter, client_ip, api_key, cost)
+                        {
+                            return Ok(rate_limited(retry_after));
+                        }
+                        if method == Method::GET && uri.path() == "/events" {
+                            return handle_events(&uri, &config, events.subscribe(), shutdown_rx);
+                        }
+
+                        let body = read_body(req.into_body()).await?;
+                        if uri.path().starts_with("/addresses/") {
+                            // batches cost as much as querying their addresses one by one
+                            let batch_cost = cost.saturating_mul(batch_size(&body) - 1);
+                            if let Err(retry_after) =
+                                check_rate_limit(rate_limiter, client_ip, api_key, batch_cost)
+                            {
+                                return Ok(rate_limited(retry_after));
+                            }
+                        }
+                        handle_request(
+                            method,
+                            uri,
+                            body,
+                            &query,
+                            &config,
+                            webhooks.as_deref(),
+                            admin.as_deref(),
+                        )
+                    }
+                    .await;
+                    let mut resp = resp.unwrap_or_else(|err| {
+                        warn!("{:?}", err);
+                        Response::builder()
+                            .status(err.0)
+                            .header("Content-Type", "text/plain")
+                            .body(Body::from(err.1))
+                            .unwrap()
+                    });
                     if let Some(ref origins) = config.cors {
                         resp.headers_mut()
                             .insert("Access-Control-Allow-Origin", origins.parse().unwrap());
@@ -390,9 +644,12 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
 
             Server::from_tcp(socket.into_tcp_listener())
                 .expect("Server::from_tcp failed")
-                .serve(make_service_fn(move |_| make_service_fn_inn()))
+                .serve(make_service_fn(move |conn: &AddrStream| {
+                    make_service_fn_inn(Some(conn.remote_addr().ip()))
+                }))
                 .with_graceful_shutdown(async {
                     rx.await.ok();
+                    shutdown_tx.send(true).ok();
                 })
                 .await
         }
@@ -408,9 +665,10 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
 
             Server::bind_unix(path)
                 .expect("Server::bind_unix failed")
-                .serve(make_service_fn(move |_| make_service_fn_inn()))
+                .serve(make_service_fn(move |_| make_service_fn_inn(None)))
                 .with_graceful_shutdown(async {
                     rx.await.ok();
+                    shutdown_tx.send(true).ok();
                 })
                 .await
         }
@@ -421,13 +679,20 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
     }
 }
 
-pub fn start(config: Arc<Config>, query: Arc<Query>) -> Handle {
+pub fn start(
+    config: Arc<Config>,
+    query: Arc<Query>,
+    events: broadcast::Sender<Arc<Event>>,
+    webhooks: Option<Arc<WebhookRegistry>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    api_keys: Option<Arc<ApiKeys>>,
+) -> Handle {
     let (tx, rx) = oneshot::channel::<()>();
 
     Handle {
         tx,
         thread: thread::spawn(move || {
-            run_server(config, query, rx);
+            run_server(config, query, events, webhooks, rate_limiter, api_keys, rx);
         }),
     }
 }
@@ -444,12 +709,390 @@ impl Handle {
     }
 }
 
+// The API key of the request, given with the X-API-Key header or with the api_key query
+// parameter (for clients that cannot set headers, e.g. EventSource). A key allowing the route is
+// required for the admin API, and for every route with --require-api-key.
+fn authenticate(
+    api_keys: Option<&ApiKeys>,
+    require_api_key: bool,
+    req: &Request<Body>,
+) -> Result<Option<Arc<ApiKey>>, HttpError> {
+    let api_keys = match api_keys {
+        Some(api_keys) => api_keys,
+        None => return Ok(None),
+    };
+    let path = req.uri().path();
+    let key = match req.headers().get("X-API-Key") {
+        Some(key) => Some(key.to_str().unwrap_or_default().to_owned()),
+        None => req.uri().query().and_then(|query| {
+            form_urlencoded::parse(query.as_bytes())
+                .find(|(name, _)| name == "api_key")
+                .map(|(_, key)| key.into_owned())
+        }),
+    };
+    match key {
+        Some(key) => {
+            let api_key = api_keys
+                .get(&key)
+                .ok_or_else(|| unauthorized("Invalid API key"))?;
+            if !api_key.allows(path) {
+                return Err(HttpError(
+                    StatusCode::FORBIDDEN,
+                    format!("API key {} is not allowed to access {}", api_key.name, path),
+                ));
+            }
+            Ok(Some(api_key))
+        }
+        None if require_api_key || is_admin_route(path) => Err(unauthorized("API key required")),
+        None => Ok(None),
+    }
+}
+
+fn unauthorized(msg: &str) -> HttpError {
+    HttpError(StatusCode::UNAUTHORIZED, msg.to_string())
+}
+
+fn rate_limited(retry_after: u64) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("Content-Type", "text/plain")
+        .header("Retry-After", retry_after.to_string())
+        .body(Body::from("Rate limit exceeded"))
+        .unwrap()
+}
+
+// Read the request body, refusing the ones larger than MAX_BODY_SIZE
+async fn read_body(mut body: Body) -> Result<hyper::body::Bytes, HttpError> {
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| HttpError::from(format!("Invalid request body: {}", e)))?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            bail!(HttpError(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Request body too large (max {} bytes)", MAX_BODY_SIZE)
+            ))
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes.into())
+}
+
+// Spend `cost` tokens of the client, failing with the number of seconds to wait when they are
+// exhausted. Requests with an API key are accounted to the key.
+fn check_rate_limit(
+    rate_limiter: Option<&RateLimiter>,
+    client_ip: Option<IpAddr>,
+    api_key: Option<&ApiKey>,
+    cost: u32,
+) -> Result<(), u64> {
+    let rate_limiter = match rate_limiter {
+        Some(rate_limiter) => rate_limiter,
+        None => return Ok(()),
+    };
+    if let Some(api_key) = api_key {
+        let client = ClientId::ApiKey(api_key.name.clone());
+        return rate_limiter.check(&client, cost, api_key.quota.as_ref(), "rest");
+    }
+    match client_ip {
+        Some(ip) => rate_limiter.check(&ClientId::Ip(ip), cost, None, "rest"),
+        None => Ok(()),
+    }
+}
+
+// The address of the client. Behind a trusted reverse proxy, it is the one the proxy forwarded,
+// which is the only way to tell the clients of the unix socket apart. The header is ignored
+// otherwise, as any client could set it.
+fn client_ip(
+    remote_ip: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxy: bool,
+) -> Option<IpAddr> {
+    if trusted_proxy {
+        if let Some(ip) = forwarded_ip(headers) {
+            return Some(ip);
+        }
+    }
+    remote_ip
+}
+
+// The client address as seen by the reverse proxy. Proxies append the address they received the
+// request from, so the last one is the only one that can be trusted.
+fn forwarded_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    let forwarded = headers.get("X-Forwarded-For")?.to_str().ok()?;
+    forwarded.rsplit(',').next()?.trim().parse().ok()
+}
+
+// The number of rate limiter tokens spent by a request, by how much work it may take
+fn request_cost(path: &str) -> u32 {
+    let path: Vec<&str> = path.split('/').skip(1).collect();
+    match path.as_slice() {
+        ["blocks", "tip", ..] => COST_DEFAULT,
+        ["addresses", "txs"]
+        | ["address", _, "txs", ..]
+        | ["scripthash", _, "txs", ..]
+        | ["color", _, "txs", ..]
+        | ["color", _, "holders"]
+        | ["oa", "asset", ..] => COST_HISTORY,
+        ["xpub", ..] => COST_XPUB,
+        ["addresses", _]
+        | ["address", _, ..]
+        | ["scripthash", _, ..]
+        | ["color", _, ..]
+        | ["address-prefix", _] => COST_SCRIPT,
+        ["blocks", ..] | ["block", _, "txs", ..] | ["mempool", ..] | ["colors", ..] => COST_LIST,
+        _ => COST_DEFAULT,
+    }
+}
+
+// The number of addresses and scripthashes of a batch request, counting invalid requests as one
+fn batch_size(body: &[u8]) -> u32 {
+    serde_json::from_slice::<BatchRequest>(body).map_or(1, |request| {
+        request.len().clamp(1, u32::MAX as usize) as u32
+    })
+}
+
+// Stream the events the client subscribed to as server-sent events, until the client
+// disconnects or the server shuts down
+fn handle_events(
+    uri: &hyper::Uri,
+    config: &Config,
+    receiver: broadcast::Receiver<Arc<Event>>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<Response<Body>, HttpError> {
+    let query_params = match uri.query() {
+        Some(value) => form_urlencoded::parse(&value.as_bytes())
+            .into_owned()
+            .collect::<HashMap<String, String>>(),
+        None => HashMap::new(),
+    };
+    info!("handle events {:?}", uri);
+
+    let subscription = EventSubscription::from_params(&query_params, config.network)?;
+    let (sender, body) = Body::channel();
+    tokio::spawn(stream_events(subscription, receiver, shutdown, sender));
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap())
+}
+
+async fn stream_events(
+    subscription: EventSubscription,
+    mut receiver: broadcast::Receiver<Arc<Event>>,
+    mut shutdown: watch::Receiver<bool>,
+    mut sender: hyper::body::Sender,
+) {
+    loop {
+        let message = tokio::select! {
+            _ = shutdown.changed() => break,
+            received = time::timeout(EVENTS_KEEPALIVE, receiver.recv()) => match received {
+                // keep idle connections open through proxies, and notice disconnected clients
+                Err(_) => ":keepalive\n\n".to_string(),
+                Ok(Ok(event)) => match subscription.format(&event) {
+                    Some(message) => message,
+                    None => continue,
+                },
+                Ok(Err(RecvError::Lagged(skipped))) => {
+                    format!("event: lagged\ndata: {}\n\n", json!({ "skipped": skipped }))
+                }
+                Ok(Err(RecvError::Closed)) => break,
+            },
+        };
+        if sender.send_data(message.into()).await.is_err() {
+            break; // the client disconnected
+        }
+    }
+}
+
+// The events requested by a GET /events client
+struct EventSubscription {
+    blocks: bool,
+    scripthashes: HashSet<FullHash>,
+    color_ids: HashSet<ColorIdentifier>,
+    txids: HashSet<Txid>,
+}
+
+impl EventSubscription {
+    fn from_params(
+        query_params: &HashMap<String, String>,
+        network: Network,
+    ) -> Result<Self, HttpError> {
+        let list = |name: &str| {
+            query_params.get(name).map_or(vec![], |value: &String| {
+                value
+                    .split(',')
+                    .filter(|item| !item.is_empty())
+                    .collect::<Vec<&str>>()
+            })
+        };
+
+        let mut scripthashes = HashSet::new();
+        for addr in list("address") {
+            scripthashes.insert(address_to_scripthash(addr, network)?);
+        }
+        for scripthash in list("scripthash") {
+            scripthashes.insert(parse_scripthash(scripthash)?);
+        }
+        let color_ids = list("color_id")
+            .into_iter()
+            .map(|color_id| {
+                ColorIdentifier::from_hex(color_id)
+                    .map_err(|_| HttpError::from("Invalid color id".to_string()))
+            })
+            .collect::<Result<HashSet<_>, _>>()?;
+        let txids = list("txid")
+            .into_iter()
+            .map(Txid::from_hex)
+            .collect::<Result<HashSet<_>, _>>()?;
+        let blocks = query_params
+            .get("blocks")
+            .map_or(false, |value| value == "true" || value == "1");
+
+        let count = scripthashes.len() + color_ids.len() + txids.len();
+        if !blocks && count == 0 {
+            bail!(HttpError::from("No events subscribed".to_string()))
+        }
+        if count > MAX_EVENT_SUBSCRIPTIONS {
+            bail!(HttpError::from(format!(
+                "Too many subscriptions (max {})",
+                MAX_EVENT_SUBSCRIPTIONS
+            )))
+        }
+
+        Ok(EventSubscription {
+            blocks,
+            scripthashes,
+            color_ids,
+            txids,
+        })
+    }
+
+    // Format the event as a server-sent event, if it matches the subscription
+    fn format(&self, event: &Event) -> Option<String> {
+        let (name, data) = match event {
+            Event::Block(blockid) if self.blocks => (
+                "block",
+                json!({
+                    "id": blockid.hash,
+                    "height": blockid.height,
+                    "timestamp": blockid.time,
+                }),
+            ),
+            Event::Tx(tx) => {
+                let scripthashes: Vec<String> = tx
+                    .scripthashes
+                    .intersection(&self.scripthashes)
+                    .map(hex::encode)
+                    .collect();
+                let color_ids: Vec<&ColorIdentifier> =
+                    tx.color_ids.intersection(&self.color_ids).collect();
+                if scripthashes.is_empty() && color_ids.is_empty() && !self.txids.contains(&tx.txid)
+                {
+                    return None;
+                }
+                (
+                    "tx",
+                    json!({
+                        "txid": tx.txid,
+                        "status": TransactionStatus::from(tx.confirmed.clone()),
+                        "scripthashes": scripthashes,
+                        "color_ids": color_ids,
+                    }),
+                )
+            }
+            _ => return None,
+        };
+        Some(format!("event: {}\ndata: {}\n\n", name, data))
+    }
+}
+
+#[derive(Deserialize)]
+struct WebhookRequest {
+    url: String,
+    #[serde(default)]
+    addresses: Vec<String>,
+    #[serde(default)]
+    scripthashes: Vec<String>,
+    #[serde(default)]
+    color_ids: Vec<String>,
+    confirmations: Option<u32>,
+}
+
+// The addresses and scripthashes of a batch request, whose results are returned in this order
+#[derive(Deserialize)]
+struct BatchRequest {
+    #[serde(default)]
+    addresses: Vec<String>,
+    #[serde(default)]
+    scripthashes: Vec<String>,
+    // for txs, the last txid returned for an address or scripthash by a previous truncated batch
+    #[serde(default)]
+    last_seen_txids: HashMap<String, String>,
+}
+
+impl BatchRequest {
+    fn len(&self) -> usize {
+        self.addresses.len() + self.scripthashes.len()
+    }
+
+    // The script type and string of each entry, as in the single address routes, along with its
+    // scripthash
+    fn scripts(&self, network: Network) -> Result<Vec<(&'static str, &str, FullHash)>, HttpError> {
+        let addresses = self.addresses.iter().map(|addr| ("address", addr));
+        let scripthashes = self.scripthashes.iter().map(|hash| ("scripthash", hash));
+        addresses
+            .chain(scripthashes)
+            .map(|(script_type, script_str)| {
+                let script_hash = to_scripthash(script_type, script_str, network)?;
+                Ok((script_type, script_str.as_str(), script_hash))
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookValue {
+    id: u64,
+    url: String,
+    scripthashes: Vec<String>,
+    color_ids: Vec<ColorIdentifier>,
+    confirmations: u32,
+}
+
+impl From<&Webhook> for WebhookValue {
+    fn from(webhook: &Webhook) -> Self {
+        let mut scripthashes: Vec<String> = webhook.scripthashes.iter().map(hex::encode).collect();
+        scripthashes.sort();
+        let mut color_ids: Vec<ColorIdentifier> = webhook.color_ids.iter().cloned().collect();
+        color_ids.sort_by_key(serialize_color_id);
+        WebhookValue {
+            id: webhook.id,
+            url: webhook.url.clone(),
+            scripthashes,
+            color_ids,
+            confirmations: webhook.confirmations,
+        }
+    }
+}
+
+fn admin_disabled() -> HttpError {
+    HttpError::not_found("The admin API requires --api-keys-file".to_string())
+}
+
+fn webhooks_disabled() -> HttpError {
+    HttpError::not_found("Webhooks are disabled".to_string())
+}
+
 fn handle_request(
     method: Method,
     uri: hyper::Uri,
     body: hyper::body::Bytes,
     query: &Query,
     config: &Config,
+    webhooks: Option<&WebhookRegistry>,
+    admin: Option<&Admin>,
 ) -> Result<Response<Body>, HttpError> {
     // TODO it looks hyper does not have routing and query parsing :(
     let path: Vec<&str> = uri.path().split('/').skip(1).collect();
@@ -481,10 +1124,102 @@ fn handle_request(
             TTL_SHORT,
         ),
 
+        (&Method::GET, Some(&"blocks"), Some(&"fill"), None, None, None) => {
+            let best_height = query.chain().best_height();
+            let to = match query_params.get("to") {
+                Some(to) => to.parse::<usize>()?.min(best_height),
+                None => best_height,
+            };
+            let from = match query_params.get("from") {
+                Some(from) => from.parse::<usize>()?,
+                None => (to + 1).saturating_sub(BLOCK_FILL_DEFAULT_RANGE),
+            };
+            if from > to {
+                return Err(HttpError::from("Invalid block range".to_string()));
+            }
+            if to - from >= BLOCK_FILL_MAX_RANGE {
+                return Err(HttpError::from(format!(
+                    "Block range too large (max {} blocks)",
+                    BLOCK_FILL_MAX_RANGE
+                )));
+            }
+
+            let max_block_sizes = query.chain().max_block_sizes(from, to);
+            let mut blocks = vec![];
+            let (mut total_size, mut total_max_size) = (0u64, 0u64);
+            for (height, max_block_size) in (from..=to).zip(max_block_sizes) {
+                let header = query
+                    .chain()
+                    .header_by_height(height)
+                    .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                let meta = query
+                    .chain()
+                    .get_block_meta(header.hash())
+                    .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                total_size += meta.size as u64;
+                total_max_size += max_block_size as u64;
+                blocks.push(json!({
+                    "height": height,
+                    "id": header.hash(),
+                    "size": meta.size,
+                    "max_block_size": max_block_size,
+                    "fill_ratio": fill_ratio(meta.size, max_block_size),
+                }));
+            }
+            json_response(
+                json!({
+                    "from": from,
+                    "to": to,
+                    "total_size": total_size,
+                    "total_max_block_size": total_max_size,
+                    "fill_ratio": if total_max_size > 0 {
+                        total_size as f64 / total_max_size as f64
+                    } else {
+                        0.0
+                    },
+                    "blocks": blocks,
+                }),
+                ttl_by_depth(Some(to), query),
+            )
+        }
         (&Method::GET, Some(&"blocks"), start_height, None, None, None) => {
             let start_height = start_height.and_then(|height| height.parse::<usize>().ok());
             blocks(&query, &config, start_height)
         }
+        (&Method::GET, Some(&"federation"), Some(&"keys"), None, None, None) => {
+            let keys: Vec<FederationKeyValue> = query
+                .chain()
+                .xfield_changes()
+                .iter()
+                .filter_map(FederationKeyValue::new)
+                .collect();
+            json_response(keys, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"federation"), Some(&"key-at"), Some(height), None, None) => {
+            let height = height.parse::<usize>()?;
+            if height > query.chain().best_height() {
+                return Err(HttpError::not_found("Block not found".to_string()));
+            }
+            let key = query
+                .chain()
+                .aggregate_pubkey_at(height)
+                .as_ref()
+                .and_then(FederationKeyValue::new)
+                .ok_or_else(|| {
+                    HttpError::not_found("Aggregate public key not found".to_string())
+                })?;
+            json_response(
+                json!({
+                    "height": height,
+                    "aggregate_pubkey": key.aggregate_pubkey,
+                    "set_at": {
+                        "height": key.height,
+                        "block_hash": key.block_hash,
+                    },
+                }),
+                ttl_by_depth(Some(height), query),
+            )
+        }
         (&Method::GET, Some(&"block-height"), Some(height), None, None, None) => {
             let height = height.parse::<usize>()?;
             let header = query
@@ -500,7 +1235,10 @@ fn handle_request(
                 .chain()
                 .get_block_with_meta(&hash)
                 .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
-            let block_value = BlockValue::new(blockhm, config.network);
+            let max_block_size = query
+                .chain()
+                .max_block_size_at(blockhm.header_entry.height());
+            let block_value = BlockValue::new(blockhm, max_block_size, config.network);
             json_response(block_value, TTL_LONG)
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"status"), None, None) => {
@@ -509,6 +1247,32 @@ fn handle_request(
             let ttl = ttl_by_depth(status.height, query);
             json_response(status, ttl)
         }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"proof"), None, None) => {
+            let hash = BlockHash::from_hex(hash)?;
+            let header_entry = query
+                .chain()
+                .height_by_hash(&hash)
+                .and_then(|height| query.chain().header_by_height(height))
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            let header = header_entry.header();
+            let pubkey = query
+                .chain()
+                .aggregate_pubkey_at(header_entry.height())
+                .and_then(|change| change.aggregate_pubkey().cloned());
+            let valid = pubkey
+                .as_ref()
+                .map_or(false, |pubkey| verify_block_proof(header, pubkey));
+            json_response(
+                json!({
+                    "block_hash": hash,
+                    "height": header_entry.height(),
+                    "signature": header.proof.map(|p| encode::serialize_hex(&p)),
+                    "aggregate_pubkey": pubkey.map(|pubkey| pubkey.to_string()),
+                    "valid": valid,
+                }),
+                ttl_by_depth(Some(header_entry.height()), query),
+            )
+        }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"txids"), None, None) => {
             let hash = BlockHash::from_hex(hash)?;
             let txids = query
@@ -730,6 +1494,150 @@ fn handle_request(
             // XXX paging?
             json_response(utxos, TTL_SHORT)
         }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"nfts"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"nfts"),
+            None,
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network)?;
+            let nfts: Vec<NftValue> = query
+                .nfts(&script_hash[..])
+                .into_iter()
+                .map(|output| NftValue::new(output, config))
+                .collect();
+            json_response(nfts, TTL_SHORT)
+        }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"oa"),
+            Some(&"utxo"),
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"oa"),
+            Some(&"utxo"),
+            None,
+        ) => {
+            if !config.enable_open_assets {
+                return Err(HttpError::from("open assets disabled".to_string()));
+            }
+            let script_hash = to_scripthash(script_type, script_str, config.network)?;
+            let utxos = query.utxo(&script_hash[..])?;
+            let mut assets = query.open_assets(&utxos)?;
+            let utxos: Vec<OpenAssetUtxoValue> = utxos
+                .into_iter()
+                .map(|utxo| OpenAssetUtxoValue {
+                    asset: assets.remove(&OutPoint::new(utxo.txid, utxo.vout)),
+                    utxo: UtxoValue::from(utxo),
+                })
+                .collect();
+            json_response(utxos, TTL_SHORT)
+        }
+        (&Method::POST, Some(&"addresses"), Some(batch_type), None, None, None) => {
+            handle_address_batch(batch_type, &body, query, config)
+        }
+        (&Method::GET, Some(&"xpub"), Some(xpub_str), None, None, None) => {
+            let xpub::Scan {
+                addresses,
+                truncated,
+            } = scan_xpub(xpub_str, &query_params, query, config)?;
+            // the next unused index of each chain
+            let next_index = |chain| {
+                addresses
+                    .iter()
+                    .filter(|address| address.chain == chain)
+                    .map(|address| address.index + 1)
+                    .max()
+                    .unwrap_or(0)
+            };
+            let values: Vec<serde_json::Value> = addresses
+                .iter()
+                .map(|address| {
+                    json!({
+                        "address": script_to_address(&address.script, config.network),
+                        "chain": address.chain,
+                        "index": address.index,
+                        "chain_stats": address.stats.0,
+                        "mempool_stats": address.stats.1,
+                    })
+                })
+                .collect();
+            let chain_stats = xpub::total_stats(addresses.iter().map(|address| &address.stats.0));
+            let mempool_stats = xpub::total_stats(addresses.iter().map(|address| &address.stats.1));
+            json_response(
+                json!({
+                    "xpub": xpub_str,
+                    "chain_stats": chain_stats,
+                    "mempool_stats": mempool_stats,
+                    "receive_index": next_index(0),
+                    "change_index": next_index(1),
+                    "addresses": values,
+                    "truncated": truncated,
+                }),
+                TTL_SHORT,
+            )
+        }
+        (&Method::GET, Some(&"xpub"), Some(xpub_str), Some(&"utxo"), None, None) => {
+            let addresses = scan_xpub(xpub_str, &query_params, query, config)?.addresses;
+            let utxos = addresses
+                .par_iter()
+                .map(|address| {
+                    let utxos: Vec<XpubUtxoValue> = query
+                        .utxo(&address.scripthash[..])?
+                        .into_iter()
+                        .map(|utxo| XpubUtxoValue::new(utxo, address, config))
+                        .collect();
+                    Ok(utxos)
+                })
+                .collect::<Result<Vec<_>, HttpError>>()?;
+            json_response(utxos.into_iter().flatten().collect::<Vec<_>>(), TTL_SHORT)
+        }
+        (&Method::GET, Some(&"xpub"), Some(xpub_str), Some(&"txs"), None, None) => {
+            let addresses = scan_xpub(xpub_str, &query_params, query, config)?.addresses;
+            // transactions between the addresses of the wallet appear in several histories
+            let txids: HashMap<Txid, Option<BlockId>> = addresses
+                .par_iter()
+                .map(|address| query.history_txids(&address.scripthash[..], MAX_BATCH_TXS + 1))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .flatten()
+                .collect();
+            if txids.len() > MAX_BATCH_TXS {
+                bail!(HttpError::from(format!(
+                    "Too many transactions, at most {} are returned per xpub",
+                    MAX_BATCH_TXS
+                )))
+            }
+            let mut txids: Vec<(Txid, Option<BlockId>)> = txids.into_iter().collect();
+            // confirmed first, sorted with oldest first, then unconfirmed
+            txids.sort_by_key(|(txid, blockid)| {
+                let height = blockid.as_ref().map_or(usize::MAX, |b| b.height);
+                (height, *txid)
+            });
+            let txs: Vec<serde_json::Value> = txids
+                .into_iter()
+                .map(|(txid, blockid)| {
+                    json!({ "txid": txid, "status": TransactionStatus::from(blockid) })
+                })
+                .collect();
+            json_response(txs, TTL_SHORT)
+        }
         (&Method::GET, Some(&"address-prefix"), Some(prefix), None, None, None) => {
             if !config.address_search {
                 return Err(HttpError::from("address search disabled".to_string()));
@@ -770,6 +1678,18 @@ fn handle_request(
                 .body(body)
                 .unwrap())
         }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"oa"), None, None) => {
+            if !config.enable_open_assets {
+                return Err(HttpError::from("open assets disabled".to_string()));
+            }
+            let hash = Txid::from_hex(hash)?;
+            let tx = query
+                .lookup_txn(&hash)
+                .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
+            let assets = query.load_assets(&tx)?;
+            let ttl = ttl_by_depth(query.get_tx_status(&hash).block_height, query);
+            json_response(assets, ttl)
+        }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"status"), None, None) => {
             let hash = Txid::from_hex(hash)?;
             let status = query.get_tx_status(&hash);
@@ -912,12 +1832,18 @@ fn handle_request(
             let color_id: Option<ColorIdentifier> = last_seen_color_id.map_or(None,|hex| {
                 ColorIdentifier::from_hex(hex).ok()
             });
-            let colors: Vec<(ColorIdentifier, u32)> = query.get_colors(color_id, COLOR_IDS_PER_PAGE);
+            let token_type = query_params
+                .get("token_type")
+                .map(|token_type| TokenType::from_str(token_type))
+                .transpose()?;
+            let colors: Vec<(ColorIdentifier, u32)> =
+                query.get_colors(color_id, token_type, COLOR_IDS_PER_PAGE);
             info!("colors: {:?}", colors);
             let values: Vec<_> = colors.into_iter().map(|(color_id, height)| {
                 let stats = query.get_colored_stats(&color_id);
                 json!({
                     "color_id": color_id,
+                    "token_type": TokenType::of(&color_id),
                     "height": height,
                     "chain_stats": ColoredStatsValue::from(stats.0),
                     "mempool_stats": ColoredStatsValue::from(stats.1)
@@ -927,10 +1853,25 @@ fn handle_request(
         }
 
         (&Method::GET, Some(&"color"), Some(color_id), None, None, None) => {
-            let color_id = ColorIdentifier::from_hex(color_id).unwrap();
+            let color_id = ColorIdentifier::from_hex(color_id)
+                .map_err(|_| HttpError::from("Invalid color id".to_string()))?;
             let stats = query.get_colored_stats(&color_id);
+            let (first_issuance, issuer) = match query.get_color_issuance(&color_id) {
+                Some((txid, blockid, issuer)) => (
+                    Some(json!({
+                        "txid": txid,
+                        "status": TransactionStatus::from(blockid),
+                    })),
+                    issuer.map(|issuer| color_issuer_value(issuer, config)),
+                ),
+                None => (None, None),
+            };
             json_response(
                 json!({
+                    "color_id": color_id,
+                    "token_type": TokenType::of(&color_id),
+                    "issuer": issuer,
+                    "first_issuance": first_issuance,
                     "chain_stats": ColoredStatsValue::from(stats.0),
                     "mempool_stats": ColoredStatsValue::from(stats.1),
                 }),
@@ -956,10 +1897,185 @@ fn handle_request(
             json_response(prepare_txs(txs, query, config), TTL_SHORT)
         }
 
+        (&Method::GET, Some(&"color"), Some(color_id), Some(&"supply"), None, None) => {
+            let color_id = ColorIdentifier::from_hex(color_id)
+                .map_err(|_| HttpError::from("Invalid color id".to_string()))?;
+            let height = match query_params.get("height") {
+                Some(height) => height.parse::<usize>()?,
+                None => query.chain().best_height(),
+            };
+            if height > query.chain().best_height() {
+                return Err(HttpError::not_found("Block not found".to_string()));
+            }
+            let supply = query.chain().get_colored_supply(&color_id, height)?;
+            json_response(
+                json!({
+                    "color_id": color_id,
+                    "height": height,
+                    "issued_sum": supply.issued_sum,
+                    "burned_sum": supply.burned_sum,
+                    "circulating_sum": supply.circulating_sum(),
+                    "holder_count": supply.holders.len(),
+                }),
+                ttl_by_depth(Some(height), query),
+            )
+        }
+        (&Method::GET, Some(&"color"), Some(color_id), Some(&"holders"), None, None) => {
+            let color_id = ColorIdentifier::from_hex(color_id)
+                .map_err(|_| HttpError::from("Invalid color id".to_string()))?;
+            let limit = query_params
+                .get("limit")
+                .map_or(HOLDERS_PER_PAGE, |el| {
+                    el.parse().unwrap_or(HOLDERS_PER_PAGE)
+                })
+                .min(MAX_HOLDERS_PER_PAGE);
+            let after = query_params
+                .get("after")
+                .map(|scripthash| parse_scripthash(scripthash))
+                .transpose()?;
+            let holders: Vec<HolderValue> = query
+                .get_colored_holders(&color_id, after.as_ref(), limit)
+                .into_iter()
+                .map(|holder| HolderValue::new(holder, config))
+                .collect();
+            json_response(holders, TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"color"), Some(color_id), Some(&"owner"), None, None) => {
+            let color_id = ColorIdentifier::from_hex(color_id)
+                .map_err(|_| HttpError::from("Invalid color id".to_string()))?;
+            if TokenType::of(&color_id) != Some(TokenType::Nft) {
+                return Err(HttpError::from("Not an NFT color id".to_string()));
+            }
+            let owner = query
+                .get_nft_owner(&color_id)
+                .ok_or_else(|| HttpError::not_found("NFT not found or burned".to_string()))?;
+            json_response(NftValue::new(owner, config), TTL_SHORT)
+        }
+
+        (&Method::GET, Some(&"oa"), Some(&"asset"), Some(asset_id), None, None) => {
+            if !config.enable_open_assets {
+                return Err(HttpError::from("open assets disabled".to_string()));
+            }
+            let asset_id = parse_asset_id(asset_id, config.network)?;
+            let stats = query.chain().asset_stats(&asset_id);
+            let issuances: Vec<serde_json::Value> = stats
+                .issuances
+                .iter()
+                .map(|(txid, height, quantity)| {
+                    json!({
+                        "txid": txid,
+                        "block_height": height,
+                        "asset_quantity": quantity,
+                    })
+                })
+                .collect();
+            json_response(
+                json!({
+                    "asset_id": asset_id.to_string(),
+                    "script_hash160": asset_id.hash.to_hex(),
+                    "issuances": issuances,
+                    "issued_sum": stats.issued_sum,
+                    "tx_count": stats.tx_count,
+                    "utxo_count": stats.utxo_count,
+                    "unspent_sum": stats.unspent_sum,
+                    "holder_count": stats.holder_count,
+                }),
+                TTL_SHORT,
+            )
+        }
+        (
+            &Method::GET,
+            Some(&"oa"),
+            Some(&"asset"),
+            Some(asset_id),
+            Some(&"txs"),
+            last_seen_txid,
+        ) => {
+            if !config.enable_open_assets {
+                return Err(HttpError::from("open assets disabled".to_string()));
+            }
+            let asset_id = parse_asset_id(asset_id, config.network)?;
+            let last_seen_txid = last_seen_txid.and_then(|txid| Txid::from_hex(txid).ok());
+            let txs = query
+                .chain()
+                .asset_history(&asset_id, last_seen_txid.as_ref(), CHAIN_TXS_PER_PAGE)
+                .into_iter()
+                .map(|(tx, blockid)| (tx, Some(blockid)))
+                .collect();
+            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+        }
+
         (&Method::GET, Some(&"fee-estimates"), None, None, None, None) => {
             json_response(query.estimate_fee_map(), TTL_SHORT)
         }
 
+        (&Method::GET, Some(&"admin"), Some(&"mempool"), None, None, None) => {
+            let admin = admin.ok_or_else(admin_disabled)?;
+            json_response(admin.mempool_stats()?, 0)
+        }
+
+        (&Method::POST, Some(&"admin"), Some(&"compact"), None, None, None) => {
+            let admin = admin.ok_or_else(admin_disabled)?;
+            admin.compact()?;
+            http_message(StatusCode::ACCEPTED, "Compaction started", 0)
+        }
+
+        (&Method::POST, Some(&"admin"), Some(&"precache"), None, None, None) => {
+            let admin = admin.ok_or_else(admin_disabled)?;
+            let count = admin.reload_precache()?;
+            json_response(json!({ "scripts": count }), 0)
+        }
+
+        (&Method::GET, Some(&"admin"), Some(&"webhooks"), None, None, None) => {
+            admin.ok_or_else(admin_disabled)?;
+            let registry = webhooks.ok_or_else(webhooks_disabled)?;
+            let values: Vec<WebhookValue> =
+                registry.list().iter().map(WebhookValue::from).collect();
+            json_response(values, 0)
+        }
+        (&Method::POST, Some(&"admin"), Some(&"webhooks"), None, None, None) => {
+            admin.ok_or_else(admin_disabled)?;
+            let registry = webhooks.ok_or_else(webhooks_disabled)?;
+            let request: WebhookRequest = serde_json::from_slice(&body)?;
+            let mut scripthashes = HashSet::new();
+            for addr in &request.addresses {
+                scripthashes.insert(address_to_scripthash(addr, config.network)?);
+            }
+            for scripthash in &request.scripthashes {
+                scripthashes.insert(parse_scripthash(scripthash)?);
+            }
+            let color_ids = request
+                .color_ids
+                .iter()
+                .map(|color_id| {
+                    ColorIdentifier::from_hex(color_id)
+                        .map_err(|_| HttpError::from("Invalid color id".to_string()))
+                })
+                .collect::<Result<HashSet<_>, _>>()?;
+            let confirmations = request
+                .confirmations
+                .unwrap_or(WEBHOOK_DEFAULT_CONFIRMATIONS);
+            let webhook = registry.add(&request.url, scripthashes, color_ids, confirmations)?;
+            json_response(WebhookValue::from(&webhook), 0)
+        }
+        (&Method::GET, Some(&"admin"), Some(&"webhooks"), Some(id), None, None) => {
+            admin.ok_or_else(admin_disabled)?;
+            let registry = webhooks.ok_or_else(webhooks_disabled)?;
+            let webhook = registry
+                .get(id.parse()?)
+                .ok_or_else(|| HttpError::not_found("Webhook not found".to_string()))?;
+            json_response(WebhookValue::from(&webhook), 0)
+        }
+        (&Method::DELETE, Some(&"admin"), Some(&"webhooks"), Some(id), None, None) => {
+            admin.ok_or_else(admin_disabled)?;
+            let registry = webhooks.ok_or_else(webhooks_disabled)?;
+            if !registry.remove(id.parse()?) {
+                return Err(HttpError::not_found("Webhook not found".to_string()));
+            }
+            http_message(StatusCode::OK, "", 0)
+        }
+
         _ => Err(HttpError::not_found(format!(
             "endpoint does not exist {:?}",
             uri.path()
@@ -1011,8 +2127,11 @@ fn blocks(
             .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
         current_hash = blockhm.header_entry.header().prev_blockhash;
 
+        let max_block_size = query
+            .chain()
+            .max_block_size_at(blockhm.header_entry.height());
         #[allow(unused_mut)]
-        let mut value = BlockValue::new(blockhm, config.network);
+        let mut value = BlockValue::new(blockhm, max_block_size, config.network);
 
         values.push(value);
 
@@ -1023,6 +2142,135 @@ fn blocks(
     json_response(values, TTL_SHORT)
 }
 
+// Query the stats, utxos or history of many addresses at once, in parallel
+fn handle_address_batch(
+    batch_type: &str,
+    body: &[u8],
+    query: &Query,
+    config: &Config,
+) -> Result<Response<Body>, HttpError> {
+    let request: BatchRequest = serde_json::from_slice(body)?;
+    if request.len() > config.rest_batch_limit {
+        bail!(HttpError::from(format!(
+            "Too many addresses, at most {} are allowed per batch",
+            config.rest_batch_limit
+        )))
+    }
+    let scripts = request.scripts(config.network)?;
+
+    let results: Vec<serde_json::Value> = match batch_type {
+        "stats" => scripts
+            .par_iter()
+            .map(|(script_type, script_str, script_hash)| {
+                let stats = query.stats(&script_hash[..]);
+                json!({
+                    *script_type: script_str,
+                    "chain_stats": stats.0,
+                    "mempool_stats": stats.1,
+                })
+            })
+            .collect(),
+        "utxo" => {
+            let utxos: Vec<Vec<UtxoValue>> = scripts
+                .par_iter()
+                .map(|(_, _, script_hash)| {
+                    let utxos: Vec<UtxoValue> = query
+                        .utxo(&script_hash[..])?
+                        .into_iter()
+                        .map(UtxoValue::from)
+                        .collect();
+                    Ok(utxos)
+                })
+                .collect::<Result<_, HttpError>>()?;
+            // the limit for a single address also applies to the whole batch
+            let total: usize = utxos.iter().map(Vec::len).sum();
+            if total > config.utxos_limit {
+                bail!(HttpError::from(format!(
+                    "Too many utxos, at most {} are returned per batch",
+                    config.utxos_limit
+                )))
+            }
+            scripts
+                .iter()
+                .zip(utxos)
+                .map(|((script_type, script_str, _), utxos)| {
+                    json!({ *script_type: script_str, "utxo": utxos })
+                })
+                .collect()
+        }
+        "txs" => {
+            let last_seen_txids = scripts
+                .iter()
+                .map(|(_, script_str, _)| {
+                    request
+                        .last_seen_txids
+                        .get(*script_str)
+                        .map(|txid| Txid::from_hex(txid))
+                        .transpose()
+                })
+                .collect::<Result<Vec<Option<Txid>>, _>>()?;
+            // the batch limit is shared by its addresses, each history is truncated to its share
+            let limit = (MAX_BATCH_TXS / scripts.len().max(1)).max(1);
+            let histories: Vec<Vec<(Txid, Option<BlockId>)>> = scripts
+                .par_iter()
+                .zip(last_seen_txids)
+                .map(|((_, _, script_hash), last_seen_txid)| {
+                    query.history_txids_after(&script_hash[..], last_seen_txid.as_ref(), limit + 1)
+                })
+                .collect();
+            scripts
+                .iter()
+                .zip(histories)
+                .map(|((script_type, script_str, _), mut txids)| {
+                    let truncated = txids.len() > limit;
+                    txids.truncate(limit);
+                    // the cursor to pass in last_seen_txids for the rest of the history
+                    let last_seen_txid = txids.last().filter(|_| truncated).map(|(txid, _)| *txid);
+                    let txs: Vec<serde_json::Value> = txids
+                        .into_iter()
+                        .map(|(txid, blockid)| {
+                            json!({ "txid": txid, "status": TransactionStatus::from(blockid) })
+                        })
+                        .collect();
+                    json!({ *script_type: script_str, "txs": txs, "last_seen_txid": last_seen_txid })
+                })
+                .collect()
+        }
+        _ => bail!(HttpError::not_found(format!(
+            "Unknown batch query {}",
+            batch_type
+        ))),
+    };
+    json_response(results, TTL_SHORT)
+}
+
+// The addresses of the xpub with a history, scanned with the gap limit given by the `gap_limit`
+// query parameter
+fn scan_xpub(
+    xpub_str: &str,
+    query_params: &HashMap<String, String>,
+    query: &Query,
+    config: &Config,
+) -> Result<xpub::Scan, HttpError> {
+    let extended_key = parse_xpub(xpub_str, config.network)?;
+    let gap_limit = parse_gap_limit(query_params)?;
+    Ok(xpub::scan(query, &extended_key, gap_limit)?)
+}
+
+fn parse_gap_limit(query_params: &HashMap<String, String>) -> Result<u32, HttpError> {
+    let gap_limit = match query_params.get("gap_limit") {
+        Some(gap_limit) => gap_limit.parse::<u32>()?,
+        None => XPUB_GAP_LIMIT,
+    };
+    if gap_limit == 0 || gap_limit > MAX_XPUB_GAP_LIMIT {
+        bail!(HttpError::from(format!(
+            "Invalid gap_limit (must be between 1 and {})",
+            MAX_XPUB_GAP_LIMIT
+        )))
+    }
+    Ok(gap_limit)
+}
+
 fn to_scripthash(
     script_type: &str,
     script_str: &str,
@@ -1056,6 +2304,24 @@ fn parse_scripthash(scripthash: &str) -> Result<FullHash, HttpError> {
     }
 }
 
+fn parse_xpub(xpub_str: &str, network: Network) -> Result<ExtendedPubKey, HttpError> {
+    let extended_key = ExtendedPubKey::from_str(xpub_str)
+        .map_err(|_| HttpError::from("Invalid xpub".to_string()))?;
+    if NetworkType::from(extended_key.network) != network.network_type {
+        bail!(HttpError::from("xpub on invalid network".to_string()))
+    }
+    Ok(extended_key)
+}
+
+fn parse_asset_id(asset_id: &str, network: Network) -> Result<AssetId, HttpError> {
+    let asset_id =
+        AssetId::from_str(asset_id).map_err(|_| HttpError::from("Invalid asset id".to_string()))?;
+    if NetworkType::from(asset_id.network) != network.network_type {
+        bail!(HttpError::from("Asset id on invalid network".to_string()))
+    }
+    Ok(asset_id)
+}
+
 #[derive(Debug)]
 struct HttpError(StatusCode, String);
 
@@ -1129,9 +2395,53 @@ impl From<std::string::FromUtf8Error> for HttpError {
 
 #[cfg(test)]
 mod tests {
-    use crate::rest::HttpError;
+    use crate::chain::{Network, TxOut};
+    use crate::new_index::compute_script_hash;
+    use crate::new_index::events::{Event, TxEvent};
+    use crate::new_index::federation::DEFAULT_MAX_BLOCK_SIZE;
+    use crate::new_index::test_helper::{self, TestIndex};
+    use crate::rate_limit::{COST_DEFAULT, COST_HISTORY, COST_LIST, COST_SCRIPT, COST_XPUB};
+    use crate::rest::{
+        batch_size, client_ip, forwarded_ip, handle_request, parse_asset_id, parse_gap_limit,
+        read_body, request_cost, EventSubscription, HttpError, MAX_BODY_SIZE, XPUB_GAP_LIMIT,
+    };
+    use crate::util::HeaderList;
+    use hyper::{Body, HeaderMap, Method, StatusCode};
     use serde_json::Value;
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
+    use tapyrus::blockdata::block::XField;
+    use tapyrus::consensus::encode;
+    use tapyrus::{Address, BlockHash, Txid};
+
+    // Handle a request to the REST API of the test index, returning its JSON response
+    fn request(
+        index: &TestIndex,
+        method: Method,
+        path: &str,
+        body: &str,
+    ) -> Result<Value, HttpError> {
+        let response = handle_request(
+            method,
+            path.parse().unwrap(),
+            hyper::body::Bytes::from(body.to_string()),
+            &index.query,
+            &index.config,
+            None,
+            None,
+        )?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let body = rt
+            .block_on(hyper::body::to_bytes(response.into_body()))
+            .unwrap();
+        Ok(serde_json::from_slice(&body).unwrap())
+    }
+
+    fn get(index: &TestIndex, path: &str) -> Result<Value, HttpError> {
+        request(index, Method::GET, path, "")
+    }
 
     #[test]
     fn test_parse_query_param() {
@@ -1173,6 +2483,64 @@ mod tests {
         assert_eq!(10, limit);
     }
 
+    #[test]
+    fn test_request_cost() {
+        assert_eq!(request_cost("/blocks/tip/height"), COST_DEFAULT);
+        assert_eq!(request_cost("/tx/abcd/status"), COST_DEFAULT);
+        assert_eq!(request_cost("/blocks/100"), COST_LIST);
+        assert_eq!(request_cost("/block/abcd/txs/25"), COST_LIST);
+        assert_eq!(request_cost("/address/abcd"), COST_SCRIPT);
+        assert_eq!(request_cost("/scripthash/abcd/utxo"), COST_SCRIPT);
+        assert_eq!(request_cost("/address/abcd/txs"), COST_HISTORY);
+        assert_eq!(request_cost("/address/abcd/txs/chain/efgh"), COST_HISTORY);
+        assert_eq!(request_cost("/color/abcd/txs"), COST_HISTORY);
+        assert_eq!(request_cost("/addresses/stats"), COST_SCRIPT);
+        assert_eq!(request_cost("/addresses/txs"), COST_HISTORY);
+        assert_eq!(request_cost("/xpub/abcd/utxo"), COST_XPUB);
+
+        let batch = br#"{"addresses": ["a", "b"], "scripthashes": ["c"]}"#;
+        assert_eq!(batch_size(batch), 3);
+        assert_eq!(batch_size(b"{}"), 1);
+        assert_eq!(batch_size(b"invalid"), 1);
+    }
+
+    #[test]
+    fn test_forwarded_ip() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(forwarded_ip(&headers), None);
+        headers.insert("X-Forwarded-For", "1.1.1.1, 10.0.0.1".parse().unwrap());
+        assert_eq!(forwarded_ip(&headers), Some("10.0.0.1".parse().unwrap()));
+        headers.insert("X-Forwarded-For", "invalid".parse().unwrap());
+        assert_eq!(forwarded_ip(&headers), None);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let remote_ip = Some("192.0.2.1".parse().unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", "10.0.0.1".parse().unwrap());
+        let forwarded = Some("10.0.0.1".parse().unwrap());
+        // the header is only trusted behind a proxy
+        assert_eq!(client_ip(remote_ip, &headers, false), remote_ip);
+        assert_eq!(client_ip(None, &headers, false), None);
+        assert_eq!(client_ip(remote_ip, &headers, true), forwarded);
+        assert_eq!(client_ip(None, &headers, true), forwarded);
+        assert_eq!(client_ip(remote_ip, &HeaderMap::new(), true), remote_ip);
+    }
+
+    #[test]
+    fn test_read_body() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let body = rt.block_on(read_body(Body::from("{}"))).unwrap();
+        assert_eq!(&body[..], b"{}");
+        let err = rt
+            .block_on(read_body(Body::from(vec![0; MAX_BODY_SIZE + 1])))
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
     #[test]
     fn test_parse_value_param() {
         let v: Value = json!({ "confirmations": 10 });
@@ -1194,4 +2562,344 @@ mod tests {
 
         assert!(err.is_err());
     }
+
+    #[test]
+    fn test_parse_gap_limit() {
+        let params = |gap_limit: &str| -> HashMap<String, String> {
+            vec![("gap_limit".to_string(), gap_limit.to_string())]
+                .into_iter()
+                .collect()
+        };
+        assert_eq!(parse_gap_limit(&HashMap::new()).unwrap(), XPUB_GAP_LIMIT);
+        assert_eq!(parse_gap_limit(&params("50")).unwrap(), 50);
+        assert_eq!(parse_gap_limit(&params("100")).unwrap(), 100);
+        for invalid in &["0", "101", "-1", "aaa", ""] {
+            let err = parse_gap_limit(&params(invalid)).unwrap_err();
+            assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_parse_asset_id() {
+        let dev = Network::new("dev", 1);
+        let asset_id = parse_asset_id("oMb2yzA542yQgwn8XtmGefTzBv5NJ2nDjh", dev).unwrap();
+        assert_eq!(asset_id.to_string(), "oMb2yzA542yQgwn8XtmGefTzBv5NJ2nDjh");
+
+        // a valid asset id for the prod network
+        assert!(parse_asset_id("ALn3aK1fSuG27N96UGYB1kUYUpGKRhBuBC", dev).is_err());
+        assert!(parse_asset_id("not an asset id", dev).is_err());
+    }
+
+    #[test]
+    fn test_event_subscription() {
+        let dev = Network::new("dev", 1);
+        let scripthash = [1u8; 32];
+        let mut params = HashMap::new();
+        assert!(EventSubscription::from_params(&params, dev).is_err());
+
+        params.insert("scripthash".to_string(), hex::encode(scripthash));
+        let subscription = EventSubscription::from_params(&params, dev).unwrap();
+        assert!(!subscription.blocks);
+
+        let mut event = TxEvent {
+            txid: Txid::default(),
+            confirmed: None,
+            scripthashes: HashSet::new(),
+            color_ids: HashSet::new(),
+        };
+        assert_eq!(subscription.format(&Event::Tx(event.clone())), None);
+        event.scripthashes.insert(scripthash);
+        let message = subscription.format(&Event::Tx(event)).unwrap();
+        assert!(message.starts_with("event: tx\ndata: "));
+        assert!(message.ends_with("\n\n"));
+
+        params.insert("txid".to_string(), "not a txid".to_string());
+        assert!(EventSubscription::from_params(&params, dev).is_err());
+    }
+
+    #[test]
+    fn test_block_proof() {
+        let mut index = test_helper::index(|_| {});
+        let (key, pubkey) = test_helper::federation_key(1);
+        // the genesis block is signed with the key it sets, block 1 has no proof
+        let blocks = test_helper::blocks_with(
+            &HeaderList::empty(),
+            BlockHash::default(),
+            vec![
+                vec![test_helper::coinbase(0, vec![])],
+                vec![test_helper::coinbase(1, vec![])],
+            ],
+            0,
+            |i, mut header| match i {
+                0 => {
+                    header.xfield = XField::AggregatePublicKey(pubkey);
+                    test_helper::sign(header, &key)
+                }
+                _ => header,
+            },
+        );
+        index.tapyrusd.lock().unwrap().connect(&blocks);
+        index.indexer.update(&index.daemon).unwrap();
+
+        let genesis = blocks[0].entry.hash();
+        let proof = get(&index, &format!("/block/{}/proof", genesis)).unwrap();
+        let signature = blocks[0].block.header.proof.unwrap();
+        assert_eq!(
+            proof,
+            json!({
+                "block_hash": genesis,
+                "height": 0,
+                "signature": encode::serialize_hex(&signature),
+                "aggregate_pubkey": pubkey.to_string(),
+                "valid": true,
+            })
+        );
+
+        let path = format!("/block/{}/proof", blocks[1].entry.hash());
+        let proof = get(&index, &path).unwrap();
+        assert_eq!(proof["height"], 1);
+        assert_eq!(proof["signature"], Value::Null);
+        assert_eq!(proof["aggregate_pubkey"], pubkey.to_string());
+        assert_eq!(proof["valid"], false);
+
+        let path = format!("/block/{}/proof", BlockHash::default());
+        let err = get(&index, &path).unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_federation() {
+        let mut index = test_helper::index(|_| {});
+        let (_, old_pubkey) = test_helper::federation_key(1);
+        let (_, new_pubkey) = test_helper::federation_key(2);
+        // the genesis block sets the first key, block 1 a new one used from block 2
+        let blocks = test_helper::blocks_with(
+            &HeaderList::empty(),
+            BlockHash::default(),
+            (0..3)
+                .map(|height| vec![test_helper::coinbase(height, vec![])])
+                .collect(),
+            0,
+            |i, mut header| {
+                match i {
+                    0 => header.xfield = XField::AggregatePublicKey(old_pubkey),
+                    1 => header.xfield = XField::AggregatePublicKey(new_pubkey),
+                    _ => (),
+                }
+                header
+            },
+        );
+        index.tapyrusd.lock().unwrap().connect(&blocks);
+        index.indexer.update(&index.daemon).unwrap();
+        let (genesis, block1) = (blocks[0].entry.hash(), blocks[1].entry.hash());
+
+        assert_eq!(
+            get(&index, "/federation/keys").unwrap(),
+            json!([
+                {
+                    "height": 0,
+                    "block_hash": genesis,
+                    "aggregate_pubkey": old_pubkey.to_string(),
+                    "activation_height": 0,
+                },
+                {
+                    "height": 1,
+                    "block_hash": block1,
+                    "aggregate_pubkey": new_pubkey.to_string(),
+                    "activation_height": 2,
+                },
+            ])
+        );
+
+        let key = get(&index, "/federation/key-at/1").unwrap();
+        assert_eq!(
+            key,
+            json!({
+                "height": 1,
+                "aggregate_pubkey": old_pubkey.to_string(),
+                "set_at": { "height": 0, "block_hash": genesis },
+            })
+        );
+        let key = get(&index, "/federation/key-at/2").unwrap();
+        assert_eq!(key["aggregate_pubkey"], new_pubkey.to_string());
+        assert_eq!(key["set_at"], json!({ "height": 1, "block_hash": block1 }));
+
+        let err = get(&index, "/federation/key-at/3").unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+        let err = get(&index, "/federation/key-at/tip").unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_block_fill() {
+        let mut index = test_helper::index(|_| {});
+        // block 1 sets a max block size of 1000 bytes, which applies from block 2
+        let blocks = test_helper::blocks_with(
+            &HeaderList::empty(),
+            BlockHash::default(),
+            (0..2018)
+                .map(|height| vec![test_helper::coinbase(height, vec![])])
+                .collect(),
+            0,
+            |i, mut header| {
+                if i == 1 {
+                    header.xfield = XField::MaxBlockSize(1000);
+                }
+                header
+            },
+        );
+        index.tapyrusd.lock().unwrap().connect(&blocks);
+        index.indexer.update(&index.daemon).unwrap();
+        let size = |height: usize| blocks[height].block.get_size() as u64;
+        let default = DEFAULT_MAX_BLOCK_SIZE as u64;
+        let max_block_sizes = [default, default, 1000, 1000];
+
+        let fill = get(&index, "/blocks/fill?from=0&to=3").unwrap();
+        assert_eq!(fill["from"], 0);
+        assert_eq!(fill["to"], 3);
+        for (height, max_block_size) in max_block_sizes.iter().enumerate() {
+            let block = &fill["blocks"][height];
+            assert_eq!(block["height"], height);
+            assert_eq!(block["id"], json!(blocks[height].entry.hash()));
+            assert_eq!(block["size"], size(height));
+            assert_eq!(block["max_block_size"], *max_block_size);
+            let fill_ratio = size(height) as f64 / *max_block_size as f64;
+            assert_eq!(block["fill_ratio"], fill_ratio);
+        }
+        let total_size: u64 = (0..4).map(size).sum();
+        let total_max_block_size: u64 = max_block_sizes.iter().sum();
+        assert_eq!(fill["total_size"], total_size);
+        assert_eq!(fill["total_max_block_size"], total_max_block_size);
+        let fill_ratio = total_size as f64 / total_max_block_size as f64;
+        assert_eq!(fill["fill_ratio"], fill_ratio);
+
+        // the max block size and fill ratio of a single block
+        let block = get(&index, &format!("/block/{}", blocks[2].entry.hash())).unwrap();
+        assert_eq!(block["max_block_size"], 1000);
+        assert_eq!(block["fill_ratio"], size(2) as f64 / 1000.0);
+        let block = get(&index, &format!("/block/{}", blocks[1].entry.hash())).unwrap();
+        assert_eq!(block["max_block_size"], DEFAULT_MAX_BLOCK_SIZE);
+
+        // the default range ends at the tip, which bounds `to`
+        let fill = get(&index, "/blocks/fill").unwrap();
+        assert_eq!(fill["from"], 1874);
+        assert_eq!(fill["to"], 2017);
+        assert_eq!(fill["blocks"].as_array().unwrap().len(), 144);
+        let fill = get(&index, "/blocks/fill?from=2017&to=5000").unwrap();
+        assert_eq!(fill["to"], 2017);
+
+        // at most 2016 blocks, in order
+        let fill = get(&index, "/blocks/fill?from=1&to=2016").unwrap();
+        assert_eq!(fill["blocks"].as_array().unwrap().len(), 2016);
+        let err = get(&index, "/blocks/fill?from=0&to=2016").unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let err = get(&index, "/blocks/fill?from=3&to=1").unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_address_batch() {
+        let mut index = test_helper::index(|config| config.utxos_limit = 20);
+        let address = |seed| {
+            let (_, pubkey) = test_helper::federation_key(seed);
+            Address::p2pkh(&pubkey, tapyrus::Network::Dev)
+        };
+        let (address_a, address_b) = (address(1), address(2));
+        let scripthash_b = hex::encode(compute_script_hash(&address_b.script_pubkey()));
+        let output = |address: &Address| TxOut {
+            value: 1000,
+            script_pubkey: address.script_pubkey(),
+        };
+        // every block pays address_a, the genesis block also pays address_b
+        let coinbases: Vec<_> = (0..12)
+            .map(|height| match height {
+                0 => test_helper::coinbase(height, vec![output(&address_a), output(&address_b)]),
+                _ => test_helper::coinbase(height, vec![output(&address_a)]),
+            })
+            .collect();
+        let txids: Vec<Txid> = coinbases.iter().map(|tx| tx.malfix_txid()).collect();
+        let txdata = coinbases.into_iter().map(|tx| vec![tx]).collect();
+        let blocks = test_helper::blocks(&HeaderList::empty(), BlockHash::default(), txdata, 0);
+        index.tapyrusd.lock().unwrap().connect(&blocks);
+        index.indexer.update(&index.daemon).unwrap();
+        let post = |path, body: Value| request(&index, Method::POST, path, &body.to_string());
+
+        // malformed bodies, invalid entries and too many of them
+        let err = request(&index, Method::POST, "/addresses/stats", "not json").unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let err = post("/addresses/stats", json!({ "addresses": ["nope"] })).unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let err = post("/addresses/stats", json!({ "scripthashes": ["00"] })).unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let scripthashes = vec![scripthash_b.clone(); 101];
+        let err = post("/addresses/stats", json!({ "scripthashes": scripthashes })).unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let err = post("/addresses/nope", json!({})).unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+
+        // the addresses come first, then the scripthashes, each in the order of the request
+        let body = json!({
+            "scripthashes": [scripthash_b],
+            "addresses": [address_b.to_string(), address_a.to_string()],
+        });
+        let stats = post("/addresses/stats", body.clone()).unwrap();
+        assert_eq!(stats.as_array().unwrap().len(), 3);
+        assert_eq!(stats[0]["address"], address_b.to_string());
+        assert_eq!(stats[1]["address"], address_a.to_string());
+        assert_eq!(stats[2]["scripthash"], scripthash_b);
+        assert_eq!(stats[0]["chain_stats"], stats[2]["chain_stats"]);
+        assert_ne!(stats[0]["chain_stats"], stats[1]["chain_stats"]);
+
+        let utxos = post("/addresses/utxo", body.clone()).unwrap();
+        let counts: Vec<usize> = (0..3)
+            .map(|i| utxos[i]["utxo"].as_array().unwrap().len())
+            .collect();
+        assert_eq!(counts, vec![1, 12, 1]);
+        assert_eq!(utxos[0]["utxo"][0]["txid"], json!(txids[0]));
+        assert_eq!(utxos[2]["scripthash"], scripthash_b);
+
+        // the utxo limit applies to the whole batch
+        let body = json!({ "addresses": [address_a.to_string(), address_a.to_string()] });
+        let err = post("/addresses/utxo", body).unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+
+        let body = json!({ "addresses": [address_a.to_string()] });
+        let txs = post("/addresses/txs", body).unwrap();
+        assert_eq!(txs[0]["txs"].as_array().unwrap().len(), 12);
+        assert_eq!(txs[0]["txs"][0]["txid"], json!(txids[0]));
+        assert_eq!(txs[0]["txs"][0]["status"]["block_height"], 0);
+        assert_eq!(txs[0]["last_seen_txid"], Value::Null);
+
+        // 100 entries share the batch limit, 10 transactions each, the longer histories are
+        // truncated and continued from their last_seen_txid
+        let body = |last_seen_txids: Value| {
+            json!({
+                "addresses": [address_a.to_string()],
+                "scripthashes": vec![scripthash_b.clone(); 99],
+                "last_seen_txids": last_seen_txids,
+            })
+        };
+        let txs = post("/addresses/txs", body(json!({}))).unwrap();
+        let first_page: Vec<Value> = txs[0]["txs"].as_array().unwrap().clone();
+        assert_eq!(first_page.len(), 10);
+        assert_eq!(txs[0]["last_seen_txid"], json!(txids[9]));
+        assert_eq!(txs[99]["txs"].as_array().unwrap().len(), 1);
+        assert_eq!(txs[99]["last_seen_txid"], Value::Null);
+
+        let last_seen_txids = json!({ address_a.to_string(): txids[9] });
+        let txs = post("/addresses/txs", body(last_seen_txids)).unwrap();
+        let second_page: Vec<Value> = txs[0]["txs"].as_array().unwrap().clone();
+        assert_eq!(txs[0]["last_seen_txid"], Value::Null);
+        let paged: Vec<Value> = first_page
+            .iter()
+            .chain(&second_page)
+            .map(|tx| tx["txid"].clone())
+            .collect();
+        let expected: Vec<Value> = txids.iter().map(|txid| json!(txid)).collect();
+        assert_eq!(paged, expected);
+
+        let last_seen_txids = json!({ address_a.to_string(): "nope" });
+        let err = post("/addresses/txs", body(last_seen_txids)).unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
 }
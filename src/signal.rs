@@ -48,4 +48,25 @@ impl Waiter {
             Err(RecvTimeoutError::Disconnected) => bail!("signal hook channel disconnected"),
         }
     }
+
+    // Like wait(), but also returns as soon as a message is received from `other`. SIGUSR1 is
+    // always accepted.
+    pub fn wait_or<T>(
+        &self,
+        duration: Duration,
+        other: &channel::Receiver<T>,
+    ) -> Result<Option<T>> {
+        channel::select! {
+            recv(self.receiver) -> sig => match sig {
+                Ok(sig) if sig == signal_hook::SIGUSR1 => {
+                    trace!("notified via SIGUSR1");
+                    Ok(None)
+                }
+                Ok(sig) => bail!(ErrorKind::Interrupt(sig)),
+                Err(_) => bail!("signal hook channel disconnected"),
+            },
+            recv(other) -> msg => Ok(msg.ok()),
+            default(duration) => Ok(None),
+        }
+    }
 }
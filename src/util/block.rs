@@ -156,7 +156,36 @@ impl HeaderList {
             .collect()
     }
 
-    pub fn apply(&mut self, new_headers: Vec<HeaderEntry>) {
+    // Returns the height of the first block that would be disconnected by applying new_headers,
+    // or None if new_headers simply extend the current tip.
+    pub fn reorg_height(&self, new_headers: &[HeaderEntry]) -> Option<usize> {
+        new_headers
+            .first()
+            .map(|entry| entry.height())
+            .filter(|height| *height < self.headers.len())
+    }
+
+    // Drops the headers at [height..] and returns them (i.e. the orphaned blocks, tip last).
+    pub fn rewind(&mut self, height: usize) -> Vec<HeaderEntry> {
+        if height >= self.headers.len() {
+            return vec![];
+        }
+        let removed = self.headers.split_off(height); // keep [0..height) entries
+        for entry in &removed {
+            self.heights.remove(entry.hash());
+        }
+        self.tip = self.headers.last().map(|h| *h.hash()).unwrap_or_default();
+        debug!(
+            "removed {} headers from height {}, tip at {:?}",
+            removed.len(),
+            height,
+            self.tip
+        );
+        removed
+    }
+
+    // Returns the headers that were disconnected from the best chain by this update.
+    pub fn apply(&mut self, new_headers: Vec<HeaderEntry>) -> Vec<HeaderEntry> {
         // new_headers[i] -> new_headers[i - 1] (i.e. new_headers.last() is the tip)
         for i in 1..new_headers.len() {
             assert_eq!(new_headers[i - 1].height() + 1, new_headers[i].height());
@@ -176,14 +205,14 @@ impl HeaderList {
                 assert_eq!(entry.header().prev_blockhash, expected_prev_blockhash);
                 height
             }
-            None => return,
+            None => return vec![],
         };
         debug!(
             "applying {} new headers from height {}",
             new_headers.len(),
             new_height
         );
-        let _removed = self.headers.split_off(new_height); // keep [0..new_height) entries
+        let removed = self.rewind(new_height);
         for new_header in new_headers {
             let height = new_header.height();
             assert_eq!(height, self.headers.len());
@@ -191,6 +220,7 @@ impl HeaderList {
             self.headers.push(new_header);
             self.heights.insert(self.tip, height);
         }
+        removed
     }
 
     pub fn header_by_blockhash(&self, blockhash: &BlockHash) -> Option<&HeaderEntry> {
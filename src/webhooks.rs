@@ -0,0 +1,976 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_TYPE, LOCATION, USER_AGENT};
+use hyper::service::Service;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use tapyrus::consensus::encode::{deserialize, serialize};
+use tapyrus::ColorIdentifier;
+use tokio::runtime;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time;
+use url::{Host, Url};
+
+use crate::errors::*;
+use crate::metrics::{CounterVec, MetricOpts, Metrics};
+use crate::new_index::db::{DBFlush, DBRow};
+use crate::new_index::events::{Event, TxEvent};
+use crate::new_index::{ChainQuery, Query, Store};
+use crate::util::{full_hash, spawn_thread, BlockId, Bytes, FullHash, TransactionStatus};
+
+const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_ATTEMPTS: u32 = 10;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(5);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(3600);
+const MAX_REDIRECTS: usize = 3;
+const MAX_WEBHOOKS: usize = 1000;
+const MAX_CONCURRENT_DELIVERIES: usize = 4; // per webhook
+
+// The webhooks are persisted in the cache db, along with the last id given to one (so that the
+// ids of removed webhooks, whose deliveries may still be pending, are never reused):
+//      W{id} → {url}{scripthashes}{color_ids}{confirmations}
+//      w → {last id}
+const LAST_ID_KEY: &[u8] = b"w";
+
+#[derive(Serialize, Deserialize)]
+struct WebhookValue {
+    url: String,
+    scripthashes: Vec<FullHash>,
+    color_ids: Vec<Bytes>, // consensus-serialized color ids
+    confirmations: u32,
+}
+
+/// A target URL called when a transaction touching the watched scripthashes or colors enters
+/// the mempool, gets its first confirmation and reaches `confirmations` confirmations.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    pub id: u64,
+    pub url: String,
+    pub scripthashes: HashSet<FullHash>,
+    pub color_ids: HashSet<ColorIdentifier>,
+    pub confirmations: u32,
+}
+
+impl Webhook {
+    fn key(id: u64) -> Bytes {
+        bincode::serialize(&(b'W', id.to_be_bytes())).unwrap()
+    }
+
+    fn filter() -> Bytes {
+        b"W".to_vec()
+    }
+
+    fn to_value(&self) -> Bytes {
+        bincode::serialize(&WebhookValue {
+            url: self.url.clone(),
+            scripthashes: self.scripthashes.iter().cloned().collect(),
+            color_ids: self.color_ids.iter().map(serialize).collect(),
+            confirmations: self.confirmations,
+        })
+        .unwrap()
+    }
+
+    fn from_row(key: &[u8], value: &[u8]) -> Self {
+        let (_prefix, id): (u8, [u8; 8]) =
+            bincode::deserialize(key).expect("failed to parse webhook key");
+        let value: WebhookValue =
+            bincode::deserialize(value).expect("failed to parse WebhookValue");
+        Webhook {
+            id: u64::from_be_bytes(id),
+            url: value.url,
+            scripthashes: value.scripthashes.into_iter().collect(),
+            color_ids: value
+                .color_ids
+                .iter()
+                .map(|color_id| deserialize(color_id).expect("failed to parse ColorIdentifier"))
+                .collect(),
+            confirmations: value.confirmations,
+        }
+    }
+
+    fn matches(&self, tx: &TxEvent) -> bool {
+        !self.scripthashes.is_disjoint(&tx.scripthashes)
+            || !self.color_ids.is_disjoint(&tx.color_ids)
+    }
+
+    fn payload(&self, event: &str, tx: &TxEvent, confirmations: u32) -> String {
+        let scripthashes: Vec<String> = self
+            .scripthashes
+            .intersection(&tx.scripthashes)
+            .map(hex::encode)
+            .collect();
+        let color_ids: Vec<&ColorIdentifier> = self.color_ids.intersection(&tx.color_ids).collect();
+        json!({
+            "webhook_id": self.id,
+            "event": event,
+            "txid": tx.txid,
+            "status": TransactionStatus::from(tx.confirmed.clone()),
+            "confirmations": confirmations,
+            "scripthashes": scripthashes,
+            "color_ids": color_ids,
+        })
+        .to_string()
+    }
+}
+
+/// The registered webhooks, kept in memory and written through to the cache db along with the
+/// transactions waiting for more confirmations and the deliveries not made yet, which resume
+/// after a restart.
+pub struct WebhookRegistry {
+    store: Arc<Store>,
+    webhooks: RwLock<(BTreeMap<u64, Webhook>, u64)>, // with the last id given to a webhook
+    pending: Mutex<Vec<PendingConfirmation>>,
+    restored_deliveries: Mutex<Vec<Delivery>>, // taken by start()
+    last_delivery_id: AtomicU64,
+}
+
+impl WebhookRegistry {
+    pub fn open(store: Arc<Store>) -> Self {
+        let webhooks: BTreeMap<u64, Webhook> = store
+            .cache_db()
+            .iter_scan(&Webhook::filter())
+            .map(|row| Webhook::from_row(&row.key, &row.value))
+            .map(|webhook| (webhook.id, webhook))
+            .collect();
+        // registries written before the last id was persisted follow the highest one
+        let last_id = match store.cache_db().get(LAST_ID_KEY) {
+            Some(value) => u64::from_be_bytes(value[..].try_into().expect("invalid last id")),
+            None => webhooks.keys().next_back().cloned().unwrap_or(0),
+        };
+        let pending: Vec<PendingConfirmation> = store
+            .cache_db()
+            .iter_scan(&PendingConfirmation::filter())
+            .map(|row| PendingConfirmation::from_row(&row.key, &row.value))
+            .collect();
+        let deliveries: Vec<Delivery> = store
+            .cache_db()
+            .iter_scan(&Delivery::filter())
+            .map(|row| Delivery::from_row(&row.key, &row.value))
+            .collect();
+        if !pending.is_empty() || !deliveries.is_empty() {
+            info!(
+                "restored {} webhook confirmation watches and {} webhook deliveries",
+                pending.len(),
+                deliveries.len()
+            );
+        }
+        let last_delivery_id = deliveries.last().map_or(0, |delivery| delivery.id);
+        WebhookRegistry {
+            store,
+            webhooks: RwLock::new((webhooks, last_id)),
+            pending: Mutex::new(pending),
+            restored_deliveries: Mutex::new(deliveries),
+            last_delivery_id: AtomicU64::new(last_delivery_id),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        self.webhooks.read().unwrap().0.values().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Webhook> {
+        self.webhooks.read().unwrap().0.get(&id).cloned()
+    }
+
+    fn matching(&self, tx: &TxEvent) -> Vec<Webhook> {
+        self.webhooks
+            .read()
+            .unwrap()
+            .0
+            .values()
+            .filter(|webhook| webhook.matches(tx))
+            .cloned()
+            .collect()
+    }
+
+    pub fn add(
+        &self,
+        url: &str,
+        scripthashes: HashSet<FullHash>,
+        color_ids: HashSet<ColorIdentifier>,
+        confirmations: u32,
+    ) -> Result<Webhook> {
+        let parsed = Url::parse(url).chain_err(|| format!("invalid url {}", url))?;
+        check_url(&parsed)?;
+        ensure!(
+            !scripthashes.is_empty() || !color_ids.is_empty(),
+            "no scripthash or color id to watch"
+        );
+        ensure!(confirmations > 0, "confirmations must be positive");
+
+        let mut guard = self.webhooks.write().unwrap();
+        let (webhooks, last_id) = &mut *guard;
+        ensure!(
+            webhooks.len() < MAX_WEBHOOKS,
+            "too many webhooks, at most {} can be registered",
+            MAX_WEBHOOKS
+        );
+        let webhook = Webhook {
+            id: *last_id + 1,
+            url: url.to_string(),
+            scripthashes,
+            color_ids,
+            confirmations,
+        };
+        let rows = vec![
+            DBRow {
+                key: Webhook::key(webhook.id),
+                value: webhook.to_value(),
+            },
+            DBRow {
+                key: LAST_ID_KEY.to_vec(),
+                value: webhook.id.to_be_bytes().to_vec(),
+            },
+        ];
+        self.store.cache_db().write(rows, DBFlush::Enable);
+        *last_id = webhook.id;
+        webhooks.insert(webhook.id, webhook.clone());
+        Ok(webhook)
+    }
+
+    // The deliveries already queued for a removed webhook are still made
+    pub fn remove(&self, id: u64) -> bool {
+        let mut webhooks = self.webhooks.write().unwrap();
+        if webhooks.0.remove(&id).is_none() {
+            return false;
+        }
+        let mut keys = vec![Webhook::key(id)];
+        self.pending.lock().unwrap().retain(|p| {
+            if p.webhook_id == id {
+                keys.push(p.key());
+            }
+            p.webhook_id != id
+        });
+        self.store.cache_db().delete(keys);
+        true
+    }
+
+    // Wait for the confirmations requested by the webhook before calling it again
+    fn watch_confirmations(&self, webhook: &Webhook, tx: &TxEvent, blockid: &BlockId) {
+        let pending = PendingConfirmation {
+            webhook_id: webhook.id,
+            tx: tx.clone(),
+            blockid: blockid.clone(),
+            confirmations: webhook.confirmations,
+        };
+        self.store
+            .cache_db()
+            .put(&pending.key(), &pending.to_value());
+        self.pending.lock().unwrap().push(pending);
+    }
+
+    // Returns the webhooks to call, along with their payload, for the pending transactions
+    // reaching their confirmations at the new tip. The transactions of orphaned blocks are no
+    // longer watched: they are notified again when confirmed again.
+    fn reached_confirmations(&self, chain: &ChainQuery, tip: &BlockId) -> Vec<(Webhook, String)> {
+        let mut reached = vec![];
+        let mut keys = vec![];
+        // locked in the same order as by remove()
+        let webhooks = self.webhooks.read().unwrap();
+        self.pending.lock().unwrap().retain(|p| {
+            let in_best_chain = chain
+                .header_by_height(p.blockid.height)
+                .map_or(false, |header| *header.hash() == p.blockid.hash);
+            let confirmations = (tip.height + 1).saturating_sub(p.blockid.height) as u32;
+            if in_best_chain && confirmations < p.confirmations {
+                return true;
+            }
+            if in_best_chain {
+                if let Some(webhook) = webhooks.0.get(&p.webhook_id) {
+                    let payload = webhook.payload("confirmations", &p.tx, confirmations);
+                    reached.push((webhook.clone(), payload));
+                }
+            }
+            keys.push(p.key());
+            false
+        });
+        if !keys.is_empty() {
+            self.store.cache_db().delete(keys);
+        }
+        reached
+    }
+
+    fn queue_delivery(&self, webhook: &Webhook, payload: String) -> Delivery {
+        let delivery = Delivery {
+            id: self.last_delivery_id.fetch_add(1, Ordering::SeqCst) + 1,
+            webhook_id: webhook.id,
+            url: webhook.url.clone(),
+            payload,
+            attempts: 0,
+        };
+        self.save_delivery(&delivery);
+        delivery
+    }
+
+    fn save_delivery(&self, delivery: &Delivery) {
+        self.store
+            .cache_db()
+            .put(&Delivery::key(delivery.id), &delivery.to_value());
+    }
+
+    fn remove_delivery(&self, delivery: &Delivery) {
+        self.store
+            .cache_db()
+            .delete(vec![Delivery::key(delivery.id)]);
+    }
+}
+
+// The deliveries are persisted until they succeed or are given up:
+//      Q{delivery id} → {webhook id}{url}{payload}{attempts}
+#[derive(Serialize, Deserialize)]
+struct DeliveryValue {
+    webhook_id: u64,
+    url: String,
+    payload: String,
+    attempts: u32,
+}
+
+struct Delivery {
+    id: u64,
+    webhook_id: u64,
+    url: String,
+    payload: String,
+    attempts: u32,
+}
+
+impl Delivery {
+    fn key(id: u64) -> Bytes {
+        bincode::serialize(&(b'Q', id.to_be_bytes())).unwrap()
+    }
+
+    fn filter() -> Bytes {
+        b"Q".to_vec()
+    }
+
+    fn to_value(&self) -> Bytes {
+        bincode::serialize(&DeliveryValue {
+            webhook_id: self.webhook_id,
+            url: self.url.clone(),
+            payload: self.payload.clone(),
+            attempts: self.attempts,
+        })
+        .unwrap()
+    }
+
+    fn from_row(key: &[u8], value: &[u8]) -> Self {
+        let (_prefix, id): (u8, [u8; 8]) =
+            bincode::deserialize(key).expect("failed to parse delivery key");
+        let value: DeliveryValue =
+            bincode::deserialize(value).expect("failed to parse DeliveryValue");
+        Delivery {
+            id: u64::from_be_bytes(id),
+            webhook_id: value.webhook_id,
+            url: value.url,
+            payload: value.payload,
+            attempts: value.attempts,
+        }
+    }
+}
+
+// The confirmed transactions waiting for more confirmations are persisted as well:
+//      P{webhook id}{txid}{blockhash} → {blockid}{scripthashes}{color_ids}{confirmations}
+#[derive(Serialize, Deserialize)]
+struct PendingValue {
+    blockid: BlockId,
+    scripthashes: Vec<FullHash>,
+    color_ids: Vec<Bytes>, // consensus-serialized color ids
+    confirmations: u32,
+}
+
+// A confirmed transaction waiting for the confirmation count requested by a webhook
+struct PendingConfirmation {
+    webhook_id: u64,
+    tx: TxEvent,
+    blockid: BlockId,
+    confirmations: u32,
+}
+
+impl PendingConfirmation {
+    fn key(&self) -> Bytes {
+        bincode::serialize(&(
+            b'P',
+            self.webhook_id.to_be_bytes(),
+            full_hash(&self.tx.txid[..]),
+            full_hash(&self.blockid.hash[..]),
+        ))
+        .unwrap()
+    }
+
+    fn filter() -> Bytes {
+        b"P".to_vec()
+    }
+
+    fn to_value(&self) -> Bytes {
+        bincode::serialize(&PendingValue {
+            blockid: self.blockid.clone(),
+            scripthashes: self.tx.scripthashes.iter().cloned().collect(),
+            color_ids: self.tx.color_ids.iter().map(serialize).collect(),
+            confirmations: self.confirmations,
+        })
+        .unwrap()
+    }
+
+    fn from_row(key: &[u8], value: &[u8]) -> Self {
+        let (_prefix, webhook_id, txid, _blockhash): (u8, [u8; 8], FullHash, FullHash) =
+            bincode::deserialize(key).expect("failed to parse pending confirmation key");
+        let value: PendingValue =
+            bincode::deserialize(value).expect("failed to parse PendingValue");
+        PendingConfirmation {
+            webhook_id: u64::from_be_bytes(webhook_id),
+            tx: TxEvent {
+                txid: deserialize(&txid).expect("failed to parse Txid"),
+                confirmed: Some(value.blockid.clone()),
+                scripthashes: value.scripthashes.into_iter().collect(),
+                color_ids: value
+                    .color_ids
+                    .iter()
+                    .map(|color_id| deserialize(color_id).expect("failed to parse ColorIdentifier"))
+                    .collect(),
+            },
+            blockid: value.blockid,
+            confirmations: value.confirmations,
+        }
+    }
+}
+
+/// Start the threads that match the chain and mempool events against the registered webhooks,
+/// and deliver the resulting calls (retrying failed ones with an exponential backoff).
+pub fn start(
+    registry: Arc<WebhookRegistry>,
+    query: Arc<Query>,
+    events: broadcast::Receiver<Arc<Event>>,
+    metrics: &Metrics,
+) {
+    let deliveries = metrics.counter_vec(
+        MetricOpts::new("webhook_deliveries", "# of webhook delivery attempts"),
+        &["result"],
+    );
+    let client = WebhookClient::new(false).expect("failed to create the webhook client");
+    let (sender, receiver) = mpsc::unbounded_channel();
+    for delivery in registry.restored_deliveries.lock().unwrap().drain(..) {
+        sender.send(delivery).ok();
+    }
+    let delivering = Arc::clone(&registry);
+    spawn_thread("webhook-events", move || {
+        run_events(registry, query, events, sender)
+    });
+    spawn_thread("webhook-delivery", move || {
+        run_deliveries(delivering, client, receiver, deliveries)
+    });
+}
+
+fn run_events(
+    registry: Arc<WebhookRegistry>,
+    query: Arc<Query>,
+    mut events: broadcast::Receiver<Arc<Event>>,
+    sender: mpsc::UnboundedSender<Delivery>,
+) {
+    loop {
+        let event = match events.blocking_recv() {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("webhooks missed {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        match *event {
+            Event::Block(ref tip) => {
+                for (webhook, payload) in registry.reached_confirmations(query.chain(), tip) {
+                    sender.send(registry.queue_delivery(&webhook, payload)).ok();
+                }
+            }
+            Event::Tx(ref tx) => {
+                for webhook in registry.matching(tx) {
+                    let payload = match tx.confirmed {
+                        None => webhook.payload("mempool", tx, 0),
+                        Some(ref blockid) => {
+                            if webhook.confirmations > 1 {
+                                registry.watch_confirmations(&webhook, tx, blockid);
+                            }
+                            webhook.payload("confirmed", tx, 1)
+                        }
+                    };
+                    sender.send(registry.queue_delivery(&webhook, payload)).ok();
+                }
+            }
+        }
+    }
+    debug!("webhook events closed");
+}
+
+// Each delivery is made by its own task, so that a slow or failing target only delays the
+// deliveries to itself, which are made at most MAX_CONCURRENT_DELIVERIES at a time.
+fn run_deliveries(
+    registry: Arc<WebhookRegistry>,
+    client: WebhookClient,
+    mut receiver: mpsc::UnboundedReceiver<Delivery>,
+    deliveries: CounterVec,
+) {
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to create the webhook delivery runtime");
+    let client = Arc::new(client);
+    rt.block_on(async {
+        let mut targets: HashMap<u64, Arc<Semaphore>> = HashMap::new();
+        while let Some(delivery) = receiver.recv().await {
+            let permits = targets
+                .entry(delivery.webhook_id)
+                .or_insert_with(|| Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES)));
+            tokio::spawn(deliver(
+                Arc::clone(&registry),
+                Arc::clone(&client),
+                Arc::clone(permits),
+                delivery,
+                deliveries.clone(),
+            ));
+        }
+    });
+    debug!("webhook deliveries closed");
+}
+
+async fn deliver(
+    registry: Arc<WebhookRegistry>,
+    client: Arc<WebhookClient>,
+    permits: Arc<Semaphore>,
+    mut delivery: Delivery,
+    deliveries: CounterVec,
+) {
+    loop {
+        let result = {
+            let _permit = permits.acquire().await.expect("semaphore closed");
+            client.post_json(&delivery.url, &delivery.payload).await
+        };
+        let e = match result {
+            Ok(()) => {
+                deliveries.with_label_values(&["delivered"]).inc();
+                break;
+            }
+            Err(e) => e,
+        };
+        delivery.attempts += 1;
+        if delivery.attempts >= MAX_ATTEMPTS {
+            warn!(
+                "giving up webhook #{} delivery to {}: {}",
+                delivery.webhook_id, delivery.url, e
+            );
+            deliveries.with_label_values(&["failed"]).inc();
+            break;
+        }
+        let delay = RETRY_BASE_DELAY
+            .checked_mul(1 << (delivery.attempts - 1))
+            .map_or(RETRY_MAX_DELAY, |delay| delay.min(RETRY_MAX_DELAY));
+        debug!(
+            "webhook #{} delivery to {} failed, retrying in {:?}: {}",
+            delivery.webhook_id, delivery.url, delay, e
+        );
+        deliveries.with_label_values(&["retried"]).inc();
+        registry.save_delivery(&delivery);
+        time::sleep(delay).await;
+    }
+    registry.remove_delivery(&delivery);
+}
+
+// Whether the address may be reached from the internet. Webhooks may not target the other
+// addresses, which would let the API users reach the services of the server's own host and
+// network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || octets[0] == 0
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64)) // shared address space
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ipv4) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ipv4));
+            }
+            let segment = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || segment & 0xfe00 == 0xfc00 // unique local
+                || segment & 0xffc0 == 0xfe80) // link local
+        }
+    }
+}
+
+// Check the scheme and host of a webhook URL. Hostnames are checked once resolved, before every
+// delivery, as they may resolve to other addresses by then.
+fn check_url(url: &Url) -> Result<()> {
+    ensure!(
+        url.scheme() == "http" || url.scheme() == "https",
+        "unsupported url scheme {}",
+        url.scheme()
+    );
+    let public = match url.host().chain_err(|| "missing host")? {
+        Host::Ipv4(ip) => is_public(IpAddr::V4(ip)),
+        Host::Ipv6(ip) => is_public(IpAddr::V6(ip)),
+        Host::Domain(domain) => domain != "localhost" && !domain.ends_with(".localhost"),
+    };
+    ensure!(public, "webhooks may not target a private address");
+    Ok(())
+}
+
+// Resolves hostnames with getaddrinfo, leaving out the non-public addresses
+#[derive(Clone)]
+struct PublicResolver {
+    inner: GaiResolver,
+    allow_private: bool,
+}
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let allow_private = self.allow_private;
+        let resolving = self.inner.call(name);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving
+                .await?
+                .filter(|addr| allow_private || is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "no public address",
+                ));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+struct WebhookClient {
+    client: Client<HttpsConnector<HttpConnector<PublicResolver>>>,
+    allow_private: bool, // only for testing against local servers
+}
+
+impl WebhookClient {
+    fn new(allow_private: bool) -> Result<Self> {
+        let mut http = HttpConnector::new_with_resolver(PublicResolver {
+            inner: GaiResolver::new(),
+            allow_private,
+        });
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(TIMEOUT));
+        let tls = native_tls::TlsConnector::new().chain_err(|| "failed to create tls connector")?;
+        Ok(WebhookClient {
+            client: Client::builder().build(HttpsConnector::from((http, tls.into()))),
+            allow_private,
+        })
+    }
+
+    // POST the payload, following the redirects that keep the method and body (307 and 308)
+    async fn post_json(&self, url: &str, payload: &str) -> Result<()> {
+        let mut url = Url::parse(url).chain_err(|| format!("invalid url {}", url))?;
+        for _ in 0..=MAX_REDIRECTS {
+            if !self.allow_private {
+                check_url(&url)?;
+            }
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url.as_str())
+                .header(CONTENT_TYPE, "application/json")
+                .header(USER_AGENT, format!("esplora-tapyrus/{}", ELECTRS_VERSION))
+                .body(Body::from(payload.to_string()))
+                .chain_err(|| format!("invalid request to {}", url))?;
+            let response = time::timeout(TIMEOUT, self.client.request(request))
+                .await
+                .chain_err(|| "request timed out")?
+                .chain_err(|| "request failed")?;
+            let status = response.status();
+            if status == StatusCode::TEMPORARY_REDIRECT || status == StatusCode::PERMANENT_REDIRECT
+            {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .chain_err(|| "redirect without location")?;
+                url = url
+                    .join(location)
+                    .chain_err(|| format!("invalid redirect location {}", location))?;
+                continue;
+            }
+            ensure!(status.is_success(), "response status {}", status);
+            return Ok(());
+        }
+        bail!("too many redirects")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_index::test_helper;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+    use std::thread;
+    use std::time::Instant;
+    use tapyrus::{BlockHash, Txid};
+
+    // Accept a single request and answer it with the given status and headers, returning the
+    // request
+    fn http_stand_in(
+        status: &'static str,
+        headers: String,
+    ) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook?key=1", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.to_lowercase().starts_with("content-length:") {
+                    content_length = line[15..].trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+            let response = format!(
+                "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+                status, headers
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            request
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_post_json() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let client = WebhookClient::new(true).unwrap();
+        let (url, handle) = http_stand_in("200 OK", String::new());
+        rt.block_on(client.post_json(&url, "{\"event\":\"mempool\"}"))
+            .unwrap();
+        let request = handle.join().unwrap();
+        assert!(request.starts_with("POST /hook?key=1 HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"event\":\"mempool\"}"));
+
+        let (url, handle) = http_stand_in("500 Internal Server Error", String::new());
+        assert!(rt.block_on(client.post_json(&url, "{}")).is_err());
+        handle.join().unwrap();
+
+        // 307 redirects are followed with the same body
+        let (target, target_handle) = http_stand_in("204 No Content", String::new());
+        let location = format!("Location: {}\r\n", target);
+        let (url, handle) = http_stand_in("307 Temporary Redirect", location);
+        rt.block_on(client.post_json(&url, "{}")).unwrap();
+        handle.join().unwrap();
+        assert!(target_handle.join().unwrap().ends_with("\r\n\r\n{}"));
+
+        // the private addresses are refused before connecting
+        let client = WebhookClient::new(false).unwrap();
+        assert!(rt.block_on(client.post_json(&url, "{}")).is_err());
+        assert!(rt
+            .block_on(client.post_json("http://localhost/hook", "{}"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_is_public() {
+        assert!(is_public(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))));
+        assert!(is_public(IpAddr::V6("2606:2800:220:1::".parse().unwrap())));
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{} is not public", ip);
+        }
+        assert!(!is_public(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+
+        let check = |url: &str| check_url(&Url::parse(url).unwrap());
+        assert!(check("https://example.com/hook").is_ok());
+        assert!(check("http://93.184.216.34/hook").is_ok());
+        assert!(check("http://localhost:3000/").is_err());
+        assert!(check("http://api.localhost/").is_err());
+        assert!(check("http://[::1]/").is_err());
+        assert!(check("http://192.168.1.1/").is_err());
+        assert!(check("ftp://example.com/").is_err());
+    }
+
+    #[test]
+    fn test_registry() {
+        let index = test_helper::index(|_| {});
+        let store = index.store;
+        let scripthashes: HashSet<FullHash> = vec![[1; 32]].into_iter().collect();
+
+        let watched = || scripthashes.clone();
+        let registry = WebhookRegistry::open(Arc::clone(&store));
+        assert!(registry
+            .add("ftp://example.com/", watched(), HashSet::new(), 6)
+            .is_err());
+        assert!(registry
+            .add("http://example.com/", HashSet::new(), HashSet::new(), 6)
+            .is_err());
+        assert!(registry
+            .add("http://127.0.0.1/", watched(), HashSet::new(), 6)
+            .is_err());
+        let first = registry.add("http://example.com/a", watched(), HashSet::new(), 6);
+        let second = registry.add("http://example.com/b", watched(), HashSet::new(), 1);
+        let third = registry.add("http://example.com/c", watched(), HashSet::new(), 1);
+        let (first, second, third) = (first.unwrap(), second.unwrap(), third.unwrap());
+        assert_eq!((first.id, second.id, third.id), (1, 2, 3));
+        assert!(registry.remove(first.id));
+        assert!(!registry.remove(first.id));
+        assert!(registry.remove(third.id));
+
+        // webhooks are persisted, and the ids of the removed ones are not given again
+        let registry = WebhookRegistry::open(store);
+        let webhooks = registry.list();
+        assert_eq!(webhooks.len(), 1);
+        assert_eq!(webhooks[0].url, "http://example.com/b");
+        assert_eq!(webhooks[0].scripthashes, scripthashes);
+        assert_eq!(webhooks[0].confirmations, 1);
+        let fourth = registry
+            .add("http://example.com/d", scripthashes, HashSet::new(), 6)
+            .unwrap();
+        assert_eq!(fourth.id, 4);
+    }
+
+    #[test]
+    fn test_persisted_state() {
+        let index = test_helper::index(|_| {});
+        let (store, chain) = (index.store, index.chain);
+        let scripthashes: HashSet<FullHash> = vec![[1; 32]].into_iter().collect();
+        let registry = WebhookRegistry::open(Arc::clone(&store));
+        let webhook = registry
+            .add(
+                "http://example.com/",
+                scripthashes.clone(),
+                HashSet::new(),
+                6,
+            )
+            .unwrap();
+        let blockid = BlockId {
+            height: 0,
+            hash: BlockHash::default(),
+            time: 0,
+        };
+        let tx = TxEvent {
+            txid: Txid::default(),
+            confirmed: Some(blockid.clone()),
+            scripthashes,
+            color_ids: HashSet::new(),
+        };
+        registry.watch_confirmations(&webhook, &tx, &blockid);
+        let first = registry.queue_delivery(&webhook, webhook.payload("confirmed", &tx, 1));
+        let second = registry.queue_delivery(&webhook, "{}".to_string());
+        registry.remove_delivery(&first);
+
+        // the confirmation watch and the delivery not made yet are restored after a restart
+        let registry = WebhookRegistry::open(Arc::clone(&store));
+        {
+            let pending = registry.pending.lock().unwrap();
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].webhook_id, webhook.id);
+            assert_eq!(pending[0].tx.txid, tx.txid);
+            assert_eq!(pending[0].tx.scripthashes, tx.scripthashes);
+            assert_eq!(pending[0].confirmations, 6);
+            let restored = registry.restored_deliveries.lock().unwrap();
+            assert_eq!(restored.len(), 1);
+            assert_eq!(
+                (restored[0].id, restored[0].webhook_id),
+                (second.id, webhook.id)
+            );
+            assert_eq!(restored[0].payload, "{}");
+        }
+        assert_eq!(registry.queue_delivery(&webhook, "{}".to_string()).id, 3);
+
+        // the block is not in the (empty) best chain, so the watch is dropped
+        assert!(registry.reached_confirmations(&chain, &blockid).is_empty());
+        registry.watch_confirmations(&webhook, &tx, &blockid);
+        assert!(registry.remove(webhook.id));
+        let registry = WebhookRegistry::open(store);
+        assert!(registry.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deliveries_per_target() {
+        let index = test_helper::index(|_| {});
+        let store = index.store;
+        let registry = Arc::new(WebhookRegistry::open(Arc::clone(&store)));
+        let webhook = |id, url: &str| Webhook {
+            id,
+            url: url.to_string(),
+            scripthashes: HashSet::new(),
+            color_ids: HashSet::new(),
+            confirmations: 1,
+        };
+        // a target that accepts connections but never answers
+        let stalled = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stalled_url = format!("http://{}/", stalled.local_addr().unwrap());
+        let (url, handle) = http_stand_in("200 OK", String::new());
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let first = registry.queue_delivery(&webhook(1, &stalled_url), "{}".to_string());
+        let second = registry.queue_delivery(&webhook(2, &url), "{\"n\":2}".to_string());
+        let second_key = Delivery::key(second.id);
+        assert!(sender.send(first).is_ok());
+        assert!(sender.send(second).is_ok());
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let deliveries = metrics.counter_vec(MetricOpts::new("test", "test"), &["result"]);
+        let client = WebhookClient::new(true).unwrap();
+        let delivering = Arc::clone(&registry);
+        let start = Instant::now();
+        thread::spawn(move || run_deliveries(delivering, client, receiver, deliveries));
+
+        // the second target is called while the first one is stalled, and its delivery is no
+        // longer persisted once made
+        assert!(handle.join().unwrap().ends_with("\r\n\r\n{\"n\":2}"));
+        while store.cache_db().get(&second_key).is_some() {
+            assert!(start.elapsed() < TIMEOUT, "delivery not removed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(start.elapsed() < TIMEOUT);
+        assert!(store.cache_db().get(&Delivery::key(1)).is_some());
+        drop(sender);
+    }
+}
@@ -0,0 +1,307 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::Sender;
+use socket2::Socket;
+use tapyrus::consensus::encode::deserialize;
+use tapyrus::{BlockHash, Transaction, Txid};
+
+use crate::errors::*;
+use crate::util::spawn_thread;
+
+// for the handshake, and for the rest of a message once its first byte arrived
+const TIMEOUT: Duration = Duration::from_secs(10);
+const KEEPALIVE: Duration = Duration::from_secs(60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_FRAME_SIZE: u64 = 32 << 20;
+
+// frame flags
+const MORE: u8 = 0x01;
+const LONG: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+
+#[derive(Debug, PartialEq)]
+pub enum Notification {
+    Block(BlockHash),
+    Tx(Txid),
+}
+
+/// Subscribe to the given topics (`hashblock` and/or `rawtx`) published by tapyrusd at `addr`,
+/// reconnecting whenever the connection is lost.
+pub fn subscribe(addr: SocketAddr, topics: Vec<&'static str>, sender: Sender<Notification>) {
+    spawn_thread("zmq", move || loop {
+        match connect(addr, &topics) {
+            Ok(stream) => {
+                info!("subscribed to {:?} notifications from {}", topics, addr);
+                if let Err(e) = receive(stream, &sender) {
+                    warn!("zmq connection to {} failed: {}", addr, e);
+                }
+            }
+            Err(e) => warn!("failed to subscribe to zmq at {}: {}", addr, e),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+}
+
+// A minimal ZMTP 3.0 handshake, with the NULL security mechanism and a SUB socket
+fn connect(addr: SocketAddr, topics: &[&str]) -> Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+        .chain_err(|| format!("failed to connect to {}", addr))?;
+    // notifications may be far apart, so only a dead peer should end the connection
+    let socket = Socket::from(stream);
+    socket
+        .set_keepalive(Some(KEEPALIVE))
+        .chain_err(|| "failed to set keepalive")?;
+    let mut stream = socket.into_tcp_stream();
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .chain_err(|| "failed to set read timeout")?;
+    stream
+        .set_write_timeout(Some(TIMEOUT))
+        .chain_err(|| "failed to set write timeout")?;
+
+    // signature, version 3.0, NULL mechanism, as-server=0 and filler
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    stream.write_all(&greeting).chain_err(|| "write failed")?;
+
+    let mut peer = [0u8; 64];
+    stream.read_exact(&mut peer).chain_err(|| "read failed")?;
+    ensure!(peer[0] == 0xff && peer[9] == 0x7f, "invalid greeting");
+    ensure!(peer[10] >= 3, "unsupported ZMTP version {}", peer[10]);
+    ensure!(
+        peer[12..32] == greeting[12..32],
+        "unsupported security mechanism"
+    );
+
+    let mut ready = command_name("READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&3u32.to_be_bytes());
+    ready.extend_from_slice(b"SUB");
+    write_frame(&mut stream, COMMAND, &ready)?;
+
+    let (flags, body) = read_frame(&mut stream)?;
+    ensure!(
+        flags & COMMAND != 0 && body.starts_with(&command_name("READY")),
+        "expected READY command"
+    );
+
+    for topic in topics {
+        let mut subscription = vec![1];
+        subscription.extend_from_slice(topic.as_bytes());
+        write_frame(&mut stream, 0, &subscription)?;
+    }
+    Ok(stream)
+}
+
+fn receive(mut stream: TcpStream, sender: &Sender<Notification>) -> Result<()> {
+    loop {
+        wait_for_message(&stream)?;
+        let mut parts = vec![];
+        loop {
+            let (flags, body) = read_frame(&mut stream)?;
+            if flags & COMMAND != 0 {
+                continue;
+            }
+            parts.push(body);
+            if flags & MORE == 0 {
+                break;
+            }
+        }
+        // {topic}{body}{sequence number}
+        let notification = match (parts.get(0).map(Vec::as_slice), parts.get(1)) {
+            (Some(b"hashblock"), Some(hash)) => {
+                // hashes are published in RPC byte order
+                let hash: Vec<u8> = hash.iter().rev().cloned().collect();
+                Notification::Block(deserialize(&hash).chain_err(|| "invalid block hash")?)
+            }
+            (Some(b"rawtx"), Some(rawtx)) => {
+                let tx: Transaction = deserialize(rawtx).chain_err(|| "invalid transaction")?;
+                Notification::Tx(tx.malfix_txid())
+            }
+            _ => {
+                trace!("ignoring zmq message with {} parts", parts.len());
+                continue;
+            }
+        };
+        sender
+            .send(notification)
+            .chain_err(|| "notification channel closed")?;
+    }
+}
+
+// Wait for the next message without a timeout, as they may be far apart. A publisher stalling
+// in the middle of a message makes read_frame() time out instead, and the caller reconnect.
+fn wait_for_message(stream: &TcpStream) -> Result<()> {
+    loop {
+        match stream.peek(&mut [0u8; 1]) {
+            Ok(0) => bail!("connection closed"),
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                continue
+            }
+            Err(e) => return Err(e).chain_err(|| "read failed"),
+        }
+    }
+}
+
+fn command_name(name: &str) -> Vec<u8> {
+    let mut body = vec![name.len() as u8];
+    body.extend_from_slice(name.as_bytes());
+    body
+}
+
+fn write_frame(stream: &mut TcpStream, flags: u8, body: &[u8]) -> Result<()> {
+    let mut frame = if body.len() > 255 {
+        let mut frame = vec![flags | LONG];
+        frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        frame
+    } else {
+        vec![flags, body.len() as u8]
+    };
+    frame.extend_from_slice(body);
+    stream.write_all(&frame).chain_err(|| "write failed")
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    let mut flags = [0u8; 1];
+    stream.read_exact(&mut flags).chain_err(|| "read failed")?;
+    let size = if flags[0] & LONG != 0 {
+        let mut size = [0u8; 8];
+        stream.read_exact(&mut size).chain_err(|| "read failed")?;
+        u64::from_be_bytes(size)
+    } else {
+        let mut size = [0u8; 1];
+        stream.read_exact(&mut size).chain_err(|| "read failed")?;
+        size[0] as u64
+    };
+    ensure!(size <= MAX_FRAME_SIZE, "frame too large ({} bytes)", size);
+    let mut body = vec![0u8; size as usize];
+    stream.read_exact(&mut body).chain_err(|| "read failed")?;
+    Ok((flags[0], body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Instant;
+    use tapyrus::consensus::encode::serialize;
+    use tapyrus::hashes::hex::FromHex;
+    use tapyrus::{OutPoint, Script, TxIn, TxOut};
+
+    // The handshake of a stand-in for the tapyrusd publisher, returning the subscriptions
+    fn accept_subscriber(listener: &TcpListener, topics: usize) -> (TcpStream, Vec<Vec<u8>>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut greeting = [0u8; 64];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(&greeting[12..16], b"NULL");
+        stream.write_all(&greeting).unwrap();
+
+        let (flags, ready) = read_frame(&mut stream).unwrap();
+        assert_eq!(flags, COMMAND);
+        assert!(ready.ends_with(b"Socket-Type\x00\x00\x00\x03SUB"));
+        let mut ready = command_name("READY");
+        ready.extend_from_slice(b"\x0bSocket-Type\x00\x00\x00\x03PUB");
+        write_frame(&mut stream, COMMAND, &ready).unwrap();
+
+        let subscriptions = (0..topics)
+            .map(|_| read_frame(&mut stream).unwrap().1)
+            .collect();
+        (stream, subscriptions)
+    }
+
+    fn publish(stream: &mut TcpStream, topic: &[u8], body: &[u8]) {
+        write_frame(stream, MORE, topic).unwrap();
+        write_frame(stream, MORE, body).unwrap();
+        write_frame(stream, 0, &0u32.to_le_bytes()).unwrap();
+    }
+
+    // a transaction larger than 255 bytes, published in a long frame
+    fn large_tx() -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::default(), 0),
+                script_sig: Script::from(vec![0x51; 300]),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let blockhash =
+            BlockHash::from_hex("000000000000000000025b7ec6a7de2cc5a9c3d37fba0d7ae1ed18e3dc1d5a29")
+                .unwrap();
+        let tx = large_tx();
+        let rawtx = serialize(&tx);
+        assert!(rawtx.len() > 255);
+
+        let publisher = thread::spawn(move || {
+            let (mut stream, subscriptions) = accept_subscriber(&listener, 2);
+            assert_eq!(
+                subscriptions,
+                vec![b"\x01hashblock".to_vec(), b"\x01rawtx".to_vec()]
+            );
+
+            let hash: Vec<u8> = blockhash[..].iter().rev().cloned().collect();
+            publish(&mut stream, b"hashblock", &hash);
+            publish(&mut stream, b"hashtx", &[0; 32]); // not subscribed, ignored
+            publish(&mut stream, b"rawtx", &rawtx);
+        });
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let stream = connect(addr, &["hashblock", "rawtx"]).unwrap();
+        assert!(receive(stream, &sender).is_err()); // the publisher disconnects when done
+        publisher.join().unwrap();
+        assert_eq!(receiver.try_recv(), Ok(Notification::Block(blockhash)));
+        assert_eq!(receiver.try_recv(), Ok(Notification::Tx(tx.malfix_txid())));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_reconnect_after_stall() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tx = large_tx();
+        let rawtx = serialize(&tx);
+
+        let publisher = thread::spawn(move || {
+            // stall in the middle of a frame, without closing the connection
+            let (mut stalled, _) = accept_subscriber(&listener, 1);
+            write_frame(&mut stalled, MORE, b"rawtx").unwrap();
+            stalled.write_all(&[MORE | LONG]).unwrap();
+            stalled
+                .write_all(&(rawtx.len() as u64).to_be_bytes())
+                .unwrap();
+            stalled.write_all(&rawtx[..10]).unwrap();
+
+            let (mut stream, _) = accept_subscriber(&listener, 1);
+            publish(&mut stream, b"rawtx", &rawtx);
+            stalled
+        });
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let started = Instant::now();
+        subscribe(addr, vec!["rawtx"], sender);
+        let notification = receiver.recv_timeout(TIMEOUT + RECONNECT_DELAY * 2);
+        assert_eq!(notification, Ok(Notification::Tx(tx.malfix_txid())));
+        assert!(started.elapsed() >= TIMEOUT);
+        drop(publisher.join().unwrap());
+    }
+}